@@ -1,8 +1,9 @@
 use clunky::{
     math::{direction_3d_to_signed_number_3d, mul_3d, mul_3d_by_1d, neg_3d, Direction},
     physics::physics_3d::{
-        aabb::AabbCentredOrigin, bodies, calculate_collision_impulse,
-        calculate_collision_impulse_with_immovable_rhs, verlet::Particle,
+        aabb::AabbCentredOrigin, bodies, calculate_collision_and_friction_impulse,
+        calculate_collision_and_friction_impulse_with_immovable_rhs, material::PhysicsMaterial,
+        verlet::Particle,
     },
 };
 
@@ -61,6 +62,16 @@ impl bodies::Body<f32> for Body {
         }
     }
 
+    #[inline]
+    fn set_position_unchecked(&mut self, position: [f32; 3]) {
+        match self {
+            Body::Creature(creature) => creature.particle.teleport(position),
+            Body::ImmovableCuboid(immovable_cuboid) => immovable_cuboid.position = position,
+            Body::TriggerImmovableCuboid { aabb, .. } => aabb.position = position,
+            Body::None => unreachable!(),
+        }
+    }
+
     #[inline]
     fn is_none(&self) -> bool {
         match self {
@@ -79,6 +90,14 @@ impl bodies::Body<f32> for Body {
         }
     }
 
+    #[inline]
+    fn material(&self) -> PhysicsMaterial<f32> {
+        match self {
+            Body::Creature(creature) => creature.material,
+            _ => PhysicsMaterial::default(),
+        }
+    }
+
     #[inline]
     fn detect_collision(&self, other: &Body) -> bool {
         let colliding_bodies = (self, other);
@@ -108,6 +127,7 @@ impl bodies::Body<f32> for Body {
         rhs_index: usize,
         delta_time: f32,
     ) {
+        let material = self.material().combine(&other.material());
         let colliding_bodies = (self, other);
         match colliding_bodies {
             // CREATURE
@@ -127,17 +147,20 @@ impl bodies::Body<f32> for Body {
                     .particle
                     .apply_uniform_position_change(neg_3d(collision_translation));
 
-                if Direction::Positive == collision_normal[1] {
-                    lhs_creature.grounded = true;
+                // Either creature can be lhs, so either can be the one standing on the other.
+                match collision_normal[1] {
+                    Direction::Positive => lhs_creature.grounded = true,
+                    Direction::Negative => rhs_creature.grounded = true,
+                    Direction::None => (),
                 }
 
-                let impulse = calculate_collision_impulse(
+                let impulse = calculate_collision_and_friction_impulse(
                     lhs_creature.particle.calculate_velocity(delta_time),
                     lhs_creature.mass,
                     rhs_creature.particle.calculate_velocity(delta_time),
                     rhs_creature.mass,
                     collision_normal_signed_number,
-                    0.5,
+                    material,
                 );
                 lhs_creature.particle.apply_impulse(impulse, delta_time);
                 rhs_creature
@@ -169,11 +192,11 @@ impl bodies::Body<f32> for Body {
                     < T::from_f32(0.5);
                 */
 
-                let impulse = calculate_collision_impulse_with_immovable_rhs(
+                let impulse = calculate_collision_and_friction_impulse_with_immovable_rhs(
                     lhs_creature.particle.calculate_velocity(delta_time),
                     lhs_creature.mass,
                     collision_normal_signed_number,
-                    0.5,
+                    material,
                 );
                 //println!("impulse: {:?}", impulse);
                 lhs_creature.particle.apply_impulse(impulse, delta_time);
@@ -196,6 +219,7 @@ pub struct Creature {
 
     pub mass: f32,
     pub dampening: [f32; 3],
+    pub material: PhysicsMaterial<f32>,
 
     pub grounded: bool,
 
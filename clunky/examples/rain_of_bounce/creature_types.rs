@@ -1,6 +1,6 @@
 use clunky::{
     math::{add_3d, normalise_2d, remap},
-    physics::physics_3d::{aabb::AabbCentredOrigin, verlet::Particle},
+    physics::physics_3d::{aabb::AabbCentredOrigin, material::PhysicsMaterial, verlet::Particle},
 };
 use rand::{thread_rng, Rng};
 use winit::event::KeyboardInput;
@@ -172,6 +172,7 @@ impl Burgle {
 
             mass: 1.0,
             dampening: [0.9, 1.0, 0.9],
+            material: PhysicsMaterial::default(),
 
             grounded: false,
 
@@ -5,7 +5,9 @@ use clunky::{
     math::{add_3d, mul_3d_by_1d, sub_3d, Matrix4},
     physics::physics_3d::{
         aabb::{AabbCentredOrigin, AabbMinMax},
-        bodies::{CommonBody, ImmovableCuboid},
+        bodies::{CommonBody, ImmovableCuboid, ImmovableObb},
+        material::PhysicsMaterial,
+        obb::ObbCentredOrigin,
         verlet::{bodies::Cuboid, Particle},
     },
 };
@@ -116,6 +118,8 @@ pub fn load_scenes() -> Nameless {
                 scene.bodies.push(CommonBody::Cuboid(Cuboid {
                     particle: Particle::from_position(transform_decomposed.0),
                     half_size: mul_3d_by_1d(transform_decomposed.2, 0.5),
+                    material: PhysicsMaterial::default(),
+                    continuous_collision_detection: false,
                 }));
             }
             if properties.contains("\"physics\":\"immovable cuboid\"") {
@@ -126,8 +130,19 @@ pub fn load_scenes() -> Nameless {
                             position: transform_decomposed.0,
                             half_size: mul_3d_by_1d(transform_decomposed.2, 0.5),
                         },
+                        material: PhysicsMaterial::default(),
                     }));
             }
+            if properties.contains("\"physics\":\"immovable obb\"") {
+                scene.bodies.push(CommonBody::ImmovableObb(ImmovableObb {
+                    obb: ObbCentredOrigin::from_quaternion(
+                        transform_decomposed.0,
+                        mul_3d_by_1d(transform_decomposed.2, 0.5),
+                        transform_decomposed.1,
+                    ),
+                    material: PhysicsMaterial::default(),
+                }));
+            }
 
             if does_not_have_required_physics {
                 panic!(
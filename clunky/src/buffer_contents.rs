@@ -1,4 +1,5 @@
 use crate::math;
+use crate::physics::physics_3d::soft_body::SoftBody;
 use cgmath::Matrix4;
 use vulkano::{buffer::BufferContents, pipeline::graphics::vertex_input::Vertex};
 
@@ -191,3 +192,36 @@ pub struct Uv3DVertex {
     #[format(R32G32_SFLOAT)]
     pub uv: [f32; 2],
 }
+
+impl Basic3DVertex {
+    /// Gets the soft body's vertices, as of its last update. Use [SoftBody::indices] for the indices.
+    pub fn from_soft_body(soft_body: &SoftBody<f32>) -> Vec<Basic3DVertex> {
+        soft_body
+            .positions()
+            .iter()
+            .zip(soft_body.normals())
+            .map(|(position, normal)| Basic3DVertex {
+                position: *position,
+                normal: *normal,
+            })
+            .collect()
+    }
+}
+
+impl Uv3DVertex {
+    /// Gets the soft body's vertices, as of its last update. Use [SoftBody::indices] for the indices.
+    /// Soft bodies without uvs get [0.0, 0.0] everywhere.
+    pub fn from_soft_body(soft_body: &SoftBody<f32>) -> Vec<Uv3DVertex> {
+        soft_body
+            .positions()
+            .iter()
+            .zip(soft_body.normals())
+            .enumerate()
+            .map(|(vertex_index, (position, normal))| Uv3DVertex {
+                position: *position,
+                normal: *normal,
+                uv: soft_body.uvs.get(vertex_index).copied().unwrap_or_default(),
+            })
+            .collect()
+    }
+}
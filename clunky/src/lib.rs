@@ -2,6 +2,7 @@
 #![feature(test)]
 #![feature(vec_push_within_capacity)]
 #![feature(int_roundings)]
+#![feature(portable_simd)] // Used to integrate CuboidArrays.
 #![doc = include_str!("../../README.md")]
 //#![warn(missing_docs)] // Uncomment this when you want to do some documenting. Otherwise leave commented.
 
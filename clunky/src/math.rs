@@ -8,7 +8,11 @@
 // This will never be as good as glam and cgmath. Might give up maintaining this, and focus on things I can do! Like physics and premade shaders and such like.
 
 use const_soft_float::soft_f32::SoftF32;
-use std::ops::{self, Mul};
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::{self, Mul},
+    simd::{Simd, SimdElement},
+};
 extern crate test;
 
 /// A basic number.
@@ -55,6 +59,35 @@ pub trait Float: SignedNumber {
     fn from_f64(value: f64) -> Self;
     /// Returns the smallest integer greater than or equal to self.
     fn ceil(self) -> Self;
+    /// Returns the raw bits of self, widened to a u64. Equal numbers with equal bits always give the same result, so this is good for hashing.
+    fn to_bits_u64(self) -> u64;
+
+    /// Verlet integrates one axis of many particles at once, giving the same results as accelerating each particle by gravity and then updating it.
+    /// Each slice holds that axis for every particle, so they must be the same length. The accelerations are reset to zero.
+    ///
+    /// By default this goes one particle at a time, but f32 and f64 use SIMD.
+    fn integrate_verlet_axis(
+        positions: &mut [Self],
+        previous_positions: &mut [Self],
+        accelerations: &mut [Self],
+        gravity: Self,
+        dampening: Self,
+        delta_time_squared: Self,
+    ) {
+        assert_eq!(positions.len(), previous_positions.len());
+        assert_eq!(positions.len(), accelerations.len());
+
+        for ((position, previous_position), acceleration) in positions
+            .iter_mut()
+            .zip(previous_positions.iter_mut())
+            .zip(accelerations.iter_mut())
+        {
+            let displacement = (*position - *previous_position) * dampening;
+            *previous_position = *position;
+            *position = *position + displacement + (*acceleration + gravity) * delta_time_squared;
+            *acceleration = Self::ZERO;
+        }
+    }
 }
 
 impl Number for f32 {
@@ -128,6 +161,30 @@ impl Float for f32 {
     fn from_f64(value: f64) -> Self {
         value as f32
     }
+
+    #[inline]
+    fn to_bits_u64(self) -> u64 {
+        self.to_bits() as u64
+    }
+
+    #[inline]
+    fn integrate_verlet_axis(
+        positions: &mut [Self],
+        previous_positions: &mut [Self],
+        accelerations: &mut [Self],
+        gravity: Self,
+        dampening: Self,
+        delta_time_squared: Self,
+    ) {
+        integrate_verlet_axis_simd::<f32, 8>(
+            positions,
+            previous_positions,
+            accelerations,
+            gravity,
+            dampening,
+            delta_time_squared,
+        );
+    }
 }
 
 impl Number for f64 {
@@ -201,6 +258,30 @@ impl Float for f64 {
     fn from_f64(value: f64) -> Self {
         value
     }
+
+    #[inline]
+    fn to_bits_u64(self) -> u64 {
+        self.to_bits()
+    }
+
+    #[inline]
+    fn integrate_verlet_axis(
+        positions: &mut [Self],
+        previous_positions: &mut [Self],
+        accelerations: &mut [Self],
+        gravity: Self,
+        dampening: Self,
+        delta_time_squared: Self,
+    ) {
+        integrate_verlet_axis_simd::<f64, 4>(
+            positions,
+            previous_positions,
+            accelerations,
+            gravity,
+            dampening,
+            delta_time_squared,
+        );
+    }
 }
 
 impl Number for usize {
@@ -214,6 +295,256 @@ impl Number for usize {
     }
 }
 
+/// A fixed point number, with 32 bits before the point and 32 after, stored in an i64.
+///
+/// Unlike floats, every operation is plain integer maths, so a simulation using it gives bit for bit the same results on every platform.
+/// Overflowing saturates, and dividing by 0 gives the largest number with the same sign, much like floats going to infinity.
+///
+/// It is 64 bits, the same size as f64, rather than 16.16 in an i32, as that overflows when squaring a distance of just 182, and its 16 fractional bits can't hold the small movements particles make each substep.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    const FRACTIONAL_BITS: u32 = 32;
+    const FRACTIONAL_MASK: i64 = (1 << Self::FRACTIONAL_BITS) - 1;
+
+    pub const PI: Fixed = Fixed(13493037705);
+    pub const HALF_PI: Fixed = Fixed(6746518852);
+    pub const TAU: Fixed = Fixed(26986075409);
+
+    /// Creates a fixed point number from its raw bits, where 1 << 32 is 1.0.
+    #[inline]
+    pub const fn from_bits(bits: i64) -> Fixed {
+        Fixed(bits)
+    }
+
+    /// The raw bits, where 1 << 32 is 1.0.
+    #[inline]
+    pub const fn to_bits(self) -> i64 {
+        self.0
+    }
+
+    /// For rendering and debugging. Don't feed the result back into the simulation, or it stops being deterministic.
+    #[inline]
+    pub fn to_f32(self) -> f32 {
+        self.to_f64() as f32
+    }
+
+    /// For rendering and debugging. Don't feed the result back into the simulation, or it stops being deterministic.
+    #[inline]
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / (1u64 << Self::FRACTIONAL_BITS) as f64
+    }
+
+    #[inline]
+    fn saturate(value: i128) -> Fixed {
+        Fixed(value.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+    }
+}
+
+impl std::fmt::Debug for Fixed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_f64())
+    }
+}
+
+impl std::fmt::Display for Fixed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_f64())
+    }
+}
+
+impl ops::Add for Fixed {
+    type Output = Self;
+    #[inline]
+    fn add(self, rhs: Self) -> Self::Output {
+        Fixed(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl ops::AddAssign for Fixed {
+    #[inline]
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl ops::Sub for Fixed {
+    type Output = Self;
+    #[inline]
+    fn sub(self, rhs: Self) -> Self::Output {
+        Fixed(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl ops::SubAssign for Fixed {
+    #[inline]
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl ops::Mul for Fixed {
+    type Output = Self;
+    #[inline]
+    fn mul(self, rhs: Self) -> Self::Output {
+        Fixed::saturate((self.0 as i128 * rhs.0 as i128) >> Fixed::FRACTIONAL_BITS)
+    }
+}
+
+impl ops::Div for Fixed {
+    type Output = Self;
+    #[inline]
+    fn div(self, rhs: Self) -> Self::Output {
+        if rhs.0 == 0 {
+            return match self.0.cmp(&0) {
+                std::cmp::Ordering::Greater => Fixed::MAX,
+                std::cmp::Ordering::Less => -Fixed::MAX,
+                std::cmp::Ordering::Equal => Fixed::ZERO,
+            };
+        }
+        Fixed::saturate(((self.0 as i128) << Fixed::FRACTIONAL_BITS) / rhs.0 as i128)
+    }
+}
+
+impl ops::Rem for Fixed {
+    type Output = Self;
+    #[inline]
+    fn rem(self, rhs: Self) -> Self::Output {
+        if rhs.0 == 0 {
+            return Fixed::ZERO;
+        }
+        Fixed(self.0.wrapping_rem(rhs.0))
+    }
+}
+
+impl ops::Neg for Fixed {
+    type Output = Self;
+    #[inline]
+    fn neg(self) -> Self::Output {
+        Fixed(self.0.saturating_neg())
+    }
+}
+
+impl From<u16> for Fixed {
+    #[inline]
+    fn from(value: u16) -> Self {
+        Fixed((value as i64) << Fixed::FRACTIONAL_BITS)
+    }
+}
+
+impl Number for Fixed {
+    const ZERO: Self = Fixed(0);
+    const ONE: Self = Fixed(1 << Fixed::FRACTIONAL_BITS);
+    const MAX: Self = Fixed(i64::MAX);
+
+    /// Truncates, and negative numbers become 0, the same as `as usize` does for floats.
+    #[inline]
+    fn to_usize(self) -> usize {
+        if self.0 < 0 {
+            0
+        } else {
+            (self.0 >> Fixed::FRACTIONAL_BITS) as usize
+        }
+    }
+}
+
+impl SignedNumber for Fixed {
+    #[inline]
+    fn abs(self) -> Self {
+        Fixed(self.0.saturating_abs())
+    }
+
+    #[inline]
+    fn is_sign_positive(self) -> bool {
+        self.0 >= 0
+    }
+
+    /// Truncates towards 0, the same as `as isize` does for floats.
+    #[inline]
+    fn to_isize(self) -> isize {
+        (self.0 / (1 << Fixed::FRACTIONAL_BITS)) as isize
+    }
+
+    #[inline]
+    fn from_direction(direction: Direction) -> Self {
+        match direction {
+            Direction::Positive => Fixed::ONE,
+            Direction::None => Fixed::ZERO,
+            Direction::Negative => -Fixed::ONE,
+        }
+    }
+}
+
+impl Float for Fixed {
+    #[inline]
+    fn ceil(self) -> Self {
+        Fixed(self.0.saturating_add(Fixed::FRACTIONAL_MASK) & !Fixed::FRACTIONAL_MASK)
+    }
+
+    /// The square root of a negative number is 0.
+    #[inline]
+    fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Fixed::ZERO;
+        }
+        // sqrt(bits / 2^32) * 2^32 = sqrt(bits * 2^32)
+        Fixed((((self.0 as u128) << Fixed::FRACTIONAL_BITS).isqrt()) as i64)
+    }
+
+    fn sin(self) -> Self {
+        // Bring the angle into -pi..=pi, and then into -pi/2..=pi/2, using sin(pi - x) = sin(x).
+        let mut angle = self % Fixed::TAU;
+        if angle > Fixed::PI {
+            angle -= Fixed::TAU;
+        } else if angle < -Fixed::PI {
+            angle += Fixed::TAU;
+        }
+        if angle > Fixed::HALF_PI {
+            angle = Fixed::PI - angle;
+        } else if angle < -Fixed::HALF_PI {
+            angle = -Fixed::PI - angle;
+        }
+
+        // The taylor series, x - x^3/3! + x^5/5! - ..., which is accurate to the last bit by x^15 within -pi/2..=pi/2.
+        let angle_squared = angle * angle;
+        let mut term = angle;
+        let mut sum = angle;
+        for n in 1..8u16 {
+            term = -term * angle_squared / Fixed::from(2 * n * (2 * n + 1));
+            sum += term;
+        }
+        sum
+    }
+
+    #[inline]
+    fn cos(self) -> Self {
+        (self + Fixed::HALF_PI).sin()
+    }
+
+    #[inline]
+    fn to_radians(self) -> Self {
+        self * Fixed(74961321)
+    }
+
+    /// Rounds to the nearest fixed point number. This is deterministic, so it is fine to use for setting up a simulation.
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        Fixed::from_f64(value as f64)
+    }
+
+    /// Rounds to the nearest fixed point number. This is deterministic, so it is fine to use for setting up a simulation.
+    #[inline]
+    fn from_f64(value: f64) -> Self {
+        Fixed((value * (1u64 << Fixed::FRACTIONAL_BITS) as f64).round() as i64)
+    }
+
+    #[inline]
+    fn to_bits_u64(self) -> u64 {
+        self.0 as u64
+    }
+}
+
 /// remember when doing matrix math transformations we do translate * rotate * scale unless you are doing world_to_camera, in which case it won't work, and you should try the reverse.
 /// All rights go to cgmath, I've just slighty tweaked their stuff.
 #[repr(C)]
@@ -717,6 +1048,15 @@ pub fn dot<T: Number>(lhs: [T; 3], rhs: [T; 3]) -> T {
     lhs[0] * rhs[0] + lhs[1] * rhs[1] + lhs[2] * rhs[2]
 }
 
+/// Calculates the cross product of 2 3d numbers.
+pub fn cross<T: Number>(lhs: [T; 3], rhs: [T; 3]) -> [T; 3] {
+    [
+        lhs[1] * rhs[2] - lhs[2] * rhs[1],
+        lhs[2] * rhs[0] - lhs[0] * rhs[2],
+        lhs[0] * rhs[1] - lhs[1] * rhs[0],
+    ]
+}
+
 /// Converts from [f32; 3] to [f64; 3].
 pub fn f32_3d_to_f64_3d(value: [f32; 3]) -> [f64; 3] {
     [value[0] as f64, value[1] as f64, value[2] as f64]
@@ -760,6 +1100,61 @@ impl Mul for F32x3 {
     }
 }
 
+/// [Float::integrate_verlet_axis], LANES particles at a time. Whatever doesn't fill a whole chunk is done one at a time.
+#[inline]
+fn integrate_verlet_axis_simd<T, const LANES: usize>(
+    positions: &mut [T],
+    previous_positions: &mut [T],
+    accelerations: &mut [T],
+    gravity: T,
+    dampening: T,
+    delta_time_squared: T,
+) where
+    T: Float + SimdElement,
+    Simd<T, LANES>: ops::Add<Output = Simd<T, LANES>>
+        + ops::Sub<Output = Simd<T, LANES>>
+        + ops::Mul<Output = Simd<T, LANES>>,
+{
+    assert_eq!(positions.len(), previous_positions.len());
+    assert_eq!(positions.len(), accelerations.len());
+
+    let (position_chunks, position_remainder) = positions.as_chunks_mut::<LANES>();
+    let (previous_position_chunks, previous_position_remainder) =
+        previous_positions.as_chunks_mut::<LANES>();
+    let (acceleration_chunks, acceleration_remainder) = accelerations.as_chunks_mut::<LANES>();
+
+    let gravity_lanes = Simd::splat(gravity);
+    let dampening_lanes = Simd::splat(dampening);
+    let delta_time_squared_lanes = Simd::splat(delta_time_squared);
+    for ((position_chunk, previous_position_chunk), acceleration_chunk) in position_chunks
+        .iter_mut()
+        .zip(previous_position_chunks.iter_mut())
+        .zip(acceleration_chunks.iter_mut())
+    {
+        let position = Simd::from_array(*position_chunk);
+        let displacement =
+            (position - Simd::from_array(*previous_position_chunk)) * dampening_lanes;
+        let acceleration = Simd::from_array(*acceleration_chunk) + gravity_lanes;
+
+        *previous_position_chunk = *position_chunk;
+        *position_chunk =
+            (position + displacement + acceleration * delta_time_squared_lanes).to_array();
+        *acceleration_chunk = [T::ZERO; LANES];
+    }
+
+    // The rest don't fill a chunk, so they are done one at a time.
+    for ((position, previous_position), acceleration) in position_remainder
+        .iter_mut()
+        .zip(previous_position_remainder.iter_mut())
+        .zip(acceleration_remainder.iter_mut())
+    {
+        let displacement = (*position - *previous_position) * dampening;
+        *previous_position = *position;
+        *position = *position + displacement + (*acceleration + gravity) * delta_time_squared;
+        *acceleration = T::ZERO;
+    }
+}
+
 // End of New Math
 
 #[cfg(test)]
@@ -799,4 +1194,74 @@ mod tests {
                 * test::black_box(F32x3([0.9, 50.2, 97.7531233]));
         })
     }
+
+    /// The smallest step between 2 fixed point numbers.
+    const FIXED_EPSILON: f64 = 1.0 / (1u64 << 32) as f64;
+
+    #[test]
+    fn fixed_arithmetic() {
+        let lhs = Fixed::from_f64(1.5);
+        let rhs = Fixed::from_f64(-2.25);
+
+        assert_eq!(lhs + rhs, Fixed::from_f64(-0.75));
+        assert_eq!(lhs - rhs, Fixed::from_f64(3.75));
+        assert_eq!(lhs * rhs, Fixed::from_f64(-3.375));
+        assert_eq!(rhs / lhs, Fixed::from_f64(-1.5));
+        assert_eq!(
+            Fixed::from_f64(5.5) % Fixed::from_f64(2.0),
+            Fixed::from_f64(1.5)
+        );
+        assert_eq!(-lhs, Fixed::from_f64(-1.5));
+        assert_eq!(rhs.abs(), Fixed::from_f64(2.25));
+        assert_eq!(Fixed::from(3u16), Fixed::from_f64(3.0));
+
+        assert_eq!(Fixed::from_f64(2.75).to_usize(), 2);
+        assert_eq!(Fixed::from_f64(-2.75).to_usize(), 0);
+        assert_eq!(Fixed::from_f64(-2.75).to_isize(), -2);
+        assert_eq!(Fixed::from_f64(2.25).ceil(), Fixed::from_f64(3.0));
+        assert_eq!(Fixed::from_f64(-2.25).ceil(), Fixed::from_f64(-2.0));
+        assert_eq!(Fixed::from_f64(2.0).ceil(), Fixed::from_f64(2.0));
+    }
+
+    #[test]
+    fn fixed_saturates() {
+        assert_eq!(Fixed::MAX + Fixed::ONE, Fixed::MAX);
+        assert_eq!(
+            -Fixed::MAX - Fixed::from_f64(2.0),
+            Fixed::from_bits(i64::MIN)
+        );
+        assert_eq!(Fixed::MAX * Fixed::from_f64(2.0), Fixed::MAX);
+        assert_eq!(
+            Fixed::MAX * Fixed::from_f64(-2.0),
+            Fixed::from_bits(i64::MIN)
+        );
+        assert_eq!(Fixed::MAX / Fixed::from_f64(0.5), Fixed::MAX);
+
+        assert_eq!(Fixed::ONE / Fixed::ZERO, Fixed::MAX);
+        assert_eq!(-Fixed::ONE / Fixed::ZERO, -Fixed::MAX);
+        assert_eq!(Fixed::ZERO / Fixed::ZERO, Fixed::ZERO);
+        assert_eq!(Fixed::ONE % Fixed::ZERO, Fixed::ZERO);
+    }
+
+    #[test]
+    fn fixed_is_accurate() {
+        for i in -2000..=2000 {
+            let value = Fixed::from_f64(i as f64 * 0.01);
+            let exact_value = value.to_f64();
+
+            assert!((value.sin().to_f64() - exact_value.sin()).abs() <= 8.0 * FIXED_EPSILON);
+            assert!((value.cos().to_f64() - exact_value.cos()).abs() <= 8.0 * FIXED_EPSILON);
+
+            if i > 0 {
+                assert!((value.sqrt().to_f64() - exact_value.sqrt()).abs() <= FIXED_EPSILON);
+
+                let numerator = Fixed::from_f64(3.7);
+                assert!(
+                    ((numerator / value).to_f64() - numerator.to_f64() / exact_value).abs()
+                        <= FIXED_EPSILON
+                );
+            }
+        }
+        assert_eq!(Fixed::from_f64(-4.0).sqrt(), Fixed::ZERO);
+    }
 }
@@ -1,9 +1,27 @@
 use crate::math::{self, Float};
 
+use self::material::CombinedMaterial;
+
 pub mod aabb;
 pub mod bodies;
+pub mod broadphase;
+pub mod bvh;
+pub mod capsule;
+pub mod character_controller;
+pub mod constraints;
+pub mod cylinder;
+pub mod function_registry;
 pub mod grid;
+pub mod heightfield;
+pub mod material;
+pub mod obb;
+pub mod soft_body;
 pub mod solver;
+pub mod spatial_hash;
+pub mod sphere;
+pub mod sweep_and_prune;
+pub mod triangle_mesh;
+pub mod uniform_grid;
 pub mod verlet;
 
 /// Calculates the final velocities when 2 rigid bodies collide elastically.
@@ -40,61 +58,32 @@ pub fn calculate_velocities_during_elastic_collision<T: Float>(
     )
 }
 
-/// Calculates the final velocities when 2 rigid bodies collide elastically.
-/// Gravity is the constant downward acceleration. I have no idea how to set this correctly currently. Try 9.8?
-/// Cannot handle an infinite mass rhs. Uncertain what to use instead.
-/// This assumes the collision happened with no intersection between the bodies. If there is intersection then it will likely provide incorrect velocities.
-/// This doesn't work. It was generated with chatgpt, as a starting point, because finding niche physics functions is surprisingly hard. I'm continuing to modify and fix this function,but it may never work.
+/// Calculates the final velocities when 2 bodies collide, using the material's restitution for the bounce, and its friction to slow them sliding along each other.
+/// The collision normal should point from lhs towards rhs.
+/// Cannot handle an infinite mass rhs. Use [calculate_collision_and_friction_impulse_with_immovable_rhs] for that.
 #[must_use]
-pub fn calculate_velocities_during_elastic_collision_with_friction_and_restitution<T: Float>(
+pub fn calculate_velocities_during_collision_with_friction_and_restitution<T: Float>(
     lhs_mass: T,
     lhs_velocity: [T; 3],
 
     rhs_mass: T,
     rhs_velocity: [T; 3],
 
-    gravity: T,
-    friction: T,
-    restitution: T,
+    collision_normal: [T; 3],
+    material: CombinedMaterial<T>,
 ) -> ([T; 3], [T; 3]) {
-    let total_momentum_before = math::add_3d(
-        math::mul_3d_by_1d(lhs_velocity, lhs_mass),
-        math::mul_3d_by_1d(rhs_velocity, rhs_mass),
-    );
-    let relative_velocity_before = math::sub_3d(rhs_velocity, lhs_velocity);
-
-    // Before the mangling: (lhs_restitution + rhs_restitution) * (lhs_mass * rhs_mass) / (lhs_mass + rhs_mass) * dot(relative_velocity_before, total_momentum_before) / dot(total_momentum_before, total_momentum_before)
-    let impulse = (T::from_f32(1.0) + restitution) * (lhs_mass * rhs_mass) / (lhs_mass + rhs_mass)
-        * math::dot(relative_velocity_before, total_momentum_before)
-        / math::dot(total_momentum_before, total_momentum_before);
-
-    // Before the mangling: lhs_velocity + impulse / lhs_mass * total_momentum_before. (Same for rhs.)
-    let lhs_simple_final_velocity = math::add_3d(
+    let impulse = calculate_collision_and_friction_impulse(
         lhs_velocity,
-        math::mul_3d_by_1d(total_momentum_before, impulse / lhs_mass),
-    );
-    let rhs_simple_final_velocity = math::sub_3d(
+        lhs_mass,
         rhs_velocity,
-        math::mul_3d_by_1d(total_momentum_before, impulse / rhs_mass),
+        rhs_mass,
+        collision_normal,
+        material,
     );
 
-    let lhs_friction_force = friction * lhs_mass * gravity;
-    let lhs_friction_direction = math::neg_3d(math::normalise_3d(lhs_velocity));
-    let lhs_friction_impulse = math::mul_3d_by_1d(lhs_friction_direction, lhs_friction_force);
-
-    let rhs_friction_force = friction * rhs_mass * gravity;
-    let rhs_friction_direction = math::neg_3d(math::normalise_3d(rhs_velocity));
-    let rhs_friction_impulse = math::mul_3d_by_1d(rhs_friction_direction, rhs_friction_force);
-
     (
-        math::add_3d(
-            lhs_simple_final_velocity,
-            math::div_3d_by_1d(lhs_friction_impulse, lhs_mass),
-        ),
-        math::add_3d(
-            rhs_simple_final_velocity,
-            math::div_3d_by_1d(rhs_friction_impulse, rhs_mass),
-        ),
+        math::add_3d(lhs_velocity, math::div_3d_by_1d(impulse, lhs_mass)),
+        math::sub_3d(rhs_velocity, math::div_3d_by_1d(impulse, rhs_mass)),
     )
 }
 
@@ -134,3 +123,99 @@ pub fn calculate_collision_impulse_with_immovable_rhs<T: Float>(
 
     math::mul_3d_by_1d(collision_normal, impulse_magnitude)
 }
+
+/// Calculates the collision impulse for lhs, with the material's restitution, plus the friction impulse that goes with it. Apply the negation to rhs.
+/// The collision normal should point from lhs towards rhs.
+pub fn calculate_collision_and_friction_impulse<T: Float>(
+    lhs_velocity: [T; 3],
+    lhs_mass: T,
+
+    rhs_velocity: [T; 3],
+    rhs_mass: T,
+
+    collision_normal: [T; 3],
+    material: CombinedMaterial<T>,
+) -> [T; 3] {
+    let collision_impulse = calculate_collision_impulse(
+        lhs_velocity,
+        lhs_mass,
+        rhs_velocity,
+        rhs_mass,
+        collision_normal,
+        material.restitution,
+    );
+    let friction_impulse = calculate_friction_impulse(
+        math::sub_3d(lhs_velocity, rhs_velocity),
+        T::ONE / lhs_mass + T::ONE / rhs_mass,
+        collision_normal,
+        collision_impulse,
+        material,
+    );
+
+    math::add_3d(collision_impulse, friction_impulse)
+}
+
+/// Like [calculate_collision_and_friction_impulse], but for when rhs can't move.
+pub fn calculate_collision_and_friction_impulse_with_immovable_rhs<T: Float>(
+    lhs_velocity: [T; 3],
+    lhs_mass: T,
+
+    collision_normal: [T; 3],
+    material: CombinedMaterial<T>,
+) -> [T; 3] {
+    let collision_impulse = calculate_collision_impulse_with_immovable_rhs(
+        lhs_velocity,
+        lhs_mass,
+        collision_normal,
+        material.restitution,
+    );
+    let friction_impulse = calculate_friction_impulse(
+        lhs_velocity,
+        T::ONE / lhs_mass,
+        collision_normal,
+        collision_impulse,
+        material,
+    );
+
+    math::add_3d(collision_impulse, friction_impulse)
+}
+
+/// Calculates the impulse friction applies to lhs, as it slides along rhs. Apply the negation to rhs, should it be able to move.
+///
+/// The relative velocity is lhs's velocity minus rhs's, and the inverse mass sum is 1 / lhs mass + 1 / rhs mass, where an immovable rhs has 1 / mass of 0.
+/// The collision impulse is the one applied to lhs by the collision, as how hard they are pressed together decides how much friction there can be.
+/// If the sliding can be stopped with static friction, it is stopped entirely, otherwise dynamic friction slows it.
+pub fn calculate_friction_impulse<T: Float>(
+    relative_velocity: [T; 3],
+    inverse_mass_sum: T,
+    collision_normal: [T; 3],
+    collision_impulse: [T; 3],
+    material: CombinedMaterial<T>,
+) -> [T; 3] {
+    // The collision impulse pushes lhs away from rhs, against the normal. If it doesn't, they aren't being pressed together.
+    let pressing_impulse = -math::dot(collision_impulse, collision_normal);
+    if pressing_impulse <= T::ZERO {
+        return [T::ZERO; 3];
+    }
+
+    let tangential_velocity = math::sub_3d(
+        relative_velocity,
+        math::mul_3d_by_1d(
+            collision_normal,
+            math::dot(relative_velocity, collision_normal),
+        ),
+    );
+    let tangential_speed = math::get_magnitude_3d(tangential_velocity);
+    if tangential_speed == T::ZERO {
+        return [T::ZERO; 3];
+    }
+
+    let stopping_impulse = tangential_speed / inverse_mass_sum;
+    let friction_impulse = if stopping_impulse <= material.static_friction * pressing_impulse {
+        stopping_impulse
+    } else {
+        material.dynamic_friction * pressing_impulse
+    };
+
+    math::mul_3d_by_1d(tangential_velocity, -friction_impulse / tangential_speed)
+}
@@ -1,8 +1,10 @@
 // TODO: Rename this to axis_aligned_shapes.rs perhaps? I want to have more than just aabb in here.
 
 use crate::math::{Direction, Number, SignedNumber};
+use serde::{Deserialize, Serialize};
 extern crate test;
 
+#[derive(Serialize, Deserialize)]
 pub struct AabbTopLeftOrigin<T>
 where
     T: Number,
@@ -34,7 +36,7 @@ where
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct AabbCentredOrigin<T>
 where
     T: Number,
@@ -73,6 +75,55 @@ where
         true
     }
 
+    /// Finds where a ray first enters the aabb, returning the normal of the face it entered through, and how far along the ray that is.
+    /// The distance is measured in multiples of direction, so normalise direction if you want it in world units.
+    /// If the ray starts inside the aabb, the distance is 0 and the normal is zero.
+    pub fn get_ray_intersection(
+        &self,
+        origin: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+    ) -> Option<([T; 3], T)> {
+        let mut normal = [T::ZERO; 3];
+        let mut entry_distance = T::ZERO;
+        let mut exit_distance = max_distance;
+
+        for axis in 0..3 {
+            let min = self.position[axis] - self.half_size[axis];
+            let max = self.position[axis] + self.half_size[axis];
+
+            if direction[axis] == T::ZERO {
+                // Parallel to these faces, so it either never enters or is always between them.
+                if origin[axis] < min || origin[axis] > max {
+                    return None;
+                }
+                continue;
+            }
+
+            let mut near_distance = (min - origin[axis]) / direction[axis];
+            let mut far_distance = (max - origin[axis]) / direction[axis];
+            let mut face_normal = -T::ONE;
+            if near_distance > far_distance {
+                std::mem::swap(&mut near_distance, &mut far_distance);
+                face_normal = T::ONE;
+            }
+
+            if near_distance > entry_distance {
+                entry_distance = near_distance;
+                normal = [T::ZERO; 3];
+                normal[axis] = face_normal;
+            }
+            if far_distance < exit_distance {
+                exit_distance = far_distance;
+            }
+            if entry_distance > exit_distance {
+                return None;
+            }
+        }
+
+        Some((normal, entry_distance))
+    }
+
     /// See [AabbCentredOrigin::get_collision_axis_with_direction] for more info. Both are garbage with documentation.
     pub fn get_collision_axis(&self, other: AabbCentredOrigin<T>) -> [bool; 3] {
         // Run this on previous position instead, so you can see what axis wasn't intersecting before the collision.
@@ -181,7 +232,7 @@ where
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct AabbMinMax<T>
 where
     T: Number,
@@ -211,6 +262,81 @@ where
             && self.min[2] <= aabb.max[2]
             && self.max[2] >= aabb.min[2]
     }
+
+    /// Whether the aabb is entirely inside of self.
+    pub fn contains_aabb(&self, aabb: AabbMinMax<T>) -> bool {
+        self.min[0] <= aabb.min[0]
+            && self.max[0] >= aabb.max[0]
+            && self.min[1] <= aabb.min[1]
+            && self.max[1] >= aabb.max[1]
+            && self.min[2] <= aabb.min[2]
+            && self.max[2] >= aabb.max[2]
+    }
+
+    /// The smallest aabb that contains both self and the aabb.
+    pub fn union(&self, aabb: AabbMinMax<T>) -> AabbMinMax<T> {
+        let mut union = *self;
+        for axis in 0..3 {
+            if aabb.min[axis] < union.min[axis] {
+                union.min[axis] = aabb.min[axis];
+            }
+            if aabb.max[axis] > union.max[axis] {
+                union.max[axis] = aabb.max[axis];
+            }
+        }
+        union
+    }
+
+    /// Half of the surface area, which is just as good as the whole thing for comparing aabbs, but cheaper.
+    pub fn half_surface_area(&self) -> T {
+        let size = [
+            self.max[0] - self.min[0],
+            self.max[1] - self.min[1],
+            self.max[2] - self.min[2],
+        ];
+        size[0] * size[1] + size[1] * size[2] + size[2] * size[0]
+    }
+}
+
+impl<T> From<AabbCentredOrigin<T>> for AabbMinMax<T>
+where
+    T: Number,
+{
+    fn from(aabb: AabbCentredOrigin<T>) -> Self {
+        AabbMinMax {
+            min: [
+                aabb.position[0] - aabb.half_size[0],
+                aabb.position[1] - aabb.half_size[1],
+                aabb.position[2] - aabb.half_size[2],
+            ],
+            max: [
+                aabb.position[0] + aabb.half_size[0],
+                aabb.position[1] + aabb.half_size[1],
+                aabb.position[2] + aabb.half_size[2],
+            ],
+        }
+    }
+}
+
+impl<T> From<AabbMinMax<T>> for AabbCentredOrigin<T>
+where
+    T: Number,
+{
+    fn from(aabb: AabbMinMax<T>) -> Self {
+        let two = T::ONE + T::ONE;
+        AabbCentredOrigin {
+            position: [
+                (aabb.min[0] + aabb.max[0]) / two,
+                (aabb.min[1] + aabb.max[1]) / two,
+                (aabb.min[2] + aabb.max[2]) / two,
+            ],
+            half_size: [
+                (aabb.max[0] - aabb.min[0]) / two,
+                (aabb.max[1] - aabb.min[1]) / two,
+                (aabb.max[2] - aabb.min[2]) / two,
+            ],
+        }
+    }
 }
 
 #[cfg(test)]
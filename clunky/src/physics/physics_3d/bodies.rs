@@ -1,9 +1,17 @@
 use crate::{
     math::{self, add_3d, Direction},
-    physics::physics_3d::{self, aabb::AabbCentredOrigin},
+    physics::physics_3d::{
+        self,
+        aabb::AabbCentredOrigin,
+        capsule, cylinder, heightfield,
+        material::{CombinedMaterial, PhysicsMaterial},
+        obb, sphere, triangle_mesh,
+    },
 };
+use serde::{Deserialize, Serialize};
 
 use super::verlet;
+use std::hash::Hasher;
 
 /// Usually you want to implement this for an enum that has varients for each of the different body types you want to use with the verlet solver.
 ///
@@ -18,10 +26,13 @@ where
     fn position_unchecked(&self) -> [T; 3];
     /// Gets the half size of the body. Panics if it doesn't have a size.
     fn half_size_unchecked(&self) -> [T; 3];
+    /// Moves the body to the position, stopping it in the process. Panics if it doesn't have a position.
+    fn set_position_unchecked(&mut self, position: [T; 3]);
     /// If the body is nothing. It won't even bother to place this thing in the grid.
     /// This is useful for when you don't want to disturb the indices of bodies, but still want to remove bodies.
     fn is_none(&self) -> bool;
     fn collide_with_others(&self) -> bool;
+    /// Each colliding pair is only responded to once per pass. Self is whichever body collides with others, preferring the smaller index, so when both do, either can be self.
     fn respond_to_collision(
         &mut self,
         other: &mut Self,
@@ -31,12 +42,120 @@ where
     );
 
     fn detect_collision(&self, other: &Self) -> bool;
+
+    /// Sweeps an aabb along a normalised direction, returning the normal of the body where it was hit, and how far the aabb travelled first.
+    /// A half size of zero makes this a raycast. If the aabb starts inside the body, the distance is 0.
+    ///
+    /// By default this treats the body as its aabb.
+    fn cast_aabb(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+    ) -> Option<([T; 3], T)> {
+        AabbCentredOrigin {
+            position: self.position_unchecked(),
+            half_size: add_3d(self.half_size_unchecked(), half_size),
+        }
+        .get_ray_intersection(origin, direction, max_distance)
+    }
+
+    /// Gets the normal pointing from self towards other, and how far they are inside each other.
+    /// By default this treats both bodies as their aabbs.
+    fn get_collision_normal_and_penetration(&self, other: &Self) -> ([T; 3], T) {
+        let (collision_normal, penetration) = AabbCentredOrigin {
+            position: self.position_unchecked(),
+            half_size: self.half_size_unchecked(),
+        }
+        .get_collision_normal_and_penetration(&AabbCentredOrigin {
+            position: other.position_unchecked(),
+            half_size: other.half_size_unchecked(),
+        });
+        (
+            math::direction_3d_to_signed_number_3d(collision_normal),
+            penetration,
+        )
+    }
+
+    /// Whether the body overlaps the aabb. By default this treats the body as its aabb.
+    fn is_intersected_by_aabb(&self, aabb: AabbCentredOrigin<T>) -> bool {
+        AabbCentredOrigin {
+            position: self.position_unchecked(),
+            half_size: self.half_size_unchecked(),
+        }
+        .is_intersected_by_aabb(aabb)
+    }
+
+    /// Whether the body overlaps the sphere. By default this treats the body as its aabb.
+    fn is_intersected_by_sphere(&self, sphere: sphere::Sphere<T>) -> bool {
+        sphere.is_intersected_by_aabb(AabbCentredOrigin {
+            position: self.position_unchecked(),
+            half_size: self.half_size_unchecked(),
+        })
+    }
+
+    /// Gets the particle that moves the body, should it be able to move.
+    /// Constraints move bodies through this, and treat bodies without one as fixed in place.
+    fn particle_mut(&mut self) -> Option<&mut verlet::Particle<T>> {
+        None
+    }
+
+    /// Gets what the body's surface is like, which decides the friction and bounciness of its collisions.
+    /// By default bodies use [PhysicsMaterial::default].
+    fn material(&self) -> PhysicsMaterial<T> {
+        PhysicsMaterial::default()
+    }
+
+    /// Whether the solver should sweep the body from where it was to where it is each substep, so that it can't tunnel through bodies that stop sweeps.
+    /// The body is swept as its aabb, and needs a particle. By default bodies aren't swept.
+    fn continuous_collision_detection(&self) -> bool {
+        false
+    }
+
+    /// Whether swept bodies are stopped by this body. See [Body::continuous_collision_detection].
+    /// By default, bodies that don't collide with others, such as immovable ones, stop sweeps.
+    fn stops_sweeps(&self) -> bool {
+        !self.collide_with_others()
+    }
+
+    /// How far the body moved during its last update. Only bodies that return Some can be put to sleep by the solver, see [super::solver::Sleeping].
+    /// By default bodies never sleep.
+    fn displacement(&self) -> Option<[T; 3]> {
+        None
+    }
+
+    /// Feeds everything that affects how the body will be simulated into the hasher, bit for bit.
+    /// Used by [super::solver::CpuSolver::state_hash].
+    ///
+    /// By default this only hashes the position and half size, so bodies with velocities should override it.
+    fn hash_state(&self, state: &mut dyn Hasher) {
+        if self.is_none() {
+            state.write_u8(0);
+            return;
+        }
+        state.write_u8(1);
+        hash_floats(state, &self.position_unchecked());
+        hash_floats(state, &self.half_size_unchecked());
+    }
+}
+
+/// Hashes the exact bits of each float, so that the same numbers always give the same hash, on any platform.
+#[inline]
+pub fn hash_floats<T: math::Float>(state: &mut dyn Hasher, floats: &[T]) {
+    for float in floats {
+        state.write_u64(float.to_bits_u64());
+    }
 }
 
 /// A premade enum for you to use as the body type for the [super::solver::CpuSolver].
 ///Player
 /// The name might change.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize + 'static",
+    deserialize = "T: Deserialize<'de> + 'static"
+))]
 pub enum CommonBody<T>
 where
     T: math::Float,
@@ -45,6 +164,15 @@ where
     Cuboid(verlet::bodies::Cuboid<T>),
     ImmovableCuboid(ImmovableCuboid<T>),
     CollisionRecorderCuboid(CollisionRecorderCuboid<T, CommonBody<T>>),
+    Sphere(verlet::bodies::Sphere<T>),
+    ImmovableSphere(ImmovableSphere<T>),
+    Capsule(verlet::bodies::Capsule<T>),
+    ImmovableCapsule(ImmovableCapsule<T>),
+    Cylinder(verlet::bodies::Cylinder<T>),
+    ImmovableCylinder(ImmovableCylinder<T>),
+    ImmovableObb(ImmovableObb<T>),
+    ImmovableTriangleMesh(ImmovableTriangleMesh<T>),
+    ImmovableHeightfield(ImmovableHeightfield<T>),
     None,
 }
 
@@ -61,6 +189,23 @@ where
             CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => {
                 Ok(collision_recorder_cuboid.aabb.position)
             }
+            CommonBody::Sphere(sphere) => Ok(sphere.particle.position),
+            CommonBody::ImmovableSphere(immovable_sphere) => Ok(immovable_sphere.sphere.position),
+            CommonBody::Capsule(capsule) => Ok(capsule.particle.position),
+            CommonBody::ImmovableCapsule(immovable_capsule) => {
+                Ok(immovable_capsule.capsule.position)
+            }
+            CommonBody::Cylinder(cylinder) => Ok(cylinder.particle.position),
+            CommonBody::ImmovableCylinder(immovable_cylinder) => {
+                Ok(immovable_cylinder.cylinder.position)
+            }
+            CommonBody::ImmovableObb(immovable_obb) => Ok(immovable_obb.obb.position),
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                Ok(immovable_triangle_mesh.mesh.bounds().position)
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                Ok(immovable_heightfield.heightfield.bounds().position)
+            }
             CommonBody::None => Err("CommonBody::None does not have a position."),
         }
     }
@@ -87,6 +232,45 @@ where
                     add_3d(collision_recorder_cuboid.aabb.position, translation);
                 Ok(())
             }
+            CommonBody::Sphere(sphere) => {
+                sphere.particle.apply_uniform_position_change(translation);
+                Ok(())
+            }
+            CommonBody::ImmovableSphere(immovable_sphere) => {
+                immovable_sphere.sphere.position =
+                    add_3d(immovable_sphere.sphere.position, translation);
+                Ok(())
+            }
+            CommonBody::Capsule(capsule) => {
+                capsule.particle.apply_uniform_position_change(translation);
+                Ok(())
+            }
+            CommonBody::ImmovableCapsule(immovable_capsule) => {
+                immovable_capsule.capsule.position =
+                    add_3d(immovable_capsule.capsule.position, translation);
+                Ok(())
+            }
+            CommonBody::Cylinder(cylinder) => {
+                cylinder.particle.apply_uniform_position_change(translation);
+                Ok(())
+            }
+            CommonBody::ImmovableCylinder(immovable_cylinder) => {
+                immovable_cylinder.cylinder.position =
+                    add_3d(immovable_cylinder.cylinder.position, translation);
+                Ok(())
+            }
+            CommonBody::ImmovableObb(immovable_obb) => {
+                immovable_obb.obb.position = add_3d(immovable_obb.obb.position, translation);
+                Ok(())
+            }
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                immovable_triangle_mesh.mesh.translate(translation);
+                Ok(())
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                immovable_heightfield.heightfield.translate(translation);
+                Ok(())
+            }
             CommonBody::None => Err("CommonBody::None does not have a position."),
         }
     }
@@ -107,6 +291,19 @@ where
             CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => Ok(
                 math::mul_3d_by_1d(collision_recorder_cuboid.aabb.half_size, T::from_f32(2.0)),
             ),
+            CommonBody::Sphere(sphere) => Ok([sphere.radius * T::from_f32(2.0); 3]),
+            CommonBody::ImmovableSphere(immovable_sphere) => {
+                Ok([immovable_sphere.sphere.radius * T::from_f32(2.0); 3])
+            }
+            CommonBody::Capsule(_)
+            | CommonBody::ImmovableCapsule(_)
+            | CommonBody::Cylinder(_)
+            | CommonBody::ImmovableCylinder(_)
+            | CommonBody::ImmovableObb(_)
+            | CommonBody::ImmovableTriangleMesh(_)
+            | CommonBody::ImmovableHeightfield(_) => {
+                Ok(math::mul_3d_by_1d(self.half_size()?, T::from_f32(2.0)))
+            }
             CommonBody::None => Err("CommonBody::None does not have a half_size."),
         }
     }
@@ -119,11 +316,80 @@ where
             CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => {
                 Ok(collision_recorder_cuboid.aabb.half_size)
             }
+            CommonBody::Sphere(sphere) => Ok([sphere.radius; 3]),
+            CommonBody::ImmovableSphere(immovable_sphere) => {
+                Ok([immovable_sphere.sphere.radius; 3])
+            }
+            CommonBody::Capsule(capsule) => {
+                Ok(capsule_half_size(capsule.radius, capsule.half_height))
+            }
+            CommonBody::ImmovableCapsule(immovable_capsule) => Ok(capsule_half_size(
+                immovable_capsule.capsule.radius,
+                immovable_capsule.capsule.half_height,
+            )),
+            CommonBody::Cylinder(cylinder) => {
+                Ok([cylinder.radius, cylinder.half_height, cylinder.radius])
+            }
+            CommonBody::ImmovableCylinder(immovable_cylinder) => Ok([
+                immovable_cylinder.cylinder.radius,
+                immovable_cylinder.cylinder.half_height,
+                immovable_cylinder.cylinder.radius,
+            ]),
+            CommonBody::ImmovableObb(immovable_obb) => Ok(immovable_obb.obb.enclosing_half_size()),
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                Ok(immovable_triangle_mesh.mesh.bounds().half_size)
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                Ok(immovable_heightfield.heightfield.bounds().half_size)
+            }
             CommonBody::None => Err("CommonBody::None does not have a half_size."),
         }
     }
 }
 
+impl<T> CommonBody<T>
+where
+    T: math::Float,
+{
+    /// Gets the shape of the body, for the bodies that share their collision code. Panics if it is CommonBody::None.
+    fn collider(&self) -> Collider<'_, T> {
+        match self {
+            CommonBody::Player(player) => Collider::Aabb(AabbCentredOrigin {
+                position: player.particle.position,
+                half_size: player.half_size,
+            }),
+            CommonBody::Cuboid(cuboid) => Collider::Aabb(AabbCentredOrigin {
+                position: cuboid.particle.position,
+                half_size: cuboid.half_size,
+            }),
+            CommonBody::ImmovableCuboid(immovable_cuboid) => Collider::Aabb(immovable_cuboid.aabb),
+            CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => {
+                Collider::Aabb(collision_recorder_cuboid.aabb)
+            }
+            CommonBody::Sphere(sphere) => Collider::Sphere(sphere.shape()),
+            CommonBody::ImmovableSphere(immovable_sphere) => {
+                Collider::Sphere(immovable_sphere.sphere)
+            }
+            CommonBody::Capsule(capsule) => Collider::Capsule(capsule.shape()),
+            CommonBody::ImmovableCapsule(immovable_capsule) => {
+                Collider::Capsule(immovable_capsule.capsule)
+            }
+            CommonBody::Cylinder(cylinder) => Collider::Cylinder(cylinder.shape()),
+            CommonBody::ImmovableCylinder(immovable_cylinder) => {
+                Collider::Cylinder(immovable_cylinder.cylinder)
+            }
+            CommonBody::ImmovableObb(immovable_obb) => Collider::Obb(immovable_obb.obb),
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                Collider::TriangleMesh(&immovable_triangle_mesh.mesh)
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                Collider::Heightfield(&immovable_heightfield.heightfield)
+            }
+            CommonBody::None => unreachable!(),
+        }
+    }
+}
+
 impl<T> Body<T> for CommonBody<T>
 where
     T: math::Float,
@@ -138,6 +404,15 @@ where
                 immovable_cuboid.update(gravity, dampening, delta_time)
             }
             CommonBody::CollisionRecorderCuboid(_) => (),
+            CommonBody::Sphere(sphere) => sphere.update(gravity, dampening, delta_time),
+            CommonBody::ImmovableSphere(_) => (),
+            CommonBody::Capsule(capsule) => capsule.update(gravity, dampening, delta_time),
+            CommonBody::ImmovableCapsule(_) => (),
+            CommonBody::Cylinder(cylinder) => cylinder.update(gravity, dampening, delta_time),
+            CommonBody::ImmovableCylinder(_) => (),
+            CommonBody::ImmovableObb(_) => (),
+            CommonBody::ImmovableTriangleMesh(_) => (),
+            CommonBody::ImmovableHeightfield(_) => (),
             CommonBody::None => unreachable!(),
         }
     }
@@ -150,6 +425,21 @@ where
             CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => {
                 collision_recorder_cuboid.aabb.position
             }
+            CommonBody::Sphere(sphere) => sphere.particle.position,
+            CommonBody::ImmovableSphere(immovable_sphere) => immovable_sphere.sphere.position,
+            CommonBody::Capsule(capsule) => capsule.particle.position,
+            CommonBody::ImmovableCapsule(immovable_capsule) => immovable_capsule.capsule.position,
+            CommonBody::Cylinder(cylinder) => cylinder.particle.position,
+            CommonBody::ImmovableCylinder(immovable_cylinder) => {
+                immovable_cylinder.cylinder.position
+            }
+            CommonBody::ImmovableObb(immovable_obb) => immovable_obb.obb.position,
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                immovable_triangle_mesh.mesh.bounds().position
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                immovable_heightfield.heightfield.bounds().position
+            }
             CommonBody::None => unreachable!(),
         }
     }
@@ -162,6 +452,53 @@ where
             CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => {
                 collision_recorder_cuboid.aabb.half_size
             }
+            CommonBody::Sphere(sphere) => [sphere.radius; 3],
+            CommonBody::ImmovableSphere(immovable_sphere) => [immovable_sphere.sphere.radius; 3],
+            CommonBody::Capsule(_)
+            | CommonBody::ImmovableCapsule(_)
+            | CommonBody::Cylinder(_)
+            | CommonBody::ImmovableCylinder(_)
+            | CommonBody::ImmovableObb(_)
+            | CommonBody::ImmovableTriangleMesh(_)
+            | CommonBody::ImmovableHeightfield(_) => self.half_size().unwrap(),
+            CommonBody::None => unreachable!(),
+        }
+    }
+
+    fn set_position_unchecked(&mut self, position: [T; 3]) {
+        match self {
+            CommonBody::Player(player) => player.particle.teleport(position),
+            CommonBody::Cuboid(cuboid) => cuboid.particle.teleport(position),
+            CommonBody::ImmovableCuboid(immovable_cuboid) => {
+                immovable_cuboid.aabb.position = position
+            }
+            CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => {
+                collision_recorder_cuboid.aabb.position = position
+            }
+            CommonBody::Sphere(sphere) => sphere.particle.teleport(position),
+            CommonBody::ImmovableSphere(immovable_sphere) => {
+                immovable_sphere.sphere.position = position
+            }
+            CommonBody::Capsule(capsule) => capsule.particle.teleport(position),
+            CommonBody::ImmovableCapsule(immovable_capsule) => {
+                immovable_capsule.capsule.position = position
+            }
+            CommonBody::Cylinder(cylinder) => cylinder.particle.teleport(position),
+            CommonBody::ImmovableCylinder(immovable_cylinder) => {
+                immovable_cylinder.cylinder.position = position
+            }
+            CommonBody::ImmovableObb(immovable_obb) => immovable_obb.obb.position = position,
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                let offset = math::sub_3d(position, immovable_triangle_mesh.mesh.bounds().position);
+                immovable_triangle_mesh.mesh.translate(offset)
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                let offset = math::sub_3d(
+                    position,
+                    immovable_heightfield.heightfield.bounds().position,
+                );
+                immovable_heightfield.heightfield.translate(offset)
+            }
             CommonBody::None => unreachable!(),
         }
     }
@@ -180,6 +517,15 @@ where
             CommonBody::Cuboid(_) => true,
             CommonBody::ImmovableCuboid(_) => false,
             CommonBody::CollisionRecorderCuboid(_) => false,
+            CommonBody::Sphere(_) => true,
+            CommonBody::ImmovableSphere(_) => false,
+            CommonBody::Capsule(_) => true,
+            CommonBody::ImmovableCapsule(_) => false,
+            CommonBody::Cylinder(_) => true,
+            CommonBody::ImmovableCylinder(_) => false,
+            CommonBody::ImmovableObb(_) => false,
+            CommonBody::ImmovableTriangleMesh(_) => false,
+            CommonBody::ImmovableHeightfield(_) => false,
             CommonBody::None => unreachable!(),
         }
     }
@@ -188,15 +534,73 @@ where
     fn respond_to_collision(
         &mut self,
         other: &mut CommonBody<T>,
-        _lhs_index: usize,
-        _rhs_index: usize,
+        lhs_index: usize,
+        rhs_index: usize,
         delta_time: T,
     ) {
+        // Each pair is only responded to once, with either body as lhs, but players need to know whether they are grounded, so they always go first.
+        if matches!(other, CommonBody::Player(_)) && !matches!(self, CommonBody::Player(_)) {
+            return other.respond_to_collision(self, rhs_index, lhs_index, delta_time);
+        }
+
+        let material = self.material().combine(&other.material());
         let colliding_bodies = (self, other);
         match colliding_bodies {
+            // Recorders never push anything, so whatever shape touched them, they only record it.
+            (lhs, CommonBody::CollisionRecorderCuboid(rhs_collision_recorder_cuboid)) => {
+                rhs_collision_recorder_cuboid.record_collision(lhs, lhs_index)
+            }
+
+            // capsules, cylinders, obbs, triangle meshes and heightfields share their collision code, rather than having an arm for every pair
+            (
+                lhs @ (CommonBody::Capsule(_)
+                | CommonBody::ImmovableCapsule(_)
+                | CommonBody::Cylinder(_)
+                | CommonBody::ImmovableCylinder(_)
+                | CommonBody::ImmovableObb(_)
+                | CommonBody::ImmovableTriangleMesh(_)
+                | CommonBody::ImmovableHeightfield(_)),
+                rhs,
+            )
+            | (
+                lhs,
+                rhs @ (CommonBody::Capsule(_)
+                | CommonBody::ImmovableCapsule(_)
+                | CommonBody::Cylinder(_)
+                | CommonBody::ImmovableCylinder(_)
+                | CommonBody::ImmovableObb(_)
+                | CommonBody::ImmovableTriangleMesh(_)
+                | CommonBody::ImmovableHeightfield(_)),
+            ) => respond_to_collision_between_colliders(lhs, rhs, material, delta_time),
+
             // player
-            (CommonBody::Player(_lhs_player), CommonBody::Player(_rhs_player)) => {
-                todo!();
+            (CommonBody::Player(lhs_player), CommonBody::Player(rhs_player)) => {
+                let lhs_player_aabb = AabbCentredOrigin {
+                    position: lhs_player.particle.position,
+                    half_size: lhs_player.half_size,
+                };
+                let rhs_player_aabb = AabbCentredOrigin {
+                    position: rhs_player.particle.position,
+                    half_size: rhs_player.half_size,
+                };
+
+                let (collision_normal, penetration) =
+                    lhs_player_aabb.get_collision_normal_and_penetration(&rhs_player_aabb);
+
+                match collision_normal[1] {
+                    Direction::Positive => lhs_player.grounded = true,
+                    Direction::Negative => rhs_player.grounded = true,
+                    Direction::None => (),
+                }
+
+                respond_to_collision_between_particles(
+                    &mut lhs_player.particle,
+                    &mut rhs_player.particle,
+                    math::direction_3d_to_signed_number_3d(collision_normal),
+                    penetration,
+                    material,
+                    delta_time,
+                );
             }
             (CommonBody::Player(lhs_player), CommonBody::Cuboid(rhs_cuboid)) => {
                 let lhs_player_aabb = AabbCentredOrigin {
@@ -210,55 +614,28 @@ where
 
                 let (collision_normal, penetration) =
                     lhs_player_aabb.get_collision_normal_and_penetration(&rhs_cuboid_aabb);
-                let collision_normal_signed_number =
-                    math::direction_3d_to_signed_number_3d(collision_normal);
-                let collision_translation = math::mul_3d_by_1d(
-                    collision_normal_signed_number,
-                    -penetration * T::from_f32(0.5),
-                );
-
-                lhs_player
-                    .particle
-                    .apply_uniform_position_change(collision_translation);
-                rhs_cuboid
-                    .particle
-                    .apply_uniform_position_change(math::neg_3d(collision_translation));
 
                 if Direction::Positive == collision_normal[1] {
                     lhs_player.grounded = true;
                 }
 
-                let impulse = physics_3d::calculate_collision_impulse(
-                    lhs_player.particle.calculate_velocity(delta_time),
-                    T::ONE,
-                    rhs_cuboid.particle.calculate_velocity(delta_time),
-                    T::ONE,
-                    collision_normal_signed_number,
-                    T::from_f32(0.5),
+                respond_to_collision_between_particles(
+                    &mut lhs_player.particle,
+                    &mut rhs_cuboid.particle,
+                    math::direction_3d_to_signed_number_3d(collision_normal),
+                    penetration,
+                    material,
+                    delta_time,
                 );
-                lhs_player.particle.apply_impulse(impulse, delta_time);
-                rhs_cuboid
-                    .particle
-                    .apply_impulse(math::neg_3d(impulse), delta_time);
             }
             (CommonBody::Player(lhs_player), CommonBody::ImmovableCuboid(rhs_immovable_cuboid)) => {
                 let lhs_player_aabb = AabbCentredOrigin {
                     position: lhs_player.particle.position,
                     half_size: lhs_player.half_size,
                 };
-                //println!("lhs: {:?}\nrhs: {:?}",lhs_player, rhs_immovable_cuboid.aabb);
 
                 let (collision_normal, penetration) = lhs_player_aabb
                     .get_collision_normal_and_penetration(&rhs_immovable_cuboid.aabb);
-                let collision_normal_signed_number =
-                    math::direction_3d_to_signed_number_3d(collision_normal);
-                let collision_translation =
-                    math::mul_3d_by_1d(collision_normal_signed_number, -penetration);
-                //println!("normal: {:?}", collision_normal);
-
-                lhs_player
-                    .particle
-                    .apply_uniform_position_change(collision_translation);
 
                 if Direction::Positive == collision_normal[1] {
                     lhs_player.grounded = true;
@@ -272,28 +649,65 @@ where
                     < T::from_f32(0.5);
                 */
 
-                let impulse = physics_3d::calculate_collision_impulse_with_immovable_rhs(
-                    lhs_player.particle.calculate_velocity(delta_time),
-                    T::ONE,
-                    collision_normal_signed_number,
-                    T::from_f32(0.5),
+                respond_to_collision_with_immovable(
+                    &mut lhs_player.particle,
+                    math::direction_3d_to_signed_number_3d(collision_normal),
+                    penetration,
+                    material,
+                    delta_time,
                 );
-                //println!("impulse: {:?}", impulse);
-                lhs_player.particle.apply_impulse(impulse, delta_time);
             }
-            (
-                CommonBody::Player(_),
-                CommonBody::CollisionRecorderCuboid(_rhs_collision_recorder_cuboid),
-            ) => {
-                todo!();
-                //(rhs_collision_recorder_cuboid.on_collision)(colliding_bodies.1);
+            (CommonBody::Player(lhs_player), CommonBody::Sphere(rhs_sphere)) => {
+                let rhs_sphere_shape = sphere::Sphere {
+                    position: rhs_sphere.particle.position,
+                    radius: rhs_sphere.radius,
+                };
+                // The sphere gives us the normal from the sphere to the player, so we flip it.
+                let (collision_normal, penetration) = rhs_sphere_shape
+                    .get_collision_normal_and_penetration_with_aabb(&AabbCentredOrigin {
+                        position: lhs_player.particle.position,
+                        half_size: lhs_player.half_size,
+                    });
+                let collision_normal = math::neg_3d(collision_normal);
+
+                if is_ground_normal(collision_normal) {
+                    lhs_player.grounded = true;
+                }
+
+                respond_to_collision_between_particles(
+                    &mut lhs_player.particle,
+                    &mut rhs_sphere.particle,
+                    collision_normal,
+                    penetration,
+                    material,
+                    delta_time,
+                );
             }
+            (CommonBody::Player(lhs_player), CommonBody::ImmovableSphere(rhs_immovable_sphere)) => {
+                let (collision_normal, penetration) = rhs_immovable_sphere
+                    .sphere
+                    .get_collision_normal_and_penetration_with_aabb(&AabbCentredOrigin {
+                        position: lhs_player.particle.position,
+                        half_size: lhs_player.half_size,
+                    });
+                let collision_normal = math::neg_3d(collision_normal);
 
-            // cuboid
-            (CommonBody::Cuboid(_lhs_cuboid), CommonBody::Player(_rhs_player)) => {
-                //todo!();
-                //println!("whoops");
+                if is_ground_normal(collision_normal) {
+                    lhs_player.grounded = true;
+                }
+
+                respond_to_collision_with_immovable(
+                    &mut lhs_player.particle,
+                    collision_normal,
+                    penetration,
+                    material,
+                    delta_time,
+                );
             }
+
+            // cuboid
+            // Players are always lhs, see the start of this function.
+            (CommonBody::Cuboid(_), CommonBody::Player(_)) => unreachable!(),
             (CommonBody::Cuboid(lhs_cuboid), CommonBody::Cuboid(rhs_cuboid)) => {
                 let lhs_cuboid_aabb = AabbCentredOrigin {
                     position: lhs_cuboid.particle.position,
@@ -305,32 +719,15 @@ where
                 };
                 let (collision_normal, penetration) =
                     lhs_cuboid_aabb.get_collision_normal_and_penetration(&rhs_cuboid_aabb);
-                let collision_normal_signed_number =
-                    math::direction_3d_to_signed_number_3d(collision_normal);
-                let collision_translation = math::mul_3d_by_1d(
-                    collision_normal_signed_number,
-                    -penetration * T::from_f32(0.5),
-                );
 
-                lhs_cuboid
-                    .particle
-                    .apply_uniform_position_change(collision_translation);
-                rhs_cuboid
-                    .particle
-                    .apply_uniform_position_change(math::neg_3d(collision_translation));
-
-                let impulse = physics_3d::calculate_collision_impulse(
-                    lhs_cuboid.particle.calculate_velocity(delta_time),
-                    T::ONE,
-                    rhs_cuboid.particle.calculate_velocity(delta_time),
-                    T::ONE,
-                    collision_normal_signed_number,
-                    T::from_f32(0.5),
+                respond_to_collision_between_particles(
+                    &mut lhs_cuboid.particle,
+                    &mut rhs_cuboid.particle,
+                    math::direction_3d_to_signed_number_3d(collision_normal),
+                    penetration,
+                    material,
+                    delta_time,
                 );
-                lhs_cuboid.particle.apply_impulse(impulse, delta_time);
-                rhs_cuboid
-                    .particle
-                    .apply_impulse(math::neg_3d(impulse), delta_time);
             }
             (CommonBody::Cuboid(lhs_cuboid), CommonBody::ImmovableCuboid(rhs_immovable_cuboid)) => {
                 let lhs_cuboid_aabb = AabbCentredOrigin {
@@ -339,29 +736,111 @@ where
                 };
                 let (collision_normal, penetration) = lhs_cuboid_aabb
                     .get_collision_normal_and_penetration(&rhs_immovable_cuboid.aabb);
-                let collision_normal_signed_number =
-                    math::direction_3d_to_signed_number_3d(collision_normal);
-                let collision_translation =
-                    math::mul_3d_by_1d(collision_normal_signed_number, -penetration);
-
-                lhs_cuboid
-                    .particle
-                    .apply_uniform_position_change(collision_translation);
-
-                let impulse = physics_3d::calculate_collision_impulse_with_immovable_rhs(
-                    lhs_cuboid.particle.calculate_velocity(delta_time),
-                    T::ONE,
-                    collision_normal_signed_number,
-                    T::from_f32(0.5),
+
+                respond_to_collision_with_immovable(
+                    &mut lhs_cuboid.particle,
+                    math::direction_3d_to_signed_number_3d(collision_normal),
+                    penetration,
+                    material,
+                    delta_time,
                 );
-                lhs_cuboid.particle.apply_impulse(impulse, delta_time);
             }
-            (
-                CommonBody::Cuboid(_),
-                CommonBody::CollisionRecorderCuboid(_rhs_collision_recorder_cuboid),
-            ) => {
-                todo!();
-                //(rhs_collision_recorder_cuboid.on_collision)(colliding_bodies.1);
+            (CommonBody::Cuboid(lhs_cuboid), CommonBody::Sphere(rhs_sphere)) => {
+                let rhs_sphere_shape = sphere::Sphere {
+                    position: rhs_sphere.particle.position,
+                    radius: rhs_sphere.radius,
+                };
+                let (collision_normal, penetration) = rhs_sphere_shape
+                    .get_collision_normal_and_penetration_with_aabb(&AabbCentredOrigin {
+                        position: lhs_cuboid.particle.position,
+                        half_size: lhs_cuboid.half_size,
+                    });
+
+                respond_to_collision_between_particles(
+                    &mut lhs_cuboid.particle,
+                    &mut rhs_sphere.particle,
+                    math::neg_3d(collision_normal),
+                    penetration,
+                    material,
+                    delta_time,
+                );
+            }
+            (CommonBody::Cuboid(lhs_cuboid), CommonBody::ImmovableSphere(rhs_immovable_sphere)) => {
+                let (collision_normal, penetration) = rhs_immovable_sphere
+                    .sphere
+                    .get_collision_normal_and_penetration_with_aabb(&AabbCentredOrigin {
+                        position: lhs_cuboid.particle.position,
+                        half_size: lhs_cuboid.half_size,
+                    });
+
+                respond_to_collision_with_immovable(
+                    &mut lhs_cuboid.particle,
+                    math::neg_3d(collision_normal),
+                    penetration,
+                    material,
+                    delta_time,
+                );
+            }
+
+            // sphere
+            // Players are always lhs, see the start of this function.
+            (CommonBody::Sphere(_), CommonBody::Player(_)) => unreachable!(),
+            (CommonBody::Sphere(lhs_sphere), CommonBody::Cuboid(rhs_cuboid)) => {
+                let (collision_normal, penetration) = lhs_sphere
+                    .shape()
+                    .get_collision_normal_and_penetration_with_aabb(&AabbCentredOrigin {
+                        position: rhs_cuboid.particle.position,
+                        half_size: rhs_cuboid.half_size,
+                    });
+
+                respond_to_collision_between_particles(
+                    &mut lhs_sphere.particle,
+                    &mut rhs_cuboid.particle,
+                    collision_normal,
+                    penetration,
+                    material,
+                    delta_time,
+                );
+            }
+            (CommonBody::Sphere(lhs_sphere), CommonBody::ImmovableCuboid(rhs_immovable_cuboid)) => {
+                let (collision_normal, penetration) = lhs_sphere
+                    .shape()
+                    .get_collision_normal_and_penetration_with_aabb(&rhs_immovable_cuboid.aabb);
+
+                respond_to_collision_with_immovable(
+                    &mut lhs_sphere.particle,
+                    collision_normal,
+                    penetration,
+                    material,
+                    delta_time,
+                );
+            }
+            (CommonBody::Sphere(lhs_sphere), CommonBody::Sphere(rhs_sphere)) => {
+                let (collision_normal, penetration) = lhs_sphere
+                    .shape()
+                    .get_collision_normal_and_penetration_with_sphere(&rhs_sphere.shape());
+
+                respond_to_collision_between_particles(
+                    &mut lhs_sphere.particle,
+                    &mut rhs_sphere.particle,
+                    collision_normal,
+                    penetration,
+                    material,
+                    delta_time,
+                );
+            }
+            (CommonBody::Sphere(lhs_sphere), CommonBody::ImmovableSphere(rhs_immovable_sphere)) => {
+                let (collision_normal, penetration) = lhs_sphere
+                    .shape()
+                    .get_collision_normal_and_penetration_with_sphere(&rhs_immovable_sphere.sphere);
+
+                respond_to_collision_with_immovable(
+                    &mut lhs_sphere.particle,
+                    collision_normal,
+                    penetration,
+                    material,
+                    delta_time,
+                );
             }
 
             // immovable simple cuboid (This cannot happen, as immovable simple cuboides don't check to see if they have collided with others.)
@@ -369,6 +848,8 @@ where
 
             (CommonBody::CollisionRecorderCuboid(_), _) => unreachable!(),
 
+            (CommonBody::ImmovableSphere(_), _) => unreachable!(),
+
             (CommonBody::None, _) => unreachable!(),
             (_, CommonBody::None) => unreachable!(),
         }
@@ -378,6 +859,28 @@ where
     fn detect_collision(&self, other: &CommonBody<T>) -> bool {
         let colliding_bodies = (self, other);
         match colliding_bodies {
+            // capsules, cylinders, obbs, triangle meshes and heightfields share their collision code, rather than having an arm for every pair
+            (
+                lhs @ (CommonBody::Capsule(_)
+                | CommonBody::ImmovableCapsule(_)
+                | CommonBody::Cylinder(_)
+                | CommonBody::ImmovableCylinder(_)
+                | CommonBody::ImmovableObb(_)
+                | CommonBody::ImmovableTriangleMesh(_)
+                | CommonBody::ImmovableHeightfield(_)),
+                rhs,
+            )
+            | (
+                lhs,
+                rhs @ (CommonBody::Capsule(_)
+                | CommonBody::ImmovableCapsule(_)
+                | CommonBody::Cylinder(_)
+                | CommonBody::ImmovableCylinder(_)
+                | CommonBody::ImmovableObb(_)
+                | CommonBody::ImmovableTriangleMesh(_)
+                | CommonBody::ImmovableHeightfield(_)),
+            ) => lhs.collider().is_intersected_by(&rhs.collider()),
+
             // player
             (CommonBody::Player(lhs_player), CommonBody::Player(rhs_player)) => {
                 let lhs_player_aabb = AabbCentredOrigin {
@@ -418,6 +921,20 @@ where
                 };
                 lhs_player_aabb.is_intersected_by_aabb(rhs_collision_recorder_cuboid.aabb)
             }
+            (CommonBody::Player(lhs_player), CommonBody::Sphere(rhs_sphere)) => rhs_sphere
+                .shape()
+                .is_intersected_by_aabb(AabbCentredOrigin {
+                    position: lhs_player.particle.position,
+                    half_size: lhs_player.half_size,
+                }),
+            (CommonBody::Player(lhs_player), CommonBody::ImmovableSphere(rhs_immovable_sphere)) => {
+                rhs_immovable_sphere
+                    .sphere
+                    .is_intersected_by_aabb(AabbCentredOrigin {
+                        position: lhs_player.particle.position,
+                        half_size: lhs_player.half_size,
+                    })
+            }
 
             // cuboid
             (CommonBody::Cuboid(lhs_cuboid), CommonBody::Player(rhs_player)) => {
@@ -459,36 +976,727 @@ where
                 };
                 lhs_cuboid_aabb.is_intersected_by_aabb(rhs_collision_recorder_cuboid.aabb)
             }
+            (CommonBody::Cuboid(lhs_cuboid), CommonBody::Sphere(rhs_sphere)) => rhs_sphere
+                .shape()
+                .is_intersected_by_aabb(AabbCentredOrigin {
+                    position: lhs_cuboid.particle.position,
+                    half_size: lhs_cuboid.half_size,
+                }),
+            (CommonBody::Cuboid(lhs_cuboid), CommonBody::ImmovableSphere(rhs_immovable_sphere)) => {
+                rhs_immovable_sphere
+                    .sphere
+                    .is_intersected_by_aabb(AabbCentredOrigin {
+                        position: lhs_cuboid.particle.position,
+                        half_size: lhs_cuboid.half_size,
+                    })
+            }
+
+            // sphere
+            (CommonBody::Sphere(lhs_sphere), CommonBody::Player(rhs_player)) => lhs_sphere
+                .shape()
+                .is_intersected_by_aabb(AabbCentredOrigin {
+                    position: rhs_player.particle.position,
+                    half_size: rhs_player.half_size,
+                }),
+            (CommonBody::Sphere(lhs_sphere), CommonBody::Cuboid(rhs_cuboid)) => lhs_sphere
+                .shape()
+                .is_intersected_by_aabb(AabbCentredOrigin {
+                    position: rhs_cuboid.particle.position,
+                    half_size: rhs_cuboid.half_size,
+                }),
+            (CommonBody::Sphere(lhs_sphere), CommonBody::ImmovableCuboid(rhs_immovable_cuboid)) => {
+                lhs_sphere
+                    .shape()
+                    .is_intersected_by_aabb(rhs_immovable_cuboid.aabb)
+            }
+            (
+                CommonBody::Sphere(lhs_sphere),
+                CommonBody::CollisionRecorderCuboid(rhs_collision_recorder_cuboid),
+            ) => lhs_sphere
+                .shape()
+                .is_intersected_by_aabb(rhs_collision_recorder_cuboid.aabb),
+            (CommonBody::Sphere(lhs_sphere), CommonBody::Sphere(rhs_sphere)) => lhs_sphere
+                .shape()
+                .is_intersected_by_sphere(rhs_sphere.shape()),
+            (CommonBody::Sphere(lhs_sphere), CommonBody::ImmovableSphere(rhs_immovable_sphere)) => {
+                lhs_sphere
+                    .shape()
+                    .is_intersected_by_sphere(rhs_immovable_sphere.sphere)
+            }
 
             // immovable simple cuboid (This cannot happen, as immovable simple cuboides don't check to see if they have collided with others.)
             (CommonBody::ImmovableCuboid(_), _) => unreachable!(),
 
             (CommonBody::CollisionRecorderCuboid(_), _) => unreachable!(),
 
+            (CommonBody::ImmovableSphere(_), _) => unreachable!(),
+
             (CommonBody::None, _) => unreachable!(),
             (_, CommonBody::None) => unreachable!(),
         }
     }
+
+    /// Finds the hit with the body's aabb first, and then for other shapes, creeps forward by the distance between the shapes until they touch.
+    fn cast_aabb(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+    ) -> Option<([T; 3], T)> {
+        match self {
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                return immovable_triangle_mesh.mesh.cast_aabb(
+                    origin,
+                    half_size,
+                    direction,
+                    max_distance,
+                );
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                return immovable_heightfield.heightfield.cast_aabb(
+                    origin,
+                    half_size,
+                    direction,
+                    max_distance,
+                );
+            }
+            _ => (),
+        }
+
+        let (aabb_normal, mut distance) = AabbCentredOrigin {
+            position: self.position_unchecked(),
+            half_size: add_3d(self.half_size_unchecked(), half_size),
+        }
+        .get_ray_intersection(origin, direction, max_distance)?;
+
+        let collider = self.collider();
+        if let Collider::Aabb(_) = collider {
+            return Some((aabb_normal, distance));
+        }
+
+        // How close counts as touching.
+        let tolerance = T::from_f32(0.0001);
+
+        for _ in 0..CAST_ITERATIONS {
+            let cast_collider =
+                Collider::Obb(obb::ObbCentredOrigin::from_aabb(&AabbCentredOrigin {
+                    position: math::add_3d(origin, math::mul_3d_by_1d(direction, distance)),
+                    half_size,
+                }));
+            let (collision_normal, penetration) =
+                collider.get_collision_normal_and_penetration(&cast_collider);
+
+            if penetration >= -tolerance {
+                return Some((collision_normal, distance));
+            }
+
+            // A negative penetration is never more than the distance between the shapes, so moving by it can't skip past the body.
+            distance -= penetration;
+            if distance > max_distance {
+                return None;
+            }
+        }
+
+        // Still not touching, so it is most likely skimming past the body.
+        None
+    }
+
+    fn get_collision_normal_and_penetration(&self, other: &CommonBody<T>) -> ([T; 3], T) {
+        self.collider()
+            .get_collision_normal_and_penetration(&other.collider())
+    }
+
+    fn is_intersected_by_aabb(&self, aabb: AabbCentredOrigin<T>) -> bool {
+        self.collider().is_intersected_by(&Collider::Aabb(aabb))
+    }
+
+    fn is_intersected_by_sphere(&self, sphere: sphere::Sphere<T>) -> bool {
+        self.collider().is_intersected_by(&Collider::Sphere(sphere))
+    }
+
+    fn particle_mut(&mut self) -> Option<&mut verlet::Particle<T>> {
+        match self {
+            CommonBody::Player(player) => Some(&mut player.particle),
+            CommonBody::Cuboid(cuboid) => Some(&mut cuboid.particle),
+            CommonBody::Sphere(sphere) => Some(&mut sphere.particle),
+            CommonBody::Capsule(capsule) => Some(&mut capsule.particle),
+            CommonBody::Cylinder(cylinder) => Some(&mut cylinder.particle),
+            CommonBody::ImmovableCuboid(_)
+            | CommonBody::CollisionRecorderCuboid(_)
+            | CommonBody::ImmovableSphere(_)
+            | CommonBody::ImmovableCapsule(_)
+            | CommonBody::ImmovableCylinder(_)
+            | CommonBody::ImmovableObb(_)
+            | CommonBody::ImmovableTriangleMesh(_)
+            | CommonBody::ImmovableHeightfield(_)
+            | CommonBody::None => None,
+        }
+    }
+
+    fn material(&self) -> PhysicsMaterial<T> {
+        match self {
+            CommonBody::Player(player) => player.material,
+            CommonBody::Cuboid(cuboid) => cuboid.material,
+            CommonBody::ImmovableCuboid(immovable_cuboid) => immovable_cuboid.material,
+            CommonBody::Sphere(sphere) => sphere.material,
+            CommonBody::ImmovableSphere(immovable_sphere) => immovable_sphere.material,
+            CommonBody::Capsule(capsule) => capsule.material,
+            CommonBody::ImmovableCapsule(immovable_capsule) => immovable_capsule.material,
+            CommonBody::Cylinder(cylinder) => cylinder.material,
+            CommonBody::ImmovableCylinder(immovable_cylinder) => immovable_cylinder.material,
+            CommonBody::ImmovableObb(immovable_obb) => immovable_obb.material,
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                immovable_triangle_mesh.material
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                immovable_heightfield.material
+            }
+            CommonBody::CollisionRecorderCuboid(_) | CommonBody::None => PhysicsMaterial::default(),
+        }
+    }
+
+    fn continuous_collision_detection(&self) -> bool {
+        match self {
+            CommonBody::Player(player) => player.continuous_collision_detection,
+            CommonBody::Cuboid(cuboid) => cuboid.continuous_collision_detection,
+            CommonBody::Sphere(sphere) => sphere.continuous_collision_detection,
+            CommonBody::Capsule(capsule) => capsule.continuous_collision_detection,
+            CommonBody::Cylinder(cylinder) => cylinder.continuous_collision_detection,
+            _ => false,
+        }
+    }
+
+    fn stops_sweeps(&self) -> bool {
+        match self {
+            // Collision recorders only record what passes through them.
+            CommonBody::CollisionRecorderCuboid(_) | CommonBody::None => false,
+            _ => !self.collide_with_others(),
+        }
+    }
+
+    fn displacement(&self) -> Option<[T; 3]> {
+        match self {
+            CommonBody::Cuboid(cuboid) => Some(cuboid.particle.calculate_displacement()),
+            CommonBody::Sphere(sphere) => Some(sphere.particle.calculate_displacement()),
+            CommonBody::Capsule(capsule) => Some(capsule.particle.calculate_displacement()),
+            CommonBody::Cylinder(cylinder) => Some(cylinder.particle.calculate_displacement()),
+            // Players are controlled every update, so putting them to sleep would only get in the way.
+            CommonBody::Player(_)
+            | CommonBody::ImmovableCuboid(_)
+            | CommonBody::CollisionRecorderCuboid(_)
+            | CommonBody::ImmovableSphere(_)
+            | CommonBody::ImmovableCapsule(_)
+            | CommonBody::ImmovableCylinder(_)
+            | CommonBody::ImmovableObb(_)
+            | CommonBody::ImmovableTriangleMesh(_)
+            | CommonBody::ImmovableHeightfield(_)
+            | CommonBody::None => None,
+        }
+    }
+
+    fn hash_state(&self, state: &mut dyn Hasher) {
+        // Each variant gets its own tag, so that different bodies with the same numbers don't hash the same.
+        match self {
+            CommonBody::Player(player) => {
+                state.write_u8(1);
+                hash_particle(state, &player.particle);
+                hash_floats(state, &[player.mass]);
+                hash_floats(state, &player.half_size);
+                hash_floats(state, &player.dampening);
+                state.write_u8(player.grounded as u8);
+                state.write_u8(player.continuous_collision_detection as u8);
+            }
+            CommonBody::Cuboid(cuboid) => {
+                state.write_u8(2);
+                hash_particle(state, &cuboid.particle);
+                hash_floats(state, &cuboid.half_size);
+                state.write_u8(cuboid.continuous_collision_detection as u8);
+            }
+            CommonBody::ImmovableCuboid(immovable_cuboid) => {
+                state.write_u8(3);
+                hash_floats(state, &immovable_cuboid.aabb.position);
+                hash_floats(state, &immovable_cuboid.aabb.half_size);
+            }
+            CommonBody::CollisionRecorderCuboid(collision_recorder_cuboid) => {
+                state.write_u8(4);
+                hash_floats(state, &collision_recorder_cuboid.aabb.position);
+                hash_floats(state, &collision_recorder_cuboid.aabb.half_size);
+                match collision_recorder_cuboid.stored_collider_index {
+                    Some(stored_collider_index) => {
+                        state.write_u8(1);
+                        state.write_u64(stored_collider_index as u64);
+                    }
+                    None => state.write_u8(0),
+                }
+            }
+            CommonBody::Sphere(sphere) => {
+                state.write_u8(5);
+                hash_particle(state, &sphere.particle);
+                hash_floats(state, &[sphere.radius]);
+                state.write_u8(sphere.continuous_collision_detection as u8);
+            }
+            CommonBody::ImmovableSphere(immovable_sphere) => {
+                state.write_u8(6);
+                hash_floats(state, &immovable_sphere.sphere.position);
+                hash_floats(state, &[immovable_sphere.sphere.radius]);
+            }
+            CommonBody::Capsule(capsule) => {
+                state.write_u8(7);
+                hash_particle(state, &capsule.particle);
+                hash_floats(state, &[capsule.radius, capsule.half_height]);
+                state.write_u8(capsule.continuous_collision_detection as u8);
+            }
+            CommonBody::ImmovableCapsule(immovable_capsule) => {
+                state.write_u8(8);
+                hash_floats(state, &immovable_capsule.capsule.position);
+                hash_floats(
+                    state,
+                    &[
+                        immovable_capsule.capsule.radius,
+                        immovable_capsule.capsule.half_height,
+                    ],
+                );
+            }
+            CommonBody::Cylinder(cylinder) => {
+                state.write_u8(9);
+                hash_particle(state, &cylinder.particle);
+                hash_floats(state, &[cylinder.radius, cylinder.half_height]);
+                state.write_u8(cylinder.continuous_collision_detection as u8);
+            }
+            CommonBody::ImmovableCylinder(immovable_cylinder) => {
+                state.write_u8(10);
+                hash_floats(state, &immovable_cylinder.cylinder.position);
+                hash_floats(
+                    state,
+                    &[
+                        immovable_cylinder.cylinder.radius,
+                        immovable_cylinder.cylinder.half_height,
+                    ],
+                );
+            }
+            CommonBody::ImmovableObb(immovable_obb) => {
+                state.write_u8(11);
+                hash_floats(state, &immovable_obb.obb.position);
+                hash_floats(state, &immovable_obb.obb.half_size);
+                for axis in &immovable_obb.obb.axes {
+                    hash_floats(state, axis);
+                }
+            }
+            CommonBody::ImmovableTriangleMesh(immovable_triangle_mesh) => {
+                state.write_u8(12);
+                for vertex in immovable_triangle_mesh.mesh.vertices() {
+                    hash_floats(state, vertex);
+                }
+                for triangle in immovable_triangle_mesh.mesh.triangles() {
+                    for index in triangle {
+                        state.write_u32(*index);
+                    }
+                }
+            }
+            CommonBody::ImmovableHeightfield(immovable_heightfield) => {
+                state.write_u8(13);
+                let heightfield = &immovable_heightfield.heightfield;
+                state.write_usize(heightfield.columns());
+                hash_floats(state, &heightfield.spacing());
+                hash_floats(state, &heightfield.origin());
+                hash_floats(state, heightfield.heights());
+            }
+            CommonBody::None => state.write_u8(0),
+        }
+        if !self.is_none() {
+            hash_material(state, &self.material());
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[inline]
+fn hash_material<T: math::Float>(state: &mut dyn Hasher, material: &PhysicsMaterial<T>) {
+    hash_floats(
+        state,
+        &[
+            material.static_friction,
+            material.dynamic_friction,
+            material.restitution,
+        ],
+    );
+    state.write_u8(material.friction_combine as u8);
+    state.write_u8(material.restitution_combine as u8);
+}
+
+/// Hashes everything about the particle, including what makes up its velocity.
+#[inline]
+fn hash_particle<T: math::Float>(state: &mut dyn Hasher, particle: &verlet::Particle<T>) {
+    hash_floats(state, &particle.position);
+    hash_floats(state, &particle.previous_position);
+    hash_floats(state, &particle.acceleration);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize, B: 'static",
+    deserialize = "T: Deserialize<'de>, B: 'static"
+))]
 pub struct CollisionRecorderCuboid<T, B>
 where
     T: math::Float,
     B: Body<T>,
 {
     pub aabb: AabbCentredOrigin<T>,
+    /// Saved as the name it was registered under, so it must be registered with [physics_3d::function_registry::register_function].
+    #[serde(with = "physics_3d::function_registry::serde_function")]
     pub save_collision: fn(&mut B) -> bool,
     pub stored_collider_index: Option<usize>,
     // TODO: multiple collisions behaviour?
 }
 
-#[derive(Debug, Clone)]
+impl<T, B> CollisionRecorderCuboid<T, B>
+where
+    T: math::Float,
+    B: Body<T>,
+{
+    /// Called when a body collides with the recorder. If [CollisionRecorderCuboid::save_collision] returns true, the body's index is stored.
+    /// The recorder never pushes the body, it only records it.
+    #[inline]
+    pub fn record_collision(&mut self, collider: &mut B, collider_index: usize) {
+        if (self.save_collision)(collider) {
+            self.stored_collider_index = Some(collider_index);
+        }
+    }
+}
+
+/// The shape of a body. Lets bodies share their collision code, rather than needing a match arm for every pair of bodies.
+#[derive(Debug, Clone, Copy)]
+enum Collider<'a, T>
+where
+    T: math::Float,
+{
+    Aabb(AabbCentredOrigin<T>),
+    Sphere(sphere::Sphere<T>),
+    Capsule(capsule::Capsule<T>),
+    Cylinder(cylinder::Cylinder<T>),
+    Obb(obb::ObbCentredOrigin<T>),
+    TriangleMesh(&'a triangle_mesh::TriangleMesh<T>),
+    Heightfield(&'a heightfield::Heightfield<T>),
+}
+
+impl<T> Collider<'_, T>
+where
+    T: math::Float,
+{
+    fn is_intersected_by(&self, other: &Collider<T>) -> bool {
+        match (self, other) {
+            (Collider::Aabb(lhs_aabb), Collider::Aabb(rhs_aabb)) => {
+                lhs_aabb.is_intersected_by_aabb(*rhs_aabb)
+            }
+            _ => self.get_collision_normal_and_penetration(other).1 >= T::ZERO,
+        }
+    }
+
+    /// The normal points from self towards other, and the penetration is how far they are inside each other.
+    fn get_collision_normal_and_penetration(&self, other: &Collider<T>) -> ([T; 3], T) {
+        // Flips a result that was worked out from other's point of view.
+        let flip = |(collision_normal, penetration): ([T; 3], T)| {
+            (math::neg_3d(collision_normal), penetration)
+        };
+
+        match (self, other) {
+            (Collider::Aabb(lhs), Collider::Aabb(rhs)) => {
+                let (collision_normal, penetration) = lhs.get_collision_normal_and_penetration(rhs);
+                (
+                    math::direction_3d_to_signed_number_3d(collision_normal),
+                    penetration,
+                )
+            }
+            (Collider::Aabb(lhs), Collider::Sphere(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_aabb(lhs))
+            }
+            (Collider::Aabb(lhs), Collider::Capsule(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_aabb(lhs))
+            }
+            (Collider::Aabb(lhs), Collider::Cylinder(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_aabb(lhs))
+            }
+            (Collider::Aabb(lhs), Collider::Obb(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_aabb(lhs))
+            }
+
+            (Collider::Sphere(lhs), Collider::Aabb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_aabb(rhs)
+            }
+            (Collider::Sphere(lhs), Collider::Sphere(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_sphere(rhs)
+            }
+            (Collider::Sphere(lhs), Collider::Capsule(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_sphere(lhs))
+            }
+            (Collider::Sphere(lhs), Collider::Cylinder(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_sphere(lhs))
+            }
+            (Collider::Sphere(lhs), Collider::Obb(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_sphere(lhs))
+            }
+
+            (Collider::Capsule(lhs), Collider::Aabb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_aabb(rhs)
+            }
+            (Collider::Capsule(lhs), Collider::Sphere(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_sphere(rhs)
+            }
+            (Collider::Capsule(lhs), Collider::Capsule(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_capsule(rhs)
+            }
+            (Collider::Capsule(lhs), Collider::Cylinder(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_cylinder(rhs)
+            }
+            (Collider::Capsule(lhs), Collider::Obb(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_capsule(lhs))
+            }
+
+            (Collider::Cylinder(lhs), Collider::Aabb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_aabb(rhs)
+            }
+            (Collider::Cylinder(lhs), Collider::Sphere(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_sphere(rhs)
+            }
+            (Collider::Cylinder(lhs), Collider::Capsule(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_cylinder(lhs))
+            }
+            (Collider::Cylinder(lhs), Collider::Cylinder(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_cylinder(rhs)
+            }
+            (Collider::Cylinder(lhs), Collider::Obb(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_cylinder(lhs))
+            }
+
+            (Collider::Obb(lhs), Collider::Aabb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_aabb(rhs)
+            }
+            (Collider::Obb(lhs), Collider::Sphere(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_sphere(rhs)
+            }
+            (Collider::Obb(lhs), Collider::Capsule(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_capsule(rhs)
+            }
+            (Collider::Obb(lhs), Collider::Cylinder(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_cylinder(rhs)
+            }
+            (Collider::Obb(lhs), Collider::Obb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_obb(rhs)
+            }
+
+            (Collider::TriangleMesh(lhs), Collider::Aabb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_aabb(rhs)
+            }
+            (Collider::TriangleMesh(lhs), Collider::Sphere(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_sphere(rhs)
+            }
+            (Collider::TriangleMesh(lhs), Collider::Capsule(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_capsule(rhs)
+            }
+            (Collider::TriangleMesh(lhs), Collider::Cylinder(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_cylinder(rhs)
+            }
+            (Collider::TriangleMesh(lhs), Collider::Obb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_obb(rhs)
+            }
+            (Collider::Aabb(lhs), Collider::TriangleMesh(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_aabb(lhs))
+            }
+            (Collider::Sphere(lhs), Collider::TriangleMesh(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_sphere(lhs))
+            }
+            (Collider::Capsule(lhs), Collider::TriangleMesh(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_capsule(lhs))
+            }
+            (Collider::Cylinder(lhs), Collider::TriangleMesh(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_cylinder(lhs))
+            }
+            (Collider::Obb(lhs), Collider::TriangleMesh(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_obb(lhs))
+            }
+
+            (Collider::Heightfield(lhs), Collider::Aabb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_aabb(rhs)
+            }
+            (Collider::Heightfield(lhs), Collider::Sphere(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_sphere(rhs)
+            }
+            (Collider::Heightfield(lhs), Collider::Capsule(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_capsule(rhs)
+            }
+            (Collider::Heightfield(lhs), Collider::Cylinder(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_cylinder(rhs)
+            }
+            (Collider::Heightfield(lhs), Collider::Obb(rhs)) => {
+                lhs.get_collision_normal_and_penetration_with_obb(rhs)
+            }
+            (Collider::Aabb(lhs), Collider::Heightfield(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_aabb(lhs))
+            }
+            (Collider::Sphere(lhs), Collider::Heightfield(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_sphere(lhs))
+            }
+            (Collider::Capsule(lhs), Collider::Heightfield(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_capsule(lhs))
+            }
+            (Collider::Cylinder(lhs), Collider::Heightfield(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_cylinder(lhs))
+            }
+            (Collider::Obb(lhs), Collider::Heightfield(rhs)) => {
+                flip(rhs.get_collision_normal_and_penetration_with_obb(lhs))
+            }
+
+            // Triangle meshes and heightfields can't move, so they never collide with each other.
+            (
+                Collider::TriangleMesh(_) | Collider::Heightfield(_),
+                Collider::TriangleMesh(_) | Collider::Heightfield(_),
+            ) => ([T::ZERO; 3], -T::MAX),
+        }
+    }
+}
+
+/// Responds to a collision using the [Collider]s of the bodies.
+#[inline]
+fn respond_to_collision_between_colliders<T: math::Float>(
+    lhs: &mut CommonBody<T>,
+    rhs: &mut CommonBody<T>,
+    material: CombinedMaterial<T>,
+    delta_time: T,
+) {
+    // Recorders are handled before getting here, as they only record bodies rather than pushing them.
+    if let CommonBody::CollisionRecorderCuboid(_) = rhs {
+        return;
+    }
+
+    let (collision_normal, penetration) = lhs
+        .collider()
+        .get_collision_normal_and_penetration(&rhs.collider());
+
+    if let CommonBody::Player(lhs_player) = lhs {
+        if is_ground_normal(collision_normal) {
+            lhs_player.grounded = true;
+        }
+    }
+    if let CommonBody::Player(rhs_player) = rhs {
+        if is_ground_normal(math::neg_3d(collision_normal)) {
+            rhs_player.grounded = true;
+        }
+    }
+
+    match (lhs.particle_mut(), rhs.particle_mut()) {
+        (Some(lhs_particle), Some(rhs_particle)) => respond_to_collision_between_particles(
+            lhs_particle,
+            rhs_particle,
+            collision_normal,
+            penetration,
+            material,
+            delta_time,
+        ),
+        (Some(lhs_particle), None) => respond_to_collision_with_immovable(
+            lhs_particle,
+            collision_normal,
+            penetration,
+            material,
+            delta_time,
+        ),
+        // Immovable bodies don't check to see if they have collided with others.
+        (None, _) => unreachable!(),
+    }
+}
+
+/// The most times [CommonBody::cast_aabb] will creep forwards before giving up.
+pub(super) const CAST_ITERATIONS: usize = 32;
+
+/// The half size of the aabb that fits around an upright capsule.
+#[inline]
+fn capsule_half_size<T: math::Float>(radius: T, half_height: T) -> [T; 3] {
+    [radius, half_height + radius, radius]
+}
+
+/// Pushes 2 movable particles out of each other equally, then applies the collision impulse and friction to both.
+/// The collision normal should point from lhs towards rhs.
+#[inline]
+fn respond_to_collision_between_particles<T: math::Float>(
+    lhs_particle: &mut verlet::Particle<T>,
+    rhs_particle: &mut verlet::Particle<T>,
+    collision_normal: [T; 3],
+    penetration: T,
+    material: CombinedMaterial<T>,
+    delta_time: T,
+) {
+    let collision_translation =
+        math::mul_3d_by_1d(collision_normal, -penetration * T::from_f32(0.5));
+
+    lhs_particle.apply_uniform_position_change(collision_translation);
+    rhs_particle.apply_uniform_position_change(math::neg_3d(collision_translation));
+
+    let lhs_velocity = lhs_particle.calculate_velocity(delta_time);
+    let rhs_velocity = rhs_particle.calculate_velocity(delta_time);
+    let impulse = physics_3d::calculate_collision_impulse(
+        lhs_velocity,
+        T::ONE,
+        rhs_velocity,
+        T::ONE,
+        collision_normal,
+        material.restitution,
+    );
+    let friction_impulse = physics_3d::calculate_friction_impulse(
+        math::sub_3d(lhs_velocity, rhs_velocity),
+        T::from_f32(2.0),
+        collision_normal,
+        impulse,
+        material,
+    );
+    lhs_particle.apply_impulse(impulse, delta_time);
+    rhs_particle.apply_impulse(math::neg_3d(impulse), delta_time);
+    lhs_particle.apply_friction_impulse(friction_impulse, delta_time);
+    rhs_particle.apply_friction_impulse(math::neg_3d(friction_impulse), delta_time);
+}
+
+/// Pushes a movable particle out of something immovable, then applies the collision impulse and friction to it.
+/// The collision normal should point from the particle towards the immovable thing.
+#[inline]
+pub(super) fn respond_to_collision_with_immovable<T: math::Float>(
+    particle: &mut verlet::Particle<T>,
+    collision_normal: [T; 3],
+    penetration: T,
+    material: CombinedMaterial<T>,
+    delta_time: T,
+) {
+    particle.apply_uniform_position_change(math::mul_3d_by_1d(collision_normal, -penetration));
+
+    let velocity = particle.calculate_velocity(delta_time);
+    let impulse = physics_3d::calculate_collision_impulse_with_immovable_rhs(
+        velocity,
+        T::ONE,
+        collision_normal,
+        material.restitution,
+    );
+    let friction_impulse = physics_3d::calculate_friction_impulse(
+        velocity,
+        T::ONE,
+        collision_normal,
+        impulse,
+        material,
+    );
+    particle.apply_impulse(impulse, delta_time);
+    particle.apply_friction_impulse(friction_impulse, delta_time);
+}
+
+/// Whether something with this collision normal is being stood on. +y is down, so the normal has to point mostly towards +y.
+#[inline]
+fn is_ground_normal<T: math::Float>(collision_normal: [T; 3]) -> bool {
+    collision_normal[1] > T::from_f32(0.5)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImmovableCuboid<T>
 where
     T: math::Float,
 {
     pub aabb: AabbCentredOrigin<T>,
+    pub material: PhysicsMaterial<T>,
 }
 
 impl<T> ImmovableCuboid<T>
@@ -499,5 +1707,64 @@ where
     pub fn update(&mut self, _gravity: [T; 3], _dampening: [T; 3], _delta_time: T) {}
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImmovableSphere<T>
+where
+    T: math::Float,
+{
+    pub sphere: sphere::Sphere<T>,
+    pub material: PhysicsMaterial<T>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImmovableCapsule<T>
+where
+    T: math::Float,
+{
+    pub capsule: capsule::Capsule<T>,
+    pub material: PhysicsMaterial<T>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImmovableCylinder<T>
+where
+    T: math::Float,
+{
+    pub cylinder: cylinder::Cylinder<T>,
+    pub material: PhysicsMaterial<T>,
+}
+
+/// A rotated cuboid that can't move. Useful for ramps and tilted walls.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImmovableObb<T>
+where
+    T: math::Float,
+{
+    pub obb: obb::ObbCentredOrigin<T>,
+    pub material: PhysicsMaterial<T>,
+}
+
+/// A mesh of triangles that can't move. Useful for level geometry that boxes can't approximate, such as sculpted terrain.
+/// See [triangle_mesh::TriangleMesh].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImmovableTriangleMesh<T>
+where
+    T: math::Float,
+{
+    pub mesh: triangle_mesh::TriangleMesh<T>,
+    pub material: PhysicsMaterial<T>,
+}
+
+/// Terrain that can't move. Much cheaper than a triangle mesh of the same terrain, as it only checks the cells under each shape.
+/// See [heightfield::Heightfield].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImmovableHeightfield<T>
+where
+    T: math::Float,
+{
+    pub heightfield: heightfield::Heightfield<T>,
+    pub material: PhysicsMaterial<T>,
+}
+
 // TODO: List of common shaps I want to include here. But first, a naming scheme. No rotation should be by default. Axis aligned should be the default. At least 1 particle should be the default. As such "cuboid" should refer to an axis aligned cuboid with a single particle that can't rotate.
 // List: Cuboid, ImmovableCuboid, Sphere, ImmovableSphere, Player, Cylinder, ImmovableCylinder
@@ -0,0 +1,538 @@
+use std::{cell::RefCell, fmt::Debug};
+
+use serde::{Deserialize, Serialize};
+
+use crate::math::{self, Float};
+
+use super::{
+    aabb::{AabbCentredOrigin, AabbMinMax},
+    bvh::Bvh,
+    spatial_hash::SpatialHash,
+    sweep_and_prune::SweepAndPrune,
+    uniform_grid::UniformGrid,
+};
+
+/// Finds which bodies are near each other, so that the solver only has to check those for collisions, and queries don't have to look at every body.
+///
+/// Each substep the solver either clears the broadphase, or retains the sleeping bodies, then inserts every other body, then calls [Broadphase::finish_inserting].
+/// Pairs and queries are only asked for after that, and use the aabbs the bodies were inserted with.
+///
+/// Implement this to plug your own broadphase into [super::solver::CpuSolver], through [CommonBroadphase::Custom].
+pub trait Broadphase<T>: Send + Sync + Debug
+where
+    T: Float,
+{
+    /// Removes every body, except the ones keep returns true for, which are left as they are.
+    fn retain(&mut self, keep: &dyn Fn(usize) -> bool);
+
+    /// Removes every body.
+    fn clear(&mut self) {
+        self.retain(&|_| false);
+    }
+
+    /// Adds the body, with the aabb it has until it is next inserted.
+    fn insert(&mut self, body_index: usize, aabb: AabbCentredOrigin<T>);
+
+    /// Called once every body has been inserted, for anything that is cheaper to do all at once, such as sorting.
+    fn finish_inserting(&mut self) {}
+
+    /// Gives every pair of bodies whose aabbs overlap to on_pair, once each, in any order. Pairs where neither body can_detect are skipped.
+    fn find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        on_pair: &mut dyn FnMut(usize, usize),
+    );
+
+    /// Like [Broadphase::find_pairs], but on multiple threads, each finding pairs into its own buffer.
+    /// By default this finds them all on the current thread.
+    fn par_find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+    ) -> Vec<Vec<(usize, usize)>> {
+        let mut pairs = vec![];
+        self.find_pairs(can_detect, &mut |lhs_body_index, rhs_body_index| {
+            pairs.push((lhs_body_index, rhs_body_index))
+        });
+        vec![pairs]
+    }
+
+    /// Adds every body whose aabb might overlap the aabb to results. Bodies may be added more than once.
+    fn extend_with_candidates(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>);
+
+    /// Gives every body that an aabb with the half size might hit, when swept from origin along the normalised direction, to cast_against.
+    /// cast_against returns how far the cast still needs to go, so that the broadphase can stop once nothing closer can be hit. Bodies may be given more than once.
+    ///
+    /// By default this gives every candidate in the aabb around the whole cast.
+    fn cast(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        cast_against: &mut dyn FnMut(usize) -> T,
+    ) {
+        let end = math::add_3d(origin, math::mul_3d_by_1d(direction, max_distance));
+        let swept_aabb = AabbMinMax {
+            min: math::sub_3d(origin, half_size),
+            max: math::add_3d(origin, half_size),
+        }
+        .union(AabbMinMax {
+            min: math::sub_3d(end, half_size),
+            max: math::add_3d(end, half_size),
+        });
+
+        with_thread_buffer(|candidates| {
+            self.extend_with_candidates(swept_aabb.into(), candidates);
+            for &body_index in candidates.iter() {
+                cast_against(body_index);
+            }
+        });
+    }
+
+    /// The min and max corners of where bodies can be, should the broadphase have any bounds.
+    /// Bodies outside of them are dealt with by [super::solver::OutsideOfGridBoundsBehaviour]. By default there are none.
+    fn bounds(&self) -> Option<([T; 3], [T; 3])> {
+        None
+    }
+}
+
+/// A premade enum with every broadphase, which is what [super::solver::CpuSolver] uses. Which one it starts with is chosen by [super::solver::BroadphaseMethod].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Deserialize<'de>"))]
+pub enum CommonBroadphase<T>
+where
+    T: Float,
+{
+    UniformGrid(UniformGrid<T>),
+    SpatialHash(SpatialHash<T>),
+    SweepAndPrune(SweepAndPrune<T>),
+    Bvh(Bvh<T>),
+    /// Your own broadphase. It can't be saved, so solvers using it fail to serialize.
+    #[serde(skip)]
+    Custom(Box<dyn Broadphase<T>>),
+}
+
+impl<T> CommonBroadphase<T>
+where
+    T: Float,
+{
+    #[inline]
+    fn inner(&self) -> &dyn Broadphase<T> {
+        match self {
+            CommonBroadphase::UniformGrid(uniform_grid) => uniform_grid,
+            CommonBroadphase::SpatialHash(spatial_hash) => spatial_hash,
+            CommonBroadphase::SweepAndPrune(sweep_and_prune) => sweep_and_prune,
+            CommonBroadphase::Bvh(bvh) => bvh,
+            CommonBroadphase::Custom(custom) => custom.as_ref(),
+        }
+    }
+
+    #[inline]
+    fn inner_mut(&mut self) -> &mut dyn Broadphase<T> {
+        match self {
+            CommonBroadphase::UniformGrid(uniform_grid) => uniform_grid,
+            CommonBroadphase::SpatialHash(spatial_hash) => spatial_hash,
+            CommonBroadphase::SweepAndPrune(sweep_and_prune) => sweep_and_prune,
+            CommonBroadphase::Bvh(bvh) => bvh,
+            CommonBroadphase::Custom(custom) => custom.as_mut(),
+        }
+    }
+}
+
+impl<T> Broadphase<T> for CommonBroadphase<T>
+where
+    T: Float,
+{
+    #[inline]
+    fn retain(&mut self, keep: &dyn Fn(usize) -> bool) {
+        self.inner_mut().retain(keep);
+    }
+
+    #[inline]
+    fn clear(&mut self) {
+        self.inner_mut().clear();
+    }
+
+    #[inline]
+    fn insert(&mut self, body_index: usize, aabb: AabbCentredOrigin<T>) {
+        self.inner_mut().insert(body_index, aabb);
+    }
+
+    #[inline]
+    fn finish_inserting(&mut self) {
+        self.inner_mut().finish_inserting();
+    }
+
+    #[inline]
+    fn find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        on_pair: &mut dyn FnMut(usize, usize),
+    ) {
+        self.inner().find_pairs(can_detect, on_pair);
+    }
+
+    #[inline]
+    fn par_find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+    ) -> Vec<Vec<(usize, usize)>> {
+        self.inner().par_find_pairs(can_detect)
+    }
+
+    #[inline]
+    fn extend_with_candidates(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>) {
+        self.inner().extend_with_candidates(aabb, results);
+    }
+
+    #[inline]
+    fn cast(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        cast_against: &mut dyn FnMut(usize) -> T,
+    ) {
+        self.inner()
+            .cast(origin, half_size, direction, max_distance, cast_against);
+    }
+
+    #[inline]
+    fn bounds(&self) -> Option<([T; 3], [T; 3])> {
+        self.inner().bounds()
+    }
+}
+
+/// Stores the aabb at the body index, growing aabbs to fit it if needed.
+#[inline]
+pub(super) fn store_aabb<T: Float>(
+    aabbs: &mut Vec<AabbMinMax<T>>,
+    body_index: usize,
+    aabb: AabbMinMax<T>,
+) {
+    if aabbs.len() <= body_index {
+        aabbs.resize(
+            body_index + 1,
+            AabbMinMax {
+                min: [T::ZERO; 3],
+                max: [T::ZERO; 3],
+            },
+        );
+    }
+    aabbs[body_index] = aabb;
+}
+
+thread_local! {
+    static THREAD_BUFFERS: RefCell<Vec<Vec<usize>>> = const { RefCell::new(vec![]) };
+}
+
+/// Lends f an empty buffer that the thread keeps between calls, for queries that can't be given one, so that they don't allocate every time.
+/// Each call gets its own buffer, so f can query again, such as from a cast's cast_against.
+#[inline]
+pub(super) fn with_thread_buffer<R>(f: impl FnOnce(&mut Vec<usize>) -> R) -> R {
+    let mut buffer = THREAD_BUFFERS
+        .with_borrow_mut(|buffers| buffers.pop())
+        .unwrap_or_default();
+    buffer.clear();
+    let result = f(&mut buffer);
+    THREAD_BUFFERS.with_borrow_mut(|buffers| buffers.push(buffer));
+    result
+}
+
+/// Reusable buffers for sorting the bodies in a cell by whether they can detect collisions.
+#[derive(Default)]
+pub(super) struct CellScratch {
+    detecting: Vec<usize>,
+    others: Vec<usize>,
+}
+
+/// Gives every pair of bodies in the cell whose aabbs overlap to on_pair, skipping pairs where neither can_detect.
+///
+/// Bodies that share several cells would be found by all of them, so only the cell that contains the corner of where their aabbs overlap, according to contains_point, gives the pair.
+/// That corner is made of the aabbs' own min values, so as long as bodies were put into cells using the same aabbs in aabbs, and the same rounding as contains_point, that cell has both bodies in it.
+#[inline]
+pub(super) fn find_pairs_in_cell<T: Float>(
+    cell: &[usize],
+    aabbs: &[AabbMinMax<T>],
+    can_detect: &(dyn Fn(usize) -> bool + Sync),
+    scratch: &mut CellScratch,
+    contains_point: impl Fn([T; 3]) -> bool,
+    mut on_pair: impl FnMut(usize, usize),
+) {
+    // Cells that are entirely asleep or immovable are common, and have nothing to find.
+    if !cell.iter().any(|&body_index| can_detect(body_index)) {
+        return;
+    }
+
+    scratch.detecting.clear();
+    scratch.others.clear();
+    for &body_index in cell {
+        if can_detect(body_index) {
+            scratch.detecting.push(body_index);
+        } else {
+            scratch.others.push(body_index);
+        }
+    }
+
+    let mut find_pair = |lhs_body_index: usize, rhs_body_index: usize| {
+        let Some(overlap_corner) = overlap_corner(aabbs[lhs_body_index], aabbs[rhs_body_index])
+        else {
+            return;
+        };
+        if contains_point(overlap_corner) {
+            on_pair(lhs_body_index, rhs_body_index);
+        }
+    };
+
+    for (position_in_cell, &lhs_body_index) in scratch.detecting.iter().enumerate() {
+        for &rhs_body_index in &scratch.detecting[position_in_cell + 1..] {
+            find_pair(lhs_body_index, rhs_body_index);
+        }
+        for &rhs_body_index in &scratch.others {
+            find_pair(lhs_body_index, rhs_body_index);
+        }
+    }
+}
+
+/// The minimum corner of where the aabbs overlap, or None if they don't.
+#[inline]
+fn overlap_corner<T: Float>(lhs: AabbMinMax<T>, rhs: AabbMinMax<T>) -> Option<[T; 3]> {
+    let mut corner = [T::ZERO; 3];
+    for (axis, corner) in corner.iter_mut().enumerate() {
+        if lhs.max[axis] < rhs.min[axis] || rhs.max[axis] < lhs.min[axis] {
+            return None;
+        }
+        *corner = if lhs.min[axis] > rhs.min[axis] {
+            lhs.min[axis]
+        } else {
+            rhs.min[axis]
+        };
+    }
+    Some(corner)
+}
+
+/// Walks through a box of cells along the cast, one cell at a time (DDA), giving the bodies in the cells the cast aabb reaches to cast_against.
+/// It stops once every cell left is further away than the distance cast_against last returned.
+///
+/// cells_origin is the minimum corner of cell [0, 0, 0], and cell gets the body indices in the cell at a position from [0, 0, 0] to cells_size - 1.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn cast_through_cells<'a, T: Float>(
+    origin: [T; 3],
+    half_size: [T; 3],
+    direction: [T; 3],
+    mut max_distance: T,
+    cells_origin: [T; 3],
+    cell_size: [T; 3],
+    cells_size: [isize; 3],
+    cell: impl Fn([isize; 3]) -> &'a [usize],
+    cast_against: &mut dyn FnMut(usize) -> T,
+) {
+    let real_half_size = [
+        T::from_f64(cells_size[0] as f64 / 2.0) * cell_size[0],
+        T::from_f64(cells_size[1] as f64 / 2.0) * cell_size[1],
+        T::from_f64(cells_size[2] as f64 / 2.0) * cell_size[2],
+    ];
+
+    // Skip straight to where the cast enters the cells.
+    let Some((_, start_distance)) = (AabbCentredOrigin {
+        position: math::add_3d(cells_origin, real_half_size),
+        half_size: math::add_3d(real_half_size, half_size),
+    })
+    .get_ray_intersection(origin, direction, max_distance) else {
+        return;
+    };
+
+    let start_position = math::sub_3d(
+        math::add_3d(origin, math::mul_3d_by_1d(direction, start_distance)),
+        cells_origin,
+    );
+
+    let mut cell_position = [0; 3];
+    // The cast aabb reaches into the cells around the one its centre is in.
+    let mut padding = [0; 3];
+    let mut step = [0; 3];
+    let mut next_cell_distance = [T::MAX; 3];
+    let mut cell_distance_step = [T::MAX; 3];
+
+    for axis in 0..3 {
+        padding[axis] = (half_size[axis] / cell_size[axis]).ceil().to_isize();
+
+        let cell = start_position[axis] / cell_size[axis];
+        // to_isize truncates, so this floors it for negatives.
+        cell_position[axis] = if cell < T::ZERO {
+            cell.to_isize() - 1
+        } else {
+            cell.to_isize()
+        }
+        .clamp(-padding[axis], cells_size[axis] - 1 + padding[axis]);
+
+        if direction[axis] == T::ZERO {
+            continue;
+        }
+
+        let next_boundary = if direction[axis] > T::ZERO {
+            step[axis] = 1;
+            T::from_f64((cell_position[axis] + 1) as f64) * cell_size[axis]
+        } else {
+            step[axis] = -1;
+            T::from_f64(cell_position[axis] as f64) * cell_size[axis]
+        };
+        next_cell_distance[axis] =
+            start_distance + (next_boundary - start_position[axis]) / direction[axis];
+        cell_distance_step[axis] = cell_size[axis] / direction[axis].abs();
+    }
+
+    loop {
+        for x in (cell_position[0] - padding[0]).max(0)
+            ..=(cell_position[0] + padding[0]).min(cells_size[0] - 1)
+        {
+            for y in (cell_position[1] - padding[1]).max(0)
+                ..=(cell_position[1] + padding[1]).min(cells_size[1] - 1)
+            {
+                for z in (cell_position[2] - padding[2]).max(0)
+                    ..=(cell_position[2] + padding[2]).min(cells_size[2] - 1)
+                {
+                    for &body_index in cell([x, y, z]) {
+                        max_distance = cast_against(body_index);
+                    }
+                }
+            }
+        }
+
+        let mut axis = 0;
+        if next_cell_distance[1] < next_cell_distance[axis] {
+            axis = 1;
+        }
+        if next_cell_distance[2] < next_cell_distance[axis] {
+            axis = 2;
+        }
+
+        // Anything in the cells further along would be hit further away than this.
+        if next_cell_distance[axis] > max_distance {
+            return;
+        }
+
+        cell_position[axis] += step[axis];
+        if cell_position[axis] < -padding[axis]
+            || cell_position[axis] >= cells_size[axis] + padding[axis]
+        {
+            return;
+        }
+        next_cell_distance[axis] += cell_distance_step[axis];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn create_broadphases() -> Vec<CommonBroadphase<f32>> {
+        vec![
+            CommonBroadphase::UniformGrid(UniformGrid::new([10; 3], [10; 3], [-50.0; 3])),
+            CommonBroadphase::SpatialHash(SpatialHash::new([5.0; 3])),
+            CommonBroadphase::SweepAndPrune(SweepAndPrune::new()),
+            CommonBroadphase::Bvh(Bvh::new(0.5)),
+        ]
+    }
+
+    /// Every pair whose aabbs overlap, with the smaller body index first, skipping pairs where neither can_detect.
+    fn brute_force_pairs(
+        aabbs: &[AabbCentredOrigin<f32>],
+        can_detect: impl Fn(usize) -> bool,
+    ) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        for lhs_body_index in 0..aabbs.len() {
+            for rhs_body_index in lhs_body_index + 1..aabbs.len() {
+                if (can_detect(lhs_body_index) || can_detect(rhs_body_index))
+                    && AabbMinMax::from(aabbs[lhs_body_index])
+                        .is_intersected_by_aabb(aabbs[rhs_body_index].into())
+                {
+                    pairs.push((lhs_body_index, rhs_body_index));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Gives the pairs with the smaller body index first, sorted.
+    fn ordered_pairs(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        let mut pairs: Vec<_> = pairs
+            .iter()
+            .map(|&(lhs_body_index, rhs_body_index)| {
+                (
+                    lhs_body_index.min(rhs_body_index),
+                    lhs_body_index.max(rhs_body_index),
+                )
+            })
+            .collect();
+        pairs.sort_unstable();
+        pairs
+    }
+
+    #[test]
+    fn every_broadphase_finds_the_same_pairs_on_one_thread_or_many() {
+        let can_detect = |body_index: usize| !body_index.is_multiple_of(3);
+
+        for mut broadphase in create_broadphases() {
+            let mut rng = StdRng::seed_from_u64(7);
+            let mut aabbs: Vec<_> = (0..400)
+                .map(|_| AabbCentredOrigin {
+                    position: [(); 3].map(|_| rng.gen_range(-20.0..20.0)),
+                    half_size: [(); 3].map(|_| rng.gen_range(0.2..2.0)),
+                })
+                .collect();
+            // A few huge bodies, which are in lots of cells, and overlap lots of others.
+            for aabb in &mut aabbs[..3] {
+                aabb.half_size = [15.0, 0.5, 15.0];
+            }
+
+            // Each round moves every body a little, so that the bvh keeps most of its fat leaves, and a few a long way, so it has to reinsert them.
+            // One round moves them all a long way, so it has to rebuild the whole tree, like it did in the first round.
+            // Every fifth body is retained, like a sleeping one, rather than inserted again.
+            for round in 0..6 {
+                if round == 0 {
+                    broadphase.clear();
+                } else {
+                    broadphase.retain(&|body_index| body_index.is_multiple_of(5));
+                }
+                for (body_index, aabb) in aabbs.iter_mut().enumerate() {
+                    if round != 0 && body_index.is_multiple_of(5) {
+                        continue;
+                    }
+                    let jump: f32 = if round == 3 || rng.gen_bool(0.05) {
+                        10.0
+                    } else {
+                        0.3
+                    };
+                    for axis in 0..3 {
+                        aabb.position[axis] =
+                            (aabb.position[axis] + rng.gen_range(-jump..jump)).clamp(-20.0, 20.0);
+                    }
+                    broadphase.insert(body_index, *aabb);
+                }
+                broadphase.finish_inserting();
+
+                let mut pairs = vec![];
+                broadphase.find_pairs(&can_detect, &mut |lhs_body_index, rhs_body_index| {
+                    pairs.push((lhs_body_index, rhs_body_index))
+                });
+                let mut par_pairs: Vec<_> = broadphase
+                    .par_find_pairs(&can_detect)
+                    .into_iter()
+                    .flatten()
+                    .collect();
+
+                let expected_pairs = brute_force_pairs(&aabbs, can_detect);
+                assert!(expected_pairs.len() > 100, "{}", expected_pairs.len());
+                assert_eq!(ordered_pairs(&pairs), expected_pairs, "{broadphase:?}");
+
+                pairs.sort_unstable();
+                par_pairs.sort_unstable();
+                assert_eq!(pairs, par_pairs);
+            }
+        }
+    }
+}
@@ -0,0 +1,475 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::math::{self, Float};
+
+use super::{
+    aabb::{AabbCentredOrigin, AabbMinMax},
+    broadphase::{self, Broadphase},
+};
+
+/// A broadphase made of a dynamic bounding volume hierarchy, which is a binary tree of aabbs, where each body is a leaf.
+/// It has no cells, so huge bodies cost no more than small ones, and bodies can go anywhere.
+///
+/// Leaves are made bigger than their body by the margin, so that bodies moving a little don't have to move around the tree.
+/// Only bodies that leave their leaf get removed and reinserted, which makes it cheap for scenes where most bodies barely move, and very cheap for ones where most are asleep or immovable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bvh<T>
+where
+    T: Float,
+{
+    pub margin: T,
+    nodes: Vec<Node<T>>,
+    root: Option<usize>,
+    /// Node indices that have been removed, and can be reused.
+    free_nodes: Vec<usize>,
+    /// The leaf node index of each body, by body index.
+    leaves: Vec<Option<usize>>,
+    /// The aabb each body was inserted with, by body index.
+    aabbs: Vec<AabbMinMax<T>>,
+    /// Whether each body has been inserted or retained since the last [Broadphase::finish_inserting], by body index.
+    touched: Vec<bool>,
+    /// Bodies that have been inserted without a leaf that fits them, waiting for [Broadphase::finish_inserting] to give them one.
+    unplaced: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Node<T>
+where
+    T: Float,
+{
+    /// For leaves, this is the body's aabb, expanded by the margin. For branches, it contains both children.
+    aabb: AabbMinMax<T>,
+    parent: Option<usize>,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum NodeKind {
+    Leaf(usize),
+    Branch([usize; 2]),
+}
+
+impl<T> Bvh<T>
+where
+    T: Float,
+{
+    pub fn new(margin: T) -> Self {
+        Self {
+            margin,
+            nodes: vec![],
+            root: None,
+            free_nodes: vec![],
+            leaves: vec![],
+            aabbs: vec![],
+            touched: vec![],
+            unplaced: vec![],
+        }
+    }
+
+    /// The body's aabb, expanded by the margin.
+    #[inline]
+    fn fat_aabb(&self, body_index: usize) -> AabbMinMax<T> {
+        let margin = [self.margin; 3];
+        AabbMinMax {
+            min: math::sub_3d(self.aabbs[body_index].min, margin),
+            max: math::add_3d(self.aabbs[body_index].max, margin),
+        }
+    }
+
+    fn allocate_node(&mut self, node: Node<T>) -> usize {
+        match self.free_nodes.pop() {
+            Some(node_index) => {
+                self.nodes[node_index] = node;
+                node_index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Inserts a leaf for the body, picking the sibling that grows the tree's surface area the least, like Box2D does.
+    fn insert_leaf(&mut self, body_index: usize, aabb: AabbMinMax<T>) {
+        let leaf = self.allocate_node(Node {
+            aabb,
+            parent: None,
+            kind: NodeKind::Leaf(body_index),
+        });
+        self.leaves[body_index] = Some(leaf);
+
+        let Some(root) = self.root else {
+            self.root = Some(leaf);
+            return;
+        };
+
+        let mut sibling = root;
+        while let NodeKind::Branch([lhs, rhs]) = self.nodes[sibling].kind {
+            let area = self.nodes[sibling].aabb.half_surface_area();
+            let combined_area = self.nodes[sibling].aabb.union(aabb).half_surface_area();
+
+            // Making a new parent for this node and the leaf.
+            let cost = combined_area + combined_area;
+            // Pushing the leaf further down grows every ancestor, including this node.
+            let inheritance_cost = (combined_area - area) + (combined_area - area);
+
+            let child_cost = |child: usize| {
+                let child_aabb = self.nodes[child].aabb;
+                let grown_area = child_aabb.union(aabb).half_surface_area();
+                match self.nodes[child].kind {
+                    NodeKind::Leaf(_) => grown_area + inheritance_cost,
+                    NodeKind::Branch(_) => {
+                        grown_area - child_aabb.half_surface_area() + inheritance_cost
+                    }
+                }
+            };
+            let lhs_cost = child_cost(lhs);
+            let rhs_cost = child_cost(rhs);
+
+            if cost < lhs_cost && cost < rhs_cost {
+                break;
+            }
+            sibling = if lhs_cost < rhs_cost { lhs } else { rhs };
+        }
+
+        let old_parent = self.nodes[sibling].parent;
+        let new_parent = self.allocate_node(Node {
+            aabb: self.nodes[sibling].aabb.union(aabb),
+            parent: old_parent,
+            kind: NodeKind::Branch([sibling, leaf]),
+        });
+        self.nodes[sibling].parent = Some(new_parent);
+        self.nodes[leaf].parent = Some(new_parent);
+
+        match old_parent {
+            Some(old_parent) => {
+                self.replace_child(old_parent, sibling, new_parent);
+                self.refit(old_parent);
+            }
+            None => self.root = Some(new_parent),
+        }
+    }
+
+    /// Throws the tree away and builds it again from the top down, with every body that has a leaf, and every unplaced body.
+    /// Inserting leaves one at a time makes a worse tree than this, so this is used whenever a lot of bodies need placing at once, such as the first update.
+    fn rebuild(&mut self) {
+        let mut leaves: Vec<(usize, AabbMinMax<T>)> = self
+            .leaves
+            .iter()
+            .enumerate()
+            .filter_map(|(body_index, leaf)| leaf.map(|leaf| (body_index, self.nodes[leaf].aabb)))
+            .collect();
+        for body_index in std::mem::take(&mut self.unplaced) {
+            leaves.push((body_index, self.fat_aabb(body_index)));
+        }
+
+        self.nodes.clear();
+        self.free_nodes.clear();
+        self.root = if leaves.is_empty() {
+            None
+        } else {
+            Some(self.build(&mut leaves, None))
+        };
+    }
+
+    /// Builds a subtree out of the leaves, splitting them in half along the axis their centres are most spread out along, returning its node index.
+    fn build(&mut self, leaves: &mut [(usize, AabbMinMax<T>)], parent: Option<usize>) -> usize {
+        if let [(body_index, aabb)] = *leaves {
+            self.nodes.push(Node {
+                aabb,
+                parent,
+                kind: NodeKind::Leaf(body_index),
+            });
+            let leaf = self.nodes.len() - 1;
+            self.leaves[body_index] = Some(leaf);
+            return leaf;
+        }
+
+        // The centres are doubled, but that doesn't change which axis is most spread out, or how they are ordered.
+        let centre = |aabb: &AabbMinMax<T>, axis: usize| aabb.min[axis] + aabb.max[axis];
+        let mut min_centre = [T::MAX; 3];
+        let mut max_centre = [-T::MAX; 3];
+        for (_, aabb) in leaves.iter() {
+            for axis in 0..3 {
+                let centre = centre(aabb, axis);
+                if centre < min_centre[axis] {
+                    min_centre[axis] = centre;
+                }
+                if centre > max_centre[axis] {
+                    max_centre[axis] = centre;
+                }
+            }
+        }
+        let mut split_axis = 0;
+        for axis in 1..3 {
+            if max_centre[axis] - min_centre[axis] > max_centre[split_axis] - min_centre[split_axis]
+            {
+                split_axis = axis;
+            }
+        }
+
+        let middle = leaves.len() / 2;
+        leaves.select_nth_unstable_by(middle, |(_, lhs), (_, rhs)| {
+            centre(lhs, split_axis)
+                .partial_cmp(&centre(rhs, split_axis))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let (lhs_leaves, rhs_leaves) = leaves.split_at_mut(middle);
+
+        self.nodes.push(Node {
+            aabb: lhs_leaves[0].1,
+            parent,
+            kind: NodeKind::Branch([0; 2]),
+        });
+        let branch = self.nodes.len() - 1;
+        let lhs = self.build(lhs_leaves, Some(branch));
+        let rhs = self.build(rhs_leaves, Some(branch));
+        self.nodes[branch].aabb = self.nodes[lhs].aabb.union(self.nodes[rhs].aabb);
+        self.nodes[branch].kind = NodeKind::Branch([lhs, rhs]);
+        branch
+    }
+
+    /// Removes the body's leaf, moving its sibling up into their parent's place.
+    fn remove_leaf(&mut self, body_index: usize) {
+        let Some(leaf) = self.leaves[body_index].take() else {
+            return;
+        };
+        self.free_nodes.push(leaf);
+
+        let Some(parent) = self.nodes[leaf].parent else {
+            self.root = None;
+            return;
+        };
+        let NodeKind::Branch(children) = self.nodes[parent].kind else {
+            unreachable!("Parents are always branches.");
+        };
+        let sibling = if children[0] == leaf {
+            children[1]
+        } else {
+            children[0]
+        };
+        self.free_nodes.push(parent);
+
+        let grandparent = self.nodes[parent].parent;
+        self.nodes[sibling].parent = grandparent;
+        match grandparent {
+            Some(grandparent) => {
+                self.replace_child(grandparent, parent, sibling);
+                self.refit(grandparent);
+            }
+            None => self.root = Some(sibling),
+        }
+    }
+
+    fn replace_child(&mut self, parent: usize, old_child: usize, new_child: usize) {
+        if let NodeKind::Branch(children) = &mut self.nodes[parent].kind {
+            for child in children {
+                if *child == old_child {
+                    *child = new_child;
+                }
+            }
+        }
+    }
+
+    /// Makes the aabbs of the node and every node above it fit their children again.
+    fn refit(&mut self, mut node_index: usize) {
+        loop {
+            if let NodeKind::Branch([lhs, rhs]) = self.nodes[node_index].kind {
+                self.nodes[node_index].aabb = self.nodes[lhs].aabb.union(self.nodes[rhs].aabb);
+            }
+            match self.nodes[node_index].parent {
+                Some(parent) => node_index = parent,
+                None => return,
+            }
+        }
+    }
+
+    /// Calls on_leaf with the body index of every leaf whose aabb overlaps the aabb.
+    #[inline]
+    fn query(&self, aabb: AabbMinMax<T>, stack: &mut Vec<usize>, mut on_leaf: impl FnMut(usize)) {
+        let Some(root) = self.root else {
+            return;
+        };
+        stack.clear();
+        stack.push(root);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node.aabb.is_intersected_by_aabb(aabb) {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf(body_index) => on_leaf(body_index),
+                NodeKind::Branch([lhs, rhs]) => {
+                    stack.push(lhs);
+                    stack.push(rhs);
+                }
+            }
+        }
+    }
+
+    /// Gives every pair the body is the lhs of. Bodies that can detect are paired with bodies that can't, and with bodies that can and have a larger index, so each pair is only given once.
+    #[inline]
+    fn find_pairs_from(
+        &self,
+        body_index: usize,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        stack: &mut Vec<usize>,
+        mut on_pair: impl FnMut(usize, usize),
+    ) {
+        if self.leaves[body_index].is_none() || !can_detect(body_index) {
+            return;
+        }
+        let aabb = self.aabbs[body_index];
+
+        self.query(aabb, stack, |other_body_index| {
+            if other_body_index == body_index
+                || (other_body_index < body_index && can_detect(other_body_index))
+            {
+                return;
+            }
+            // Leaves are fat, so the real aabbs might not overlap.
+            if aabb.is_intersected_by_aabb(self.aabbs[other_body_index]) {
+                on_pair(body_index, other_body_index);
+            }
+        });
+    }
+}
+
+impl<T> Broadphase<T> for Bvh<T>
+where
+    T: Float,
+{
+    /// Bodies aren't removed until [Broadphase::finish_inserting], so that bodies which are inserted again can keep their leaf.
+    fn retain(&mut self, keep: &dyn Fn(usize) -> bool) {
+        for (body_index, touched) in self.touched.iter_mut().enumerate() {
+            *touched = keep(body_index);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.touched.fill(false);
+    }
+
+    fn insert(&mut self, body_index: usize, aabb: AabbCentredOrigin<T>) {
+        let aabb = AabbMinMax::from(aabb);
+        broadphase::store_aabb(&mut self.aabbs, body_index, aabb);
+        if self.leaves.len() <= body_index {
+            self.leaves.resize(body_index + 1, None);
+            self.touched.resize(body_index + 1, false);
+        }
+
+        match self.leaves[body_index] {
+            Some(leaf) if self.nodes[leaf].aabb.contains_aabb(aabb) => (),
+            Some(_) => {
+                self.remove_leaf(body_index);
+                self.unplaced.push(body_index);
+            }
+            // Touched bodies without a leaf are already unplaced.
+            None if !self.touched[body_index] => self.unplaced.push(body_index),
+            None => (),
+        }
+        self.touched[body_index] = true;
+    }
+
+    /// Removes the leaves of every body that wasn't inserted or retained, then gives every unplaced body a leaf.
+    /// If more than a quarter of the bodies need one, the whole tree is rebuilt instead.
+    fn finish_inserting(&mut self) {
+        let mut leaf_count = 0;
+        for body_index in 0..self.leaves.len() {
+            if !self.touched[body_index] {
+                self.remove_leaf(body_index);
+            } else if self.leaves[body_index].is_some() {
+                leaf_count += 1;
+            }
+        }
+        self.touched.fill(false);
+
+        if self.unplaced.len() * 3 > leaf_count {
+            self.rebuild();
+        } else {
+            for body_index in std::mem::take(&mut self.unplaced) {
+                self.insert_leaf(body_index, self.fat_aabb(body_index));
+            }
+        }
+    }
+
+    fn find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        on_pair: &mut dyn FnMut(usize, usize),
+    ) {
+        let mut stack = vec![];
+        for body_index in 0..self.leaves.len() {
+            self.find_pairs_from(body_index, can_detect, &mut stack, &mut *on_pair);
+        }
+    }
+
+    fn par_find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+    ) -> Vec<Vec<(usize, usize)>> {
+        (0..self.leaves.len())
+            .into_par_iter()
+            .fold(
+                || (vec![], vec![]),
+                |(mut pairs, mut stack), body_index| {
+                    self.find_pairs_from(
+                        body_index,
+                        can_detect,
+                        &mut stack,
+                        |lhs_body_index, rhs_body_index| {
+                            pairs.push((lhs_body_index, rhs_body_index))
+                        },
+                    );
+                    (pairs, stack)
+                },
+            )
+            .map(|(pairs, _)| pairs)
+            .collect()
+    }
+
+    fn extend_with_candidates(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>) {
+        broadphase::with_thread_buffer(|stack| {
+            self.query(aabb.into(), stack, |body_index| results.push(body_index))
+        });
+    }
+
+    /// Walks down the tree, skipping any node the cast misses, or only hits further away than the distance cast_against last returned.
+    fn cast(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        mut max_distance: T,
+        cast_against: &mut dyn FnMut(usize) -> T,
+    ) {
+        let Some(root) = self.root else {
+            return;
+        };
+
+        // The tree has no depth limit, so the stack can't be a fixed size array.
+        broadphase::with_thread_buffer(|stack| {
+            stack.push(root);
+            while let Some(node_index) = stack.pop() {
+                let node = &self.nodes[node_index];
+                let mut node_aabb = AabbCentredOrigin::from(node.aabb);
+                node_aabb.half_size = math::add_3d(node_aabb.half_size, half_size);
+                if node_aabb
+                    .get_ray_intersection(origin, direction, max_distance)
+                    .is_none()
+                {
+                    continue;
+                }
+                match node.kind {
+                    NodeKind::Leaf(body_index) => max_distance = cast_against(body_index),
+                    NodeKind::Branch([lhs, rhs]) => {
+                        stack.push(lhs);
+                        stack.push(rhs);
+                    }
+                }
+            }
+        });
+    }
+}
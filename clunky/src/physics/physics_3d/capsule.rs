@@ -0,0 +1,113 @@
+use crate::math::{self, Float};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    aabb::AabbCentredOrigin,
+    cylinder::{clamp, Cylinder},
+    sphere::Sphere,
+};
+
+/// An upright capsule. A line segment along the y axis, with everything within radius of it being inside.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Capsule<T>
+where
+    T: Float,
+{
+    pub position: [T; 3],
+    pub radius: T,
+    /// Half the length of the line segment. The total height is (half_height + radius) * 2.
+    pub half_height: T,
+}
+
+impl<T> Capsule<T>
+where
+    T: Float,
+{
+    /// Gets the sphere on the line segment with the closest height to y.
+    /// As the capsule is upright, this is the sphere closest to anything whose nearest point is at y.
+    #[inline]
+    pub fn sphere_closest_to_height(&self, y: T) -> Sphere<T> {
+        Sphere {
+            position: [
+                self.position[0],
+                clamp(
+                    y,
+                    self.position[1] - self.half_height,
+                    self.position[1] + self.half_height,
+                ),
+                self.position[2],
+            ],
+            radius: self.radius,
+        }
+    }
+
+    pub fn is_intersected_by_point(&self, point: [T; 3]) -> bool {
+        self.sphere_closest_to_height(point[1])
+            .is_intersected_by_point(point)
+    }
+
+    pub fn is_intersected_by_sphere(&self, sphere: Sphere<T>) -> bool {
+        self.sphere_closest_to_height(sphere.position[1])
+            .is_intersected_by_sphere(sphere)
+    }
+
+    pub fn is_intersected_by_aabb(&self, aabb: AabbCentredOrigin<T>) -> bool {
+        self.sphere_closest_to_height(aabb.position[1])
+            .is_intersected_by_aabb(aabb)
+    }
+
+    pub fn is_intersected_by_capsule(&self, capsule: Capsule<T>) -> bool {
+        self.get_collision_normal_and_penetration_with_capsule(&capsule)
+            .1
+            >= T::ZERO
+    }
+
+    pub fn is_intersected_by_cylinder(&self, cylinder: Cylinder<T>) -> bool {
+        cylinder.is_intersected_by_sphere(self.sphere_closest_to_height(cylinder.position[1]))
+    }
+
+    /// The normal points from self towards the sphere, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_sphere(
+        &self,
+        sphere: &Sphere<T>,
+    ) -> ([T; 3], T) {
+        self.sphere_closest_to_height(sphere.position[1])
+            .get_collision_normal_and_penetration_with_sphere(sphere)
+    }
+
+    /// The normal points from self towards the aabb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_aabb(
+        &self,
+        aabb: &AabbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        self.sphere_closest_to_height(aabb.position[1])
+            .get_collision_normal_and_penetration_with_aabb(aabb)
+    }
+
+    /// The normal points from self towards other, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_capsule(
+        &self,
+        other: &Capsule<T>,
+    ) -> ([T; 3], T) {
+        // Both segments are upright, so the closest point on self to the other's centre, is also the closest to the other's segment.
+        let self_sphere = self.sphere_closest_to_height(other.position[1]);
+        let other_sphere = other.sphere_closest_to_height(self_sphere.position[1]);
+        self_sphere.get_collision_normal_and_penetration_with_sphere(&other_sphere)
+    }
+
+    /// The normal points from self towards the cylinder, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_cylinder(
+        &self,
+        cylinder: &Cylinder<T>,
+    ) -> ([T; 3], T) {
+        let (collision_normal, penetration) = cylinder
+            .get_collision_normal_and_penetration_with_sphere(
+                &self.sphere_closest_to_height(cylinder.position[1]),
+            );
+        (math::neg_3d(collision_normal), penetration)
+    }
+}
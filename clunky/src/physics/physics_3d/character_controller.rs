@@ -0,0 +1,413 @@
+use serde::{Deserialize, Serialize};
+
+use crate::math::{self, Float};
+
+use super::{
+    bodies::Body,
+    solver::{BodyHandle, CollisionLayers, CpuSolver},
+};
+
+/// The most times a move will slide along something it hit, before giving up on the rest of the move.
+const SLIDE_ITERATIONS: usize = 4;
+/// The most bodies a single cast will ignore, for being behind the character or already inside it, before giving up.
+const IGNORED_HITS_PER_CAST: usize = 8;
+
+/// How a [CharacterController] moves.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CharacterControllerConfig<T> {
+    /// The half size of the character's aabb.
+    pub half_size: [T; 3],
+    /// How high a ledge can be for the character to walk straight up onto it.
+    pub step_height: T,
+    /// The steepest slope, in radians, that the character can stand on. Anything steeper is treated like a wall, and slid down.
+    pub max_slope: T,
+    /// How far the character will be pulled down onto the ground, to stay stuck to it when walking down slopes and steps.
+    pub ground_snap_distance: T,
+    /// How long after walking off of a ledge the character can still jump.
+    pub coyote_time: T,
+    /// How long a jump is remembered for, so that pressing jump just before landing still jumps.
+    pub jump_buffer_time: T,
+    /// The upwards speed a jump gives.
+    pub jump_speed: T,
+    /// The gap kept between the character and everything else. Casts that start touching something can't tell which way it is, so this stops that from happening.
+    pub skin_width: T,
+    /// Which layers the character collides with. Only the mask is used.
+    pub collision_layers: CollisionLayers,
+}
+
+impl<T> Default for CharacterControllerConfig<T>
+where
+    T: Float,
+{
+    fn default() -> Self {
+        Self {
+            half_size: [T::from_f64(0.4), T::from_f64(0.9), T::from_f64(0.4)],
+            step_height: T::from_f64(0.4),
+            max_slope: T::from_f64(45.0).to_radians(),
+            ground_snap_distance: T::from_f64(0.3),
+            coyote_time: T::from_f64(0.1),
+            jump_buffer_time: T::from_f64(0.1),
+            jump_speed: T::from_f64(12.0),
+            skin_width: T::from_f64(0.01),
+            collision_layers: CollisionLayers::ALL,
+        }
+    }
+}
+
+/// What the character is standing on.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Ground<T> {
+    pub body_index: usize,
+    /// The normal of the ground, pointing up out of it.
+    pub normal: [T; 3],
+    /// Used to carry the character along with the ground, should it move. Bodies pushed straight into the solver's bodies don't have a handle, so they don't carry.
+    handle: Option<BodyHandle>,
+    body_position: [T; 3],
+}
+
+/// Moves an aabb around a [CpuSolver]'s bodies using shape casts, rather than being simulated, so it goes exactly where it is told, unless something is in the way.
+/// It walks up steps and slopes, stays stuck to the ground going down them, and rides moving platforms.
+///
+/// As everywhere else, +y is down, so up is -y. Only the y component of the solver's gravity is used.
+///
+/// The controller isn't a body itself, so nothing else collides with it. To have bodies bump into it, insert a body for it, such as an [super::bodies::ImmovableCuboid], and set body_handle. The body is moved to the controller every update, and ignored by its casts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CharacterController<T> {
+    pub config: CharacterControllerConfig<T>,
+    pub position: [T; 3],
+    /// Horizontal velocity is replaced every update by the movement given to it, so only the vertical velocity carries between updates.
+    pub velocity: [T; 3],
+    pub body_handle: Option<BodyHandle>,
+
+    ground: Option<Ground<T>>,
+    time_since_grounded: T,
+    /// Stops the character jumping again during coyote time, having already jumped.
+    jumped_since_grounded: bool,
+    time_since_jump_requested: Option<T>,
+}
+
+impl<T> CharacterController<T>
+where
+    T: Float,
+{
+    pub fn new(config: CharacterControllerConfig<T>, position: [T; 3]) -> Self {
+        Self {
+            config,
+            position,
+            velocity: [T::ZERO; 3],
+            body_handle: None,
+
+            ground: None,
+            time_since_grounded: T::MAX,
+            jumped_since_grounded: false,
+            time_since_jump_requested: None,
+        }
+    }
+
+    /// Jumps during the next update that the character is able to, as long as that is within the jump buffer time.
+    pub fn jump(&mut self) {
+        self.time_since_jump_requested = Some(T::ZERO);
+    }
+
+    #[inline]
+    pub fn is_grounded(&self) -> bool {
+        self.ground.is_some()
+    }
+
+    /// What the character is standing on, should it be standing on anything.
+    #[inline]
+    pub fn ground(&self) -> Option<Ground<T>> {
+        self.ground
+    }
+
+    /// Moves the character. Call this after updating the solver, so that it moves with the world as it is now.
+    /// The movement is the horizontal velocity the character wants to move at. Its y is ignored.
+    pub fn update<B: Body<T>>(
+        &mut self,
+        solver: &mut CpuSolver<T, B>,
+        movement: [T; 3],
+        delta_time: T,
+    ) {
+        let own_body_index = self
+            .body_handle
+            .and_then(|body_handle| solver.body_index(body_handle));
+        let mover = Mover {
+            solver: &*solver,
+            half_size: self.config.half_size,
+            skin_width: self.config.skin_width,
+            min_ground_up: self.config.max_slope.cos(),
+            collision_layers: self.config.collision_layers,
+            own_body_index,
+        };
+
+        // Moving platforms carry whatever is standing on them.
+        if let Some(ground) = self.ground {
+            if let Some(body) = ground.handle.and_then(|handle| mover.solver.get(handle)) {
+                let platform_displacement =
+                    math::sub_3d(body.position_unchecked(), ground.body_position);
+                if platform_displacement != [T::ZERO; 3] {
+                    self.position = mover
+                        .slide(self.position, platform_displacement, false)
+                        .position;
+                }
+            }
+        }
+
+        let was_grounded = self.ground.is_some();
+        if was_grounded {
+            self.time_since_grounded = T::ZERO;
+        } else if self.time_since_grounded <= self.config.coyote_time {
+            self.time_since_grounded += delta_time;
+        }
+
+        self.velocity = [
+            movement[0],
+            self.velocity[1] + mover.solver.gravity[1] * delta_time,
+            movement[2],
+        ];
+
+        let mut jumped = false;
+        if self.time_since_jump_requested.is_some()
+            && !self.jumped_since_grounded
+            && (was_grounded || self.time_since_grounded <= self.config.coyote_time)
+        {
+            self.velocity[1] = -self.config.jump_speed;
+            self.time_since_jump_requested = None;
+            self.jumped_since_grounded = true;
+            jumped = true;
+        }
+        // Jumps that couldn't happen yet are remembered for a bit, in case they can soon.
+        if let Some(time_since_jump_requested) = &mut self.time_since_jump_requested {
+            *time_since_jump_requested += delta_time;
+            if *time_since_jump_requested > self.config.jump_buffer_time {
+                self.time_since_jump_requested = None;
+            }
+        }
+        // Whether the character was on the ground at the start of the update, and is staying there.
+        let staying_grounded = was_grounded && !jumped;
+
+        // Horizontal movement, stepping up onto anything too steep to walk up, but low enough to step onto.
+        let horizontal_displacement = [
+            self.velocity[0] * delta_time,
+            T::ZERO,
+            self.velocity[2] * delta_time,
+        ];
+        let start = self.position;
+        let walked = mover.slide(start, horizontal_displacement, staying_grounded);
+        self.position = walked.position;
+        if staying_grounded && walked.hit_wall && self.config.step_height > T::ZERO {
+            if let Some(stepped_position) =
+                mover.step(start, horizontal_displacement, self.config.step_height)
+            {
+                if horizontal_distance_squared(start, stepped_position)
+                    > horizontal_distance_squared(start, walked.position)
+                {
+                    self.position = stepped_position;
+                }
+            }
+        }
+
+        // Vertical movement.
+        let fallen = mover.slide(
+            self.position,
+            [T::ZERO, self.velocity[1] * delta_time, T::ZERO],
+            false,
+        );
+        self.position = fallen.position;
+        let mut ground = fallen.ground;
+        if fallen.hit_ceiling && self.velocity[1] < T::ZERO {
+            self.velocity[1] = T::ZERO;
+        }
+
+        // Snapping down onto the ground, so that walking down slopes and steps doesn't turn into lots of tiny falls.
+        if ground.is_none() && staying_grounded {
+            if let Some(hit) = mover.cast(
+                self.position,
+                [T::ZERO, T::ONE, T::ZERO],
+                self.config.ground_snap_distance,
+            ) {
+                if mover.is_walkable(hit.normal) {
+                    self.position[1] += hit.distance;
+                    ground = Some((hit.body_index, hit.normal));
+                }
+            }
+        }
+
+        self.ground = ground.map(|(body_index, normal)| Ground {
+            body_index,
+            normal,
+            handle: mover.solver.handle(body_index),
+            body_position: mover.solver.bodies[body_index].position_unchecked(),
+        });
+        if self.ground.is_some() {
+            self.velocity[1] = T::ZERO;
+            self.jumped_since_grounded = false;
+        }
+
+        if let Some(body) = self
+            .body_handle
+            .and_then(|body_handle| solver.get_mut(body_handle))
+        {
+            body.set_position_unchecked(self.position);
+        }
+    }
+}
+
+/// Where a slide ended up, and what it hit on the way.
+struct Slide<T> {
+    position: [T; 3],
+    /// The body index and normal of the first walkable surface hit.
+    ground: Option<(usize, [T; 3])>,
+    /// Whether it hit something too steep to walk on.
+    hit_wall: bool,
+    /// Whether it hit something above it.
+    hit_ceiling: bool,
+}
+
+/// A hit that has already had the skin width taken off.
+struct Hit<T> {
+    body_index: usize,
+    normal: [T; 3],
+    distance: T,
+}
+
+/// Everything needed to move the character's aabb around the solver.
+struct Mover<'a, T, B>
+where
+    T: Float,
+    B: Body<T>,
+{
+    solver: &'a CpuSolver<T, B>,
+    half_size: [T; 3],
+    skin_width: T,
+    /// The cosine of the max slope. A surface is walkable if its normal points at least this much up.
+    min_ground_up: T,
+    collision_layers: CollisionLayers,
+    own_body_index: Option<usize>,
+}
+
+impl<'a, T, B> Mover<'a, T, B>
+where
+    T: Float,
+    B: Body<T>,
+{
+    /// +y is down, so a normal pointing up has a negative y.
+    #[inline]
+    fn is_walkable(&self, normal: [T; 3]) -> bool {
+        -normal[1] >= self.min_ground_up
+    }
+
+    /// Casts the aabb, ignoring anything it starts inside of, or is moving away from.
+    /// The distance returned keeps the skin width away from what was hit.
+    fn cast(&self, origin: [T; 3], direction: [T; 3], distance: T) -> Option<Hit<T>> {
+        let mut ignored_body_indices = vec![];
+        for _ in 0..IGNORED_HITS_PER_CAST {
+            let hit = self.solver.shape_cast(
+                origin,
+                self.half_size,
+                direction,
+                distance + self.skin_width,
+                |body_index, _| {
+                    Some(body_index) != self.own_body_index
+                        && !ignored_body_indices.contains(&body_index)
+                        && self.collision_layers.mask
+                            & self.solver.collision_layers(body_index).layers
+                            != 0
+                },
+            )?;
+
+            if math::dot(hit.normal, direction) >= T::ZERO {
+                ignored_body_indices.push(hit.body_index);
+                continue;
+            }
+
+            let distance = hit.distance - self.skin_width;
+            return Some(Hit {
+                body_index: hit.body_index,
+                normal: hit.normal,
+                distance: if distance > T::ZERO {
+                    distance
+                } else {
+                    T::ZERO
+                },
+            });
+        }
+        None
+    }
+
+    /// Moves as far as it can along the displacement, then slides along whatever it hit, for the rest of the displacement.
+    /// When keeping to the ground, walls have their normals flattened, so that sliding along them can't lift the character up them.
+    fn slide(
+        &self,
+        mut position: [T; 3],
+        mut displacement: [T; 3],
+        keep_to_ground: bool,
+    ) -> Slide<T> {
+        let mut slide = Slide {
+            position,
+            ground: None,
+            hit_wall: false,
+            hit_ceiling: false,
+        };
+
+        for _ in 0..SLIDE_ITERATIONS {
+            let distance = math::get_magnitude_3d(displacement);
+            if distance <= T::ZERO {
+                break;
+            }
+            let direction = math::div_3d_by_1d(displacement, distance);
+
+            let Some(hit) = self.cast(position, direction, distance) else {
+                position = math::add_3d(position, displacement);
+                break;
+            };
+            position = math::add_3d(position, math::mul_3d_by_1d(direction, hit.distance));
+            let remaining = math::mul_3d_by_1d(direction, distance - hit.distance);
+
+            let mut normal = hit.normal;
+            if self.is_walkable(normal) {
+                slide.ground.get_or_insert((hit.body_index, normal));
+            } else if normal[1] > T::ZERO {
+                slide.hit_ceiling = true;
+            } else {
+                slide.hit_wall = true;
+                if keep_to_ground {
+                    normal = math::normalise_3d([normal[0], T::ZERO, normal[2]]);
+                }
+            }
+
+            displacement = math::sub_3d(
+                remaining,
+                math::mul_3d_by_1d(normal, math::dot(remaining, normal)),
+            );
+        }
+
+        slide.position = position;
+        slide
+    }
+
+    /// Tries to step up onto something, by going up, then along, then back down onto walkable ground.
+    fn step(&self, start: [T; 3], displacement: [T; 3], step_height: T) -> Option<[T; 3]> {
+        let up = [T::ZERO, -T::ONE, T::ZERO];
+        let step_up = match self.cast(start, up, step_height) {
+            Some(hit) => hit.distance,
+            None => step_height,
+        };
+        let raised = math::add_3d(start, math::mul_3d_by_1d(up, step_up));
+
+        let moved = self.slide(raised, displacement, true).position;
+
+        let hit = self.cast(moved, [T::ZERO, T::ONE, T::ZERO], step_up)?;
+        if !self.is_walkable(hit.normal) {
+            return None;
+        }
+        Some([moved[0], moved[1] + hit.distance, moved[2]])
+    }
+}
+
+#[inline]
+fn horizontal_distance_squared<T: Float>(lhs: [T; 3], rhs: [T; 3]) -> T {
+    let x = rhs[0] - lhs[0];
+    let z = rhs[2] - lhs[2];
+    x * x + z * z
+}
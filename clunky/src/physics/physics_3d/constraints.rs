@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+
+use crate::math::{self, Float};
+
+use super::{bodies::Body, solver::BodyIndexChange};
+
+/// Links bodies together, or to the world. Used for ropes, chains, bridges, creature limbs and the like.
+///
+/// Bodies without a particle, such as immovable ones, can be used as fixed anchors.
+/// Every particle is treated as having the same mass, so when both ends can move, they both move the same amount.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Constraint<T> {
+    /// Keeps two bodies exactly a distance apart, like a rigid rod.
+    Distance {
+        lhs_body_index: usize,
+        rhs_body_index: usize,
+        distance: T,
+    },
+    /// Pulls two bodies towards being rest_length apart. Stiffness is how hard it pulls per unit of stretch, and damping is how much it resists the bodies moving apart or together.
+    /// Unlike the others, this is a force, so it is applied once per substep, rather than being relaxed.
+    Spring {
+        lhs_body_index: usize,
+        rhs_body_index: usize,
+        rest_length: T,
+        stiffness: T,
+        damping: T,
+    },
+    /// Keeps two bodies between a min and max distance apart. A rope has a min of 0, so it can go slack.
+    Rope {
+        lhs_body_index: usize,
+        rhs_body_index: usize,
+        min_distance: T,
+        max_distance: T,
+    },
+    /// Keeps a body at a position in the world.
+    Pin { body_index: usize, position: [T; 3] },
+}
+
+impl<T> Constraint<T>
+where
+    T: Float,
+{
+    /// Gets the body indices the constraint links. Pins only have the one.
+    pub fn body_indices(&self) -> (usize, Option<usize>) {
+        match *self {
+            Constraint::Distance {
+                lhs_body_index,
+                rhs_body_index,
+                ..
+            }
+            | Constraint::Spring {
+                lhs_body_index,
+                rhs_body_index,
+                ..
+            }
+            | Constraint::Rope {
+                lhs_body_index,
+                rhs_body_index,
+                ..
+            } => (lhs_body_index, Some(rhs_body_index)),
+            Constraint::Pin { body_index, .. } => (body_index, None),
+        }
+    }
+
+    /// Follows the body index change. Returns false if one of the constraint's bodies was deleted, in which case the constraint should be removed.
+    pub(super) fn apply_body_index_change(&mut self, body_index_change: BodyIndexChange) -> bool {
+        let body_indices: [Option<&mut usize>; 2] = match self {
+            Constraint::Distance {
+                lhs_body_index,
+                rhs_body_index,
+                ..
+            }
+            | Constraint::Spring {
+                lhs_body_index,
+                rhs_body_index,
+                ..
+            }
+            | Constraint::Rope {
+                lhs_body_index,
+                rhs_body_index,
+                ..
+            } => [Some(lhs_body_index), Some(rhs_body_index)],
+            Constraint::Pin { body_index, .. } => [Some(body_index), None],
+        };
+
+        for body_index in body_indices.into_iter().flatten() {
+            match body_index_change {
+                BodyIndexChange::Deleted(deleted) => {
+                    if *body_index == deleted {
+                        return false;
+                    }
+                }
+                BodyIndexChange::Moved { from, to } => {
+                    if *body_index == from {
+                        *body_index = to;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Applies the spring force, if this is a spring. Must be done before the bodies are updated.
+    pub(super) fn apply_force<B: Body<T>>(&self, bodies: &mut [B], delta_time: T) {
+        let Constraint::Spring {
+            lhs_body_index,
+            rhs_body_index,
+            rest_length,
+            stiffness,
+            damping,
+        } = *self
+        else {
+            return;
+        };
+        if lhs_body_index == rhs_body_index {
+            return;
+        }
+        let (Some(lhs), Some(rhs)) = (
+            ConstraintEnd::of(bodies, lhs_body_index, delta_time),
+            ConstraintEnd::of(bodies, rhs_body_index, delta_time),
+        ) else {
+            return;
+        };
+
+        let offset = math::sub_3d(rhs.position, lhs.position);
+        let length = math::dot(offset, offset).sqrt();
+        if length == T::ZERO {
+            return;
+        }
+        let normal = math::div_3d_by_1d(offset, length);
+
+        let stretch = length - rest_length;
+        let separating_speed = math::dot(math::sub_3d(rhs.velocity, lhs.velocity), normal);
+        let acceleration =
+            math::mul_3d_by_1d(normal, stiffness * stretch + damping * separating_speed);
+
+        if let Some(particle) = bodies[lhs_body_index].particle_mut() {
+            particle.accelerate(acceleration);
+        }
+        if let Some(particle) = bodies[rhs_body_index].particle_mut() {
+            particle.accelerate(math::neg_3d(acceleration));
+        }
+    }
+
+    /// Moves the bodies so that they satisfy the constraint. Doing this a few times in a row gets chains of constraints closer to all being satisfied.
+    pub(super) fn relax<B: Body<T>>(&self, bodies: &mut [B]) {
+        match *self {
+            Constraint::Distance {
+                lhs_body_index,
+                rhs_body_index,
+                distance,
+            } => relax_distance(bodies, lhs_body_index, rhs_body_index, distance, distance),
+            Constraint::Rope {
+                lhs_body_index,
+                rhs_body_index,
+                min_distance,
+                max_distance,
+            } => relax_distance(
+                bodies,
+                lhs_body_index,
+                rhs_body_index,
+                min_distance,
+                max_distance,
+            ),
+            Constraint::Spring { .. } => (),
+            Constraint::Pin {
+                body_index,
+                position,
+            } => {
+                if let Some(particle) = bodies.get_mut(body_index).and_then(Body::particle_mut) {
+                    particle.position = position;
+                }
+            }
+        }
+    }
+}
+
+/// One end of a constraint.
+struct ConstraintEnd<T> {
+    position: [T; 3],
+    velocity: [T; 3],
+    is_movable: bool,
+}
+
+impl<T: Float> ConstraintEnd<T> {
+    /// Returns None if there is no body at the index.
+    fn of<B: Body<T>>(bodies: &mut [B], body_index: usize, delta_time: T) -> Option<Self> {
+        let body = bodies.get_mut(body_index)?;
+        if body.is_none() {
+            return None;
+        }
+
+        Some(match body.particle_mut() {
+            Some(particle) => ConstraintEnd {
+                position: particle.position,
+                velocity: particle.calculate_velocity(delta_time),
+                is_movable: true,
+            },
+            None => ConstraintEnd {
+                position: body.position_unchecked(),
+                velocity: [T::ZERO; 3],
+                is_movable: false,
+            },
+        })
+    }
+}
+
+/// Moves the bodies towards or away from each other, until they are between min_distance and max_distance apart.
+fn relax_distance<T: Float, B: Body<T>>(
+    bodies: &mut [B],
+    lhs_body_index: usize,
+    rhs_body_index: usize,
+    min_distance: T,
+    max_distance: T,
+) {
+    if lhs_body_index == rhs_body_index {
+        return;
+    }
+    // The velocity isn't needed, so any delta time will do.
+    let (Some(lhs), Some(rhs)) = (
+        ConstraintEnd::of(bodies, lhs_body_index, T::ONE),
+        ConstraintEnd::of(bodies, rhs_body_index, T::ONE),
+    ) else {
+        return;
+    };
+    if !lhs.is_movable && !rhs.is_movable {
+        return;
+    }
+
+    let offset = math::sub_3d(rhs.position, lhs.position);
+    let length = math::dot(offset, offset).sqrt();
+    let target_length = if length < min_distance {
+        min_distance
+    } else if length > max_distance {
+        max_distance
+    } else {
+        return;
+    };
+    // With no direction to push them apart in, there isn't much we can do.
+    if length == T::ZERO {
+        return;
+    }
+
+    // How far lhs would have to move towards rhs, if rhs didn't move at all.
+    let correction = math::mul_3d_by_1d(offset, (length - target_length) / length);
+    let (lhs_share, rhs_share) = match (lhs.is_movable, rhs.is_movable) {
+        (true, true) => (T::from_f64(0.5), T::from_f64(0.5)),
+        (true, false) => (T::ONE, T::ZERO),
+        (false, _) => (T::ZERO, T::ONE),
+    };
+
+    if let Some(particle) = bodies[lhs_body_index].particle_mut() {
+        particle.position =
+            math::add_3d(particle.position, math::mul_3d_by_1d(correction, lhs_share));
+    }
+    if let Some(particle) = bodies[rhs_body_index].particle_mut() {
+        particle.position =
+            math::sub_3d(particle.position, math::mul_3d_by_1d(correction, rhs_share));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::physics::{
+        physics_3d::{
+            aabb::AabbCentredOrigin,
+            bodies::{CommonBody, ImmovableCuboid},
+            material::PhysicsMaterial,
+            solver::{Config, CpuSolver},
+            verlet::{bodies::Cuboid, Particle},
+        },
+        PhysicsSimulation,
+    };
+
+    use super::*;
+
+    fn create_cuboid(position: [f32; 3]) -> CommonBody<f32> {
+        CommonBody::Cuboid(Cuboid {
+            particle: Particle::from_position(position),
+            half_size: [0.5; 3],
+            material: PhysicsMaterial::default(),
+            continuous_collision_detection: false,
+        })
+    }
+
+    fn distance_between(bodies: &[CommonBody<f32>]) -> f32 {
+        let offset = math::sub_3d(
+            bodies[1].position_unchecked(),
+            bodies[0].position_unchecked(),
+        );
+        math::dot(offset, offset).sqrt()
+    }
+
+    #[test]
+    fn distance_holds_its_length() {
+        let constraint = Constraint::Distance {
+            lhs_body_index: 0,
+            rhs_body_index: 1,
+            distance: 2.0,
+        };
+
+        for rhs_x in [0.5, 2.0, 5.0] {
+            let mut bodies = vec![create_cuboid([0.0; 3]), create_cuboid([rhs_x, 0.0, 0.0])];
+            constraint.relax(&mut bodies);
+            assert_eq!(distance_between(&bodies), 2.0);
+            // Both ends move the same amount.
+            assert_eq!(
+                bodies[0].position_unchecked()[0] + bodies[1].position_unchecked()[0],
+                rhs_x
+            );
+        }
+
+        // Immovable bodies are anchors, so only the other end moves.
+        let mut bodies = vec![
+            CommonBody::ImmovableCuboid(ImmovableCuboid {
+                aabb: AabbCentredOrigin {
+                    position: [0.0; 3],
+                    half_size: [0.5; 3],
+                },
+                material: PhysicsMaterial::default(),
+            }),
+            create_cuboid([5.0, 0.0, 0.0]),
+        ];
+        constraint.relax(&mut bodies);
+        assert_eq!(bodies[1].position_unchecked(), [2.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn rope_only_acts_outside_of_its_range() {
+        let constraint = Constraint::Rope {
+            lhs_body_index: 0,
+            rhs_body_index: 1,
+            min_distance: 1.0,
+            max_distance: 3.0,
+        };
+
+        for (rhs_x, expected_distance) in
+            [(0.5, 1.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0), (5.0, 3.0)]
+        {
+            let mut bodies = vec![create_cuboid([0.0; 3]), create_cuboid([rhs_x, 0.0, 0.0])];
+            constraint.relax(&mut bodies);
+            assert_eq!(distance_between(&bodies), expected_distance);
+            if rhs_x == expected_distance {
+                assert_eq!(bodies[0].position_unchecked(), [0.0; 3]);
+                assert_eq!(bodies[1].position_unchecked(), [rhs_x, 0.0, 0.0]);
+            }
+        }
+    }
+
+    #[test]
+    fn pinned_body_ends_exactly_at_its_position() {
+        let mut solver = CpuSolver::new(Config {
+            gravity: [0.0, 10.0, 0.0],
+            grid_origin: [-25.0; 3],
+            bodies: vec![create_cuboid([0.0; 3]), create_cuboid([5.0, 0.0, 0.0])],
+            // The pin comes first, but the distance relaxed after it mustn't drag the pinned body away.
+            constraints: vec![
+                Constraint::Pin {
+                    body_index: 0,
+                    position: [0.0; 3],
+                },
+                Constraint::Distance {
+                    lhs_body_index: 0,
+                    rhs_body_index: 1,
+                    distance: 2.0,
+                },
+            ],
+            ..Default::default()
+        });
+
+        for _ in 0..10 {
+            solver.update(0.04);
+            assert_eq!(solver.bodies[0].position_unchecked(), [0.0; 3]);
+        }
+    }
+
+    #[test]
+    fn constraints_on_removed_bodies_are_dropped() {
+        let mut solver = CpuSolver::new(Config {
+            gravity: [0.0; 3],
+            grid_origin: [-25.0; 3],
+            constraints: vec![
+                Constraint::Distance {
+                    lhs_body_index: 0,
+                    rhs_body_index: 1,
+                    distance: 2.0,
+                },
+                Constraint::Rope {
+                    lhs_body_index: 1,
+                    rhs_body_index: 2,
+                    min_distance: 0.0,
+                    max_distance: 2.0,
+                },
+                Constraint::Pin {
+                    body_index: 2,
+                    position: [4.0, 0.0, 0.0],
+                },
+            ],
+            ..Default::default()
+        });
+        let bodies: Vec<_> = (0..3)
+            .map(|i| solver.insert(create_cuboid([i as f32 * 2.0, 0.0, 0.0])))
+            .collect();
+
+        // Body 2 is moved into body 0's index.
+        solver.remove(bodies[0]);
+
+        assert_eq!(
+            solver.constraints,
+            [
+                Constraint::Rope {
+                    lhs_body_index: 1,
+                    rhs_body_index: 0,
+                    min_distance: 0.0,
+                    max_distance: 2.0,
+                },
+                Constraint::Pin {
+                    body_index: 0,
+                    position: [4.0, 0.0, 0.0],
+                },
+            ]
+        );
+    }
+}
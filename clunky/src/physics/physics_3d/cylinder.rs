@@ -0,0 +1,207 @@
+use crate::math::{self, Float};
+use serde::{Deserialize, Serialize};
+
+use super::{aabb::AabbCentredOrigin, sphere::Sphere};
+
+/// An upright cylinder, with its flat ends facing along the y axis.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Cylinder<T>
+where
+    T: Float,
+{
+    pub position: [T; 3],
+    pub radius: T,
+    /// Half the distance between the flat ends.
+    pub half_height: T,
+}
+
+impl<T> Cylinder<T>
+where
+    T: Float,
+{
+    pub fn is_intersected_by_point(&self, point: [T; 3]) -> bool {
+        let offset = math::sub_3d(point, self.position);
+        offset[1].abs() <= self.half_height
+            && offset[0] * offset[0] + offset[2] * offset[2] <= self.radius * self.radius
+    }
+
+    pub fn is_intersected_by_sphere(&self, sphere: Sphere<T>) -> bool {
+        self.get_collision_normal_and_penetration_with_sphere(&sphere)
+            .1
+            >= T::ZERO
+    }
+
+    pub fn is_intersected_by_aabb(&self, aabb: AabbCentredOrigin<T>) -> bool {
+        self.get_collision_normal_and_penetration_with_aabb(&aabb).1 >= T::ZERO
+    }
+
+    pub fn is_intersected_by_cylinder(&self, cylinder: Cylinder<T>) -> bool {
+        self.get_collision_normal_and_penetration_with_cylinder(&cylinder)
+            .1
+            >= T::ZERO
+    }
+
+    /// The normal points from self towards the sphere, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_sphere(
+        &self,
+        sphere: &Sphere<T>,
+    ) -> ([T; 3], T) {
+        let offset = math::sub_3d(sphere.position, self.position);
+        let radial_offset = [offset[0], T::ZERO, offset[2]];
+        let radial_distance = math::get_magnitude_3d(radial_offset);
+
+        let mut closest_point = if radial_distance > self.radius {
+            math::mul_3d_by_1d(radial_offset, self.radius / radial_distance)
+        } else {
+            radial_offset
+        };
+        closest_point[1] = clamp(offset[1], -self.half_height, self.half_height);
+
+        let difference = math::sub_3d(offset, closest_point);
+        let distance = math::get_magnitude_3d(difference);
+
+        if distance != T::ZERO {
+            return (
+                math::div_3d_by_1d(difference, distance),
+                sphere.radius - distance,
+            );
+        }
+
+        // The centre of the sphere is inside the cylinder, so we push it out of the side or an end, whichever is closer.
+        let distance_to_side = self.radius - radial_distance;
+        let distance_to_end = self.half_height - offset[1].abs();
+
+        if distance_to_side < distance_to_end {
+            (
+                radial_normal(radial_offset, radial_distance),
+                sphere.radius + distance_to_side,
+            )
+        } else {
+            (
+                [T::ZERO, sign(offset[1]), T::ZERO],
+                sphere.radius + distance_to_end,
+            )
+        }
+    }
+
+    /// The normal points from self towards the aabb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_aabb(
+        &self,
+        aabb: &AabbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        // Vertically the cylinder is just an aabb, so we only need to do circle against rectangle on the x and z axis.
+        let y_difference = aabb.position[1] - self.position[1];
+        let y_penetration = self.half_height + aabb.half_size[1] - y_difference.abs();
+
+        let closest_point = [
+            clamp(
+                self.position[0],
+                aabb.position[0] - aabb.half_size[0],
+                aabb.position[0] + aabb.half_size[0],
+            ),
+            T::ZERO,
+            clamp(
+                self.position[2],
+                aabb.position[2] - aabb.half_size[2],
+                aabb.position[2] + aabb.half_size[2],
+            ),
+        ];
+        let radial_difference = [
+            closest_point[0] - self.position[0],
+            T::ZERO,
+            closest_point[2] - self.position[2],
+        ];
+        let radial_distance = math::get_magnitude_3d(radial_difference);
+
+        let (radial_normal, radial_penetration) = if radial_distance != T::ZERO {
+            (
+                math::div_3d_by_1d(radial_difference, radial_distance),
+                self.radius - radial_distance,
+            )
+        } else {
+            // The centre is inside the rectangle, so we push it out of whichever side is closest.
+            let x_offset = self.position[0] - aabb.position[0];
+            let z_offset = self.position[2] - aabb.position[2];
+            let x_distance_to_side = aabb.half_size[0] - x_offset.abs();
+            let z_distance_to_side = aabb.half_size[2] - z_offset.abs();
+
+            // The aabb is on the opposite side to the side we are leaving through.
+            if x_distance_to_side < z_distance_to_side {
+                (
+                    [-sign(x_offset), T::ZERO, T::ZERO],
+                    self.radius + x_distance_to_side,
+                )
+            } else {
+                (
+                    [T::ZERO, T::ZERO, -sign(z_offset)],
+                    self.radius + z_distance_to_side,
+                )
+            }
+        };
+
+        if radial_penetration < y_penetration {
+            (radial_normal, radial_penetration)
+        } else {
+            ([T::ZERO, sign(y_difference), T::ZERO], y_penetration)
+        }
+    }
+
+    /// The normal points from self towards other, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_cylinder(
+        &self,
+        other: &Cylinder<T>,
+    ) -> ([T; 3], T) {
+        let y_difference = other.position[1] - self.position[1];
+        let y_penetration = self.half_height + other.half_height - y_difference.abs();
+
+        let radial_offset = [
+            other.position[0] - self.position[0],
+            T::ZERO,
+            other.position[2] - self.position[2],
+        ];
+        let radial_distance = math::get_magnitude_3d(radial_offset);
+        let radial_penetration = self.radius + other.radius - radial_distance;
+
+        if radial_penetration < y_penetration {
+            (
+                radial_normal(radial_offset, radial_distance),
+                radial_penetration,
+            )
+        } else {
+            ([T::ZERO, sign(y_difference), T::ZERO], y_penetration)
+        }
+    }
+}
+
+/// Normalises a horizontal offset. If there is no offset, any horizontal direction will do.
+#[inline]
+pub(super) fn radial_normal<T: Float>(radial_offset: [T; 3], radial_distance: T) -> [T; 3] {
+    if radial_distance == T::ZERO {
+        [T::ONE, T::ZERO, T::ZERO]
+    } else {
+        math::div_3d_by_1d(radial_offset, radial_distance)
+    }
+}
+
+#[inline]
+pub(super) fn clamp<T: Float>(value: T, min: T, max: T) -> T {
+    if value < min {
+        min
+    } else if value > max {
+        max
+    } else {
+        value
+    }
+}
+
+#[inline]
+fn sign<T: Float>(value: T) -> T {
+    if value.is_sign_positive() {
+        T::ONE
+    } else {
+        -T::ONE
+    }
+}
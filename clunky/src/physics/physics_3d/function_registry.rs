@@ -0,0 +1,106 @@
+//! Function pointers can't be saved, so instead they are registered here under a name, and the name is saved in their place.
+//!
+//! Any function stored in a body or the solver, such as [super::bodies::CollisionRecorderCuboid::save_collision] or [super::solver::OutsideOfGridBoundsBehaviour::Custom], must be registered before serializing or deserializing, otherwise it will error.
+//! Register them at startup, using the same names every time, so that old saves can still find them.
+
+use std::{
+    any::{Any, TypeId},
+    sync::RwLock,
+};
+
+struct RegisteredFunction {
+    name: &'static str,
+    type_id: TypeId,
+    function: Box<dyn Any + Send + Sync>,
+}
+
+static REGISTERED_FUNCTIONS: RwLock<Vec<RegisteredFunction>> = RwLock::new(Vec::new());
+
+/// Registers a function pointer under a name. Names only have to be unique between functions of the same type.
+/// Registering a name again replaces the function.
+///
+/// The function type usually needs to be written out, so that it isn't registered as a function item:
+/// ```ignore
+/// register_function::<fn(&mut CommonBody<f32>) -> bool>("save_everything", save_everything);
+/// ```
+pub fn register_function<F>(name: &'static str, function: F)
+where
+    F: Copy + PartialEq + Send + Sync + 'static,
+{
+    let mut registered_functions = REGISTERED_FUNCTIONS.write().unwrap();
+    let registered_function = RegisteredFunction {
+        name,
+        type_id: TypeId::of::<F>(),
+        function: Box::new(function),
+    };
+
+    match registered_functions.iter_mut().find(|registered_function| {
+        registered_function.type_id == TypeId::of::<F>() && registered_function.name == name
+    }) {
+        Some(existing_function) => *existing_function = registered_function,
+        None => registered_functions.push(registered_function),
+    }
+}
+
+/// Gets the function registered under the name, should there be one of this type.
+pub fn registered_function<F>(name: &str) -> Option<F>
+where
+    F: Copy + PartialEq + Send + Sync + 'static,
+{
+    REGISTERED_FUNCTIONS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|registered_function| registered_function.name == name)
+        .find_map(|registered_function| registered_function.function.downcast_ref::<F>())
+        .copied()
+}
+
+/// Gets the name the function was registered under, should it have been registered.
+pub fn registered_function_name<F>(function: F) -> Option<&'static str>
+where
+    F: Copy + PartialEq + Send + Sync + 'static,
+{
+    REGISTERED_FUNCTIONS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|registered_function| {
+            registered_function.function.downcast_ref::<F>() == Some(&function)
+        })
+        .map(|registered_function| registered_function.name)
+}
+
+/// For use with `#[serde(with = "...")]`, to save a function pointer as the name it was registered under.
+pub mod serde_function {
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<F, S>(function: &F, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        F: Copy + PartialEq + Send + Sync + 'static,
+        S: Serializer,
+    {
+        match super::registered_function_name(*function) {
+            Some(name) => serializer.serialize_str(name),
+            None => Err(ser::Error::custom(format!(
+                "a function of type {} hasn't been registered",
+                std::any::type_name::<F>()
+            ))),
+        }
+    }
+
+    pub fn deserialize<'de, F, D>(deserializer: D) -> Result<F, D::Error>
+    where
+        F: Copy + PartialEq + Send + Sync + 'static,
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        super::registered_function(&name).ok_or_else(|| {
+            de::Error::custom(format!(
+                "no function of type {} has been registered as {:?}",
+                std::any::type_name::<F>(),
+                name
+            ))
+        })
+    }
+}
@@ -0,0 +1,427 @@
+use crate::math::{self, Float};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+use super::{
+    aabb::AabbCentredOrigin,
+    capsule::Capsule,
+    cylinder::Cylinder,
+    obb::ObbCentredOrigin,
+    sphere::Sphere,
+    triangle_mesh::{self, max, min},
+};
+
+/// A grid of heights, for terrain.
+/// The samples are spacing apart along x and z, starting at origin. As +y is down, each sample's surface is at origin[1] - height.
+///
+/// The ground is solid from the surface down to origin[1], so anything that ends up beneath the surface gets pushed back out of the top, rather than falling through.
+/// Each cell between 4 samples is split into 2 triangles, and only the cells under a shape are checked against it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heightfield<T>
+where
+    T: Float,
+{
+    heights: Vec<T>,
+    /// How many samples there are along x.
+    columns: usize,
+    /// How many samples there are along z.
+    rows: usize,
+    spacing: [T; 2],
+    origin: [T; 3],
+    lowest_height: T,
+    highest_height: T,
+}
+
+impl<T> Heightfield<T>
+where
+    T: Float,
+{
+    /// The height at origin + [column * spacing[0], _, row * spacing[1]] is heights[row * columns + column].
+    /// Panics if there are less than 2 columns or 2 rows, or if the last row isn't full.
+    pub fn new(heights: Vec<T>, columns: usize, spacing: [T; 2], origin: [T; 3]) -> Self {
+        assert!(
+            columns >= 2 && heights.len() >= columns * 2 && heights.len().is_multiple_of(columns),
+            "A heightfield needs at least 2 by 2 samples, and a whole number of rows."
+        );
+
+        let (lowest_height, highest_height) = heights
+            .iter()
+            .fold((T::MAX, -T::MAX), |(lowest, highest), height| {
+                (min(lowest, *height), max(highest, *height))
+            });
+
+        Self {
+            rows: heights.len() / columns,
+            heights,
+            columns,
+            spacing,
+            origin,
+            lowest_height,
+            highest_height,
+        }
+    }
+
+    /// Reads the heights from a grayscale png, where black is 0 high and white is height_scale high.
+    /// Each pixel is a sample, and the top row of pixels has the smallest z. Colour pngs use their red channel.
+    pub fn from_png(png: &[u8], spacing: [T; 2], height_scale: T, origin: [T; 3]) -> Self {
+        let mut decoder = png::Decoder::new(png);
+        // Turns palettes, and bit depths below 8, into 8 bits per channel.
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().unwrap();
+        let mut bytes = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut bytes).unwrap();
+
+        let bytes_per_channel = match info.bit_depth {
+            png::BitDepth::Sixteen => 2,
+            _ => 1,
+        };
+        let white = if bytes_per_channel == 2 {
+            u16::MAX as f32
+        } else {
+            u8::MAX as f32
+        };
+
+        let heights = bytes[..info.buffer_size()]
+            .chunks_exact(info.color_type.samples() * bytes_per_channel)
+            .map(|pixel| {
+                let value = if bytes_per_channel == 2 {
+                    u16::from_be_bytes([pixel[0], pixel[1]]) as f32
+                } else {
+                    pixel[0] as f32
+                };
+                T::from_f32(value / white) * height_scale
+            })
+            .collect();
+
+        Self::new(heights, info.width as usize, spacing, origin)
+    }
+
+    pub fn heights(&self) -> &[T] {
+        &self.heights
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn spacing(&self) -> [T; 2] {
+        self.spacing
+    }
+
+    pub fn origin(&self) -> [T; 3] {
+        self.origin
+    }
+
+    /// The smallest aabb that contains the ground.
+    pub fn bounds(&self) -> AabbCentredOrigin<T> {
+        let half = T::from_f32(0.5);
+        let half_size = [
+            T::from_f32((self.columns - 1) as f32) * self.spacing[0] * half,
+            (self.highest_height - min(self.lowest_height, T::ZERO)) * half,
+            T::from_f32((self.rows - 1) as f32) * self.spacing[1] * half,
+        ];
+        AabbCentredOrigin {
+            position: [
+                self.origin[0] + half_size[0],
+                self.origin[1] - self.highest_height + half_size[1],
+                self.origin[2] + half_size[2],
+            ],
+            half_size,
+        }
+    }
+
+    pub fn translate(&mut self, translation: [T; 3]) {
+        self.origin = math::add_3d(self.origin, translation);
+    }
+
+    /// The normal points from self towards the sphere, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_sphere(
+        &self,
+        sphere: &Sphere<T>,
+    ) -> ([T; 3], T) {
+        self.deepest_contact(
+            AabbCentredOrigin {
+                position: sphere.position,
+                half_size: [sphere.radius; 3],
+            },
+            |_| sphere.radius,
+            |triangle| {
+                triangle_mesh::get_collision_normal_and_penetration_with_sphere(triangle, sphere)
+            },
+        )
+    }
+
+    /// The normal points from self towards the aabb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_aabb(
+        &self,
+        aabb: &AabbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        self.get_collision_normal_and_penetration_with_obb(&ObbCentredOrigin::from_aabb(aabb))
+    }
+
+    /// The normal points from self towards the obb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_obb(
+        &self,
+        obb: &ObbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        self.deepest_contact(
+            AabbCentredOrigin {
+                position: obb.position,
+                half_size: obb.enclosing_half_size(),
+            },
+            |normal| obb.projected_half_size(normal),
+            |triangle| triangle_mesh::get_collision_normal_and_penetration_with_obb(triangle, obb),
+        )
+    }
+
+    /// The normal points from self towards the capsule, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_capsule(
+        &self,
+        capsule: &Capsule<T>,
+    ) -> ([T; 3], T) {
+        self.deepest_contact(
+            AabbCentredOrigin {
+                position: capsule.position,
+                half_size: [
+                    capsule.radius,
+                    capsule.half_height + capsule.radius,
+                    capsule.radius,
+                ],
+            },
+            |normal| capsule.radius + capsule.half_height * normal[1].abs(),
+            |triangle| {
+                triangle_mesh::get_collision_normal_and_penetration_with_capsule(triangle, capsule)
+            },
+        )
+    }
+
+    /// The normal points from self towards the cylinder, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    ///
+    /// The cylinder is treated as the aabb around it.
+    pub fn get_collision_normal_and_penetration_with_cylinder(
+        &self,
+        cylinder: &Cylinder<T>,
+    ) -> ([T; 3], T) {
+        self.get_collision_normal_and_penetration_with_aabb(&AabbCentredOrigin {
+            position: cylinder.position,
+            half_size: [cylinder.radius, cylinder.half_height, cylinder.radius],
+        })
+    }
+
+    /// Sweeps an aabb along a normalised direction, returning the normal of the surface it hit, and how far the aabb travelled first.
+    /// If the aabb's centre starts under the surface, the distance is 0.
+    pub fn cast_aabb(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+    ) -> Option<([T; 3], T)> {
+        if let Some((normal, _)) = self.depth_under_surface(origin) {
+            return Some((normal, T::ZERO));
+        }
+
+        let mut max_distance = max_distance;
+        let mut closest_hit = None;
+
+        let end = math::add_3d(origin, math::mul_3d_by_1d(direction, max_distance));
+        let rows = self.cells_between(
+            2,
+            min(origin[2], end[2]) - half_size[2],
+            max(origin[2], end[2]) + half_size[2],
+        );
+        // Going through the rows in the order the cast reaches them means that once a row is further away than the closest hit, so are the rest.
+        let backwards = direction[2] < T::ZERO;
+
+        for step in 0..rows.len() {
+            let row = if backwards {
+                rows.end - 1 - step
+            } else {
+                rows.start + step
+            };
+
+            // Work out which part of the cast is over this row, and so which columns it can touch.
+            let row_start = self.origin[2] + T::from_f32(row as f32) * self.spacing[1];
+            let (mut entry, mut exit) = if direction[2] == T::ZERO {
+                (T::ZERO, max_distance)
+            } else {
+                let first = (row_start - half_size[2] - origin[2]) / direction[2];
+                let second =
+                    (row_start + self.spacing[1] + half_size[2] - origin[2]) / direction[2];
+                (min(first, second), max(first, second))
+            };
+            entry = max(entry, T::ZERO);
+            exit = min(exit, max_distance);
+            if entry > max_distance {
+                break;
+            }
+            if entry > exit {
+                continue;
+            }
+
+            let entry_x = origin[0] + direction[0] * entry;
+            let exit_x = origin[0] + direction[0] * exit;
+            for column in self.cells_between(
+                0,
+                min(entry_x, exit_x) - half_size[0],
+                max(entry_x, exit_x) + half_size[0],
+            ) {
+                for triangle in self.cell_triangles(column, row) {
+                    if let Some(hit) = triangle_mesh::cast_aabb_at_triangle(
+                        triangle,
+                        origin,
+                        half_size,
+                        direction,
+                        max_distance,
+                    ) {
+                        max_distance = hit.1;
+                        closest_hit = Some(hit);
+                    }
+                }
+            }
+        }
+
+        closest_hit
+    }
+
+    /// Finds the deepest contact between a shape and the cells under it. If no cells are under it, the penetration is -T::MAX.
+    /// The reach is how far the shape goes along a direction from its centre, and is used to push shapes whose centre is under the surface back out of the top.
+    #[inline]
+    fn deepest_contact(
+        &self,
+        shape_bounds: AabbCentredOrigin<T>,
+        reach: impl Fn([T; 3]) -> T,
+        mut get_collision_normal_and_penetration: impl FnMut([[T; 3]; 3]) -> ([T; 3], T),
+    ) -> ([T; 3], T) {
+        let mut deepest = ([T::ZERO; 3], -T::MAX);
+
+        let shape_min = math::sub_3d(shape_bounds.position, shape_bounds.half_size);
+        let shape_max = math::add_3d(shape_bounds.position, shape_bounds.half_size);
+        for row in self.cells_between(2, shape_min[2], shape_max[2]) {
+            for column in self.cells_between(0, shape_min[0], shape_max[0]) {
+                let triangles = self.cell_triangles(column, row);
+                // Skip cells that the shape is completely above.
+                let [[a, b, c], [_, _, d]] = triangles;
+                if shape_max[1] < min(min(a[1], b[1]), min(c[1], d[1])) {
+                    continue;
+                }
+
+                for triangle in triangles {
+                    let contact = get_collision_normal_and_penetration(triangle);
+                    if contact.1 > deepest.1 {
+                        deepest = contact;
+                    }
+                }
+            }
+        }
+
+        if let Some((normal, depth)) = self.depth_under_surface(shape_bounds.position) {
+            let penetration = reach(normal) + depth;
+            if penetration > deepest.1 {
+                deepest = (normal, penetration);
+            }
+        }
+
+        deepest
+    }
+
+    /// If the point is under the surface, returns the upwards normal of the surface above it, and how far below the surface it is.
+    fn depth_under_surface(&self, point: [T; 3]) -> Option<([T; 3], T)> {
+        let column = self.cells_between(0, point[0], point[0]);
+        let row = self.cells_between(2, point[2], point[2]);
+        if column.is_empty() || row.is_empty() {
+            return None;
+        }
+        let (column, row) = (column.start, row.start);
+
+        // The point could be just past the edge of the heightfield, as cells_between only floors.
+        let corner = self.sample(column, row);
+        let far_corner = self.sample(column + 1, row + 1);
+        if point[0] < corner[0]
+            || point[0] > far_corner[0]
+            || point[2] < corner[2]
+            || point[2] > far_corner[2]
+        {
+            return None;
+        }
+
+        // The cell is split from its corner to its far corner, and the triangles are either side of that.
+        let [first, second] = self.cell_triangles(column, row);
+        let triangle = if (point[0] - corner[0]) / self.spacing[0]
+            >= (point[2] - corner[2]) / self.spacing[1]
+        {
+            first
+        } else {
+            second
+        };
+
+        let [a, b, c] = triangle;
+        let mut normal = math::normalise_3d(math::cross(math::sub_3d(b, a), math::sub_3d(c, a)));
+        // Up is -y.
+        if normal[1] > T::ZERO {
+            normal = math::neg_3d(normal);
+        }
+
+        let depth = -math::dot(math::sub_3d(point, a), normal);
+        if depth > T::ZERO {
+            Some((normal, depth))
+        } else {
+            None
+        }
+    }
+
+    /// Gets the cells that overlap from min to max along x (axis 0) or z (axis 2).
+    #[inline]
+    fn cells_between(&self, axis: usize, min: T, max: T) -> Range<usize> {
+        let (cells, spacing) = if axis == 0 {
+            (self.columns - 1, self.spacing[0])
+        } else {
+            (self.rows - 1, self.spacing[1])
+        };
+
+        let cell = |coordinate: T| {
+            let cell = (coordinate - self.origin[axis]) / spacing;
+            // to_isize truncates, so this floors it for negatives.
+            if cell < T::ZERO {
+                cell.to_isize() - 1
+            } else {
+                cell.to_isize()
+            }
+        };
+
+        let first = cell(min).max(0) as usize;
+        let end = (cell(max) + 1).clamp(0, cells as isize) as usize;
+        first..end
+    }
+
+    #[inline]
+    fn sample(&self, column: usize, row: usize) -> [T; 3] {
+        [
+            self.origin[0] + T::from_f32(column as f32) * self.spacing[0],
+            self.origin[1] - self.heights[row * self.columns + column],
+            self.origin[2] + T::from_f32(row as f32) * self.spacing[1],
+        ]
+    }
+
+    /// Splits the cell from its corner to its far corner.
+    #[inline]
+    fn cell_triangles(&self, column: usize, row: usize) -> [[[T; 3]; 3]; 2] {
+        let corner = self.sample(column, row);
+        let next_column = self.sample(column + 1, row);
+        let next_row = self.sample(column, row + 1);
+        let far_corner = self.sample(column + 1, row + 1);
+        [
+            [corner, next_column, far_corner],
+            [corner, far_corner, next_row],
+        ]
+    }
+}
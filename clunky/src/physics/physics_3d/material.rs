@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+use crate::math::Float;
+
+/// How 2 bodies' values are combined into the one used for their collision.
+///
+/// When the 2 bodies want different rules, the one later in this list wins, so something like ice using [CombineRule::Min] for friction stays slippery, whatever touches it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum CombineRule {
+    Average,
+    Min,
+    Multiply,
+    Max,
+}
+
+impl CombineRule {
+    #[inline]
+    pub fn combine<T: Float>(self, lhs: T, rhs: T) -> T {
+        match self {
+            CombineRule::Average => (lhs + rhs) * T::from_f64(0.5),
+            CombineRule::Min => {
+                if lhs < rhs {
+                    lhs
+                } else {
+                    rhs
+                }
+            }
+            CombineRule::Multiply => lhs * rhs,
+            CombineRule::Max => {
+                if lhs > rhs {
+                    lhs
+                } else {
+                    rhs
+                }
+            }
+        }
+    }
+}
+
+/// What a body's surface is like.
+///
+/// Friction follows Coulomb's model: a body sliding along another is slowed by dynamic_friction times how hard they are pressed together,
+/// and a body that is barely sliding is stopped entirely, as long as stopping it takes less than static_friction times how hard they are pressed together.
+/// Restitution is how bouncy the collision is, from 0 for no bounce, to 1 for bouncing back at the same speed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PhysicsMaterial<T> {
+    pub static_friction: T,
+    pub dynamic_friction: T,
+    pub restitution: T,
+    pub friction_combine: CombineRule,
+    pub restitution_combine: CombineRule,
+}
+
+impl<T> PhysicsMaterial<T>
+where
+    T: Float,
+{
+    /// Barely any friction, and it stays that way, no matter what is on it.
+    pub fn ice() -> Self {
+        Self {
+            static_friction: T::from_f64(0.05),
+            dynamic_friction: T::from_f64(0.02),
+            restitution: T::from_f64(0.1),
+            friction_combine: CombineRule::Min,
+            restitution_combine: CombineRule::Average,
+        }
+    }
+
+    /// Lots of friction, and nothing bounces off of it.
+    pub fn mud() -> Self {
+        Self {
+            static_friction: T::from_f64(2.0),
+            dynamic_friction: T::from_f64(1.5),
+            restitution: T::ZERO,
+            friction_combine: CombineRule::Max,
+            restitution_combine: CombineRule::Min,
+        }
+    }
+
+    /// Everything bounces off of it, with more speed than it hit it with.
+    pub fn bouncy_pad() -> Self {
+        Self {
+            static_friction: T::from_f64(0.5),
+            dynamic_friction: T::from_f64(0.4),
+            restitution: T::from_f64(1.2),
+            friction_combine: CombineRule::Average,
+            restitution_combine: CombineRule::Max,
+        }
+    }
+
+    /// Combines the 2 materials into what is used for a collision between them.
+    #[inline]
+    pub fn combine(&self, other: &PhysicsMaterial<T>) -> CombinedMaterial<T> {
+        let friction_combine = self.friction_combine.max(other.friction_combine);
+        let restitution_combine = self.restitution_combine.max(other.restitution_combine);
+        CombinedMaterial {
+            static_friction: friction_combine.combine(self.static_friction, other.static_friction),
+            dynamic_friction: friction_combine
+                .combine(self.dynamic_friction, other.dynamic_friction),
+            restitution: restitution_combine.combine(self.restitution, other.restitution),
+        }
+    }
+}
+
+/// No friction, and half bouncy. This is how bodies behaved before they had materials.
+impl<T> Default for PhysicsMaterial<T>
+where
+    T: Float,
+{
+    fn default() -> Self {
+        Self {
+            static_friction: T::ZERO,
+            dynamic_friction: T::ZERO,
+            restitution: T::from_f64(0.5),
+            friction_combine: CombineRule::Average,
+            restitution_combine: CombineRule::Average,
+        }
+    }
+}
+
+/// The material used for a collision between 2 bodies. See [PhysicsMaterial::combine].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CombinedMaterial<T> {
+    pub static_friction: T,
+    pub dynamic_friction: T,
+    pub restitution: T,
+}
@@ -0,0 +1,364 @@
+use crate::math::{self, Float};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    aabb::AabbCentredOrigin,
+    capsule::Capsule,
+    cylinder::{clamp, Cylinder},
+    sphere::Sphere,
+};
+
+extern crate test;
+
+/// An oriented bounding box. An aabb that has been rotated around its centre.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct ObbCentredOrigin<T>
+where
+    T: Float,
+{
+    pub position: [T; 3],
+    /// The half size along each of the obb's own axes.
+    pub half_size: [T; 3],
+    /// The obb's local x, y and z axes in world space. They must be normalised and perpendicular to each other.
+    pub axes: [[T; 3]; 3],
+}
+
+impl<T> ObbCentredOrigin<T>
+where
+    T: Float,
+{
+    /// Creates an obb rotated by a quaternion.
+    /// The quaternion is read the same way as [math::Matrix4::from_quaternion], so an obb made from a glTF node's rotation lines up with what is rendered.
+    pub fn from_quaternion(position: [T; 3], half_size: [T; 3], quaternion: [T; 4]) -> Self {
+        let two = T::from_f32(2.0);
+        let q1q1 = quaternion[1] * quaternion[1];
+        let q2q2 = quaternion[2] * quaternion[2];
+        let q3q3 = quaternion[3] * quaternion[3];
+
+        Self {
+            position,
+            half_size,
+            axes: [
+                [
+                    T::ONE - two * (q2q2 + q3q3),
+                    two * (quaternion[1] * quaternion[2] - quaternion[0] * quaternion[3]),
+                    two * (quaternion[0] * quaternion[2] + quaternion[1] * quaternion[3]),
+                ],
+                [
+                    two * (quaternion[1] * quaternion[2] + quaternion[0] * quaternion[3]),
+                    T::ONE - two * (q1q1 + q3q3),
+                    two * (quaternion[2] * quaternion[3] - quaternion[0] * quaternion[1]),
+                ],
+                [
+                    two * (quaternion[1] * quaternion[3] - quaternion[0] * quaternion[2]),
+                    two * (quaternion[0] * quaternion[1] + quaternion[2] * quaternion[3]),
+                    T::ONE - two * (q1q1 + q2q2),
+                ],
+            ],
+        }
+    }
+
+    /// Creates an obb that isn't rotated at all.
+    pub fn from_aabb(aabb: &AabbCentredOrigin<T>) -> Self {
+        Self {
+            position: aabb.position,
+            half_size: aabb.half_size,
+            axes: [
+                [T::ONE, T::ZERO, T::ZERO],
+                [T::ZERO, T::ONE, T::ZERO],
+                [T::ZERO, T::ZERO, T::ONE],
+            ],
+        }
+    }
+
+    /// Gets the half size of the smallest aabb that contains the obb.
+    pub fn enclosing_half_size(&self) -> [T; 3] {
+        let mut enclosing_half_size = [T::ZERO; 3];
+        for (axis, half_size) in self.axes.iter().zip(self.half_size) {
+            for (enclosing_half_size, axis_component) in enclosing_half_size.iter_mut().zip(axis) {
+                *enclosing_half_size += (*axis_component * half_size).abs();
+            }
+        }
+        enclosing_half_size
+    }
+
+    /// Converts a point from world space, into the obb's local space.
+    #[inline]
+    pub fn to_local(&self, point: [T; 3]) -> [T; 3] {
+        let offset = math::sub_3d(point, self.position);
+        [
+            math::dot(offset, self.axes[0]),
+            math::dot(offset, self.axes[1]),
+            math::dot(offset, self.axes[2]),
+        ]
+    }
+
+    /// Converts a point from the obb's local space, into world space.
+    #[inline]
+    pub fn to_world(&self, local_point: [T; 3]) -> [T; 3] {
+        let mut point = self.position;
+        for (axis, local_coordinate) in self.axes.iter().zip(local_point) {
+            point = math::add_3d(point, math::mul_3d_by_1d(*axis, local_coordinate));
+        }
+        point
+    }
+
+    /// Gets the point on or in the obb that is closest to the point.
+    pub fn closest_point(&self, point: [T; 3]) -> [T; 3] {
+        let local_point = self.to_local(point);
+        self.to_world([
+            clamp(local_point[0], -self.half_size[0], self.half_size[0]),
+            clamp(local_point[1], -self.half_size[1], self.half_size[1]),
+            clamp(local_point[2], -self.half_size[2], self.half_size[2]),
+        ])
+    }
+
+    /// How far the obb reaches from its centre along the axis.
+    #[inline]
+    pub fn projected_half_size(&self, axis: [T; 3]) -> T {
+        (math::dot(self.axes[0], axis) * self.half_size[0]).abs()
+            + (math::dot(self.axes[1], axis) * self.half_size[1]).abs()
+            + (math::dot(self.axes[2], axis) * self.half_size[2]).abs()
+    }
+
+    pub fn is_intersected_by_point(&self, point: [T; 3]) -> bool {
+        let local_point = self.to_local(point);
+        local_point[0].abs() <= self.half_size[0]
+            && local_point[1].abs() <= self.half_size[1]
+            && local_point[2].abs() <= self.half_size[2]
+    }
+
+    pub fn is_intersected_by_sphere(&self, sphere: Sphere<T>) -> bool {
+        sphere.is_intersected_by_point(self.closest_point(sphere.position))
+    }
+
+    pub fn is_intersected_by_aabb(&self, aabb: AabbCentredOrigin<T>) -> bool {
+        self.is_intersected_by_obb(ObbCentredOrigin::from_aabb(&aabb))
+    }
+
+    pub fn is_intersected_by_obb(&self, obb: ObbCentredOrigin<T>) -> bool {
+        self.get_collision_normal_and_penetration_with_obb(&obb).1 >= T::ZERO
+    }
+
+    pub fn is_intersected_by_capsule(&self, capsule: Capsule<T>) -> bool {
+        self.get_collision_normal_and_penetration_with_capsule(&capsule)
+            .1
+            >= T::ZERO
+    }
+
+    pub fn is_intersected_by_cylinder(&self, cylinder: Cylinder<T>) -> bool {
+        self.get_collision_normal_and_penetration_with_cylinder(&cylinder)
+            .1
+            >= T::ZERO
+    }
+
+    /// The normal points from self towards the sphere, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_sphere(
+        &self,
+        sphere: &Sphere<T>,
+    ) -> ([T; 3], T) {
+        let difference = math::sub_3d(sphere.position, self.closest_point(sphere.position));
+        let distance = math::get_magnitude_3d(difference);
+
+        if distance != T::ZERO {
+            return (
+                math::div_3d_by_1d(difference, distance),
+                sphere.radius - distance,
+            );
+        }
+
+        // The centre of the sphere is inside the obb, so we push it out of whichever face is closest.
+        let local_point = self.to_local(sphere.position);
+        let mut normal = self.axes[0];
+        let mut smallest_distance_to_face = T::MAX;
+        for (axis, local_coordinate) in local_point.iter().enumerate() {
+            let distance_to_face = self.half_size[axis] - local_coordinate.abs();
+            if distance_to_face < smallest_distance_to_face {
+                smallest_distance_to_face = distance_to_face;
+                normal = if local_coordinate.is_sign_positive() {
+                    self.axes[axis]
+                } else {
+                    math::neg_3d(self.axes[axis])
+                };
+            }
+        }
+
+        (normal, sphere.radius + smallest_distance_to_face)
+    }
+
+    /// The normal points from self towards the aabb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_aabb(
+        &self,
+        aabb: &AabbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        self.get_collision_normal_and_penetration_with_obb(&ObbCentredOrigin::from_aabb(aabb))
+    }
+
+    /// Uses the separating axis theorem. Two boxes are only separated if you can find an axis where their shadows don't overlap.
+    /// The only axes worth checking are the face normals of both boxes, and the cross products of their edges.
+    ///
+    /// The normal points from self towards other, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_obb(
+        &self,
+        other: &ObbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        let mut axes = [[T::ZERO; 3]; 15];
+        axes[0..3].copy_from_slice(&self.axes);
+        axes[3..6].copy_from_slice(&other.axes);
+        for self_axis in 0..3 {
+            for other_axis in 0..3 {
+                axes[6 + self_axis * 3 + other_axis] =
+                    math::cross(self.axes[self_axis], other.axes[other_axis]);
+            }
+        }
+
+        get_collision_normal_and_penetration_on_axes(
+            &axes,
+            math::sub_3d(other.position, self.position),
+            |axis| self.projected_half_size(axis),
+            |axis| other.projected_half_size(axis),
+        )
+    }
+
+    /// The normal points from self towards the capsule, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_capsule(
+        &self,
+        capsule: &Capsule<T>,
+    ) -> ([T; 3], T) {
+        // Find the sphere on the capsule's segment that is closest to the obb. Going back and forth twice gets close enough.
+        let mut sphere = capsule.sphere_closest_to_height(self.closest_point(capsule.position)[1]);
+        sphere = capsule.sphere_closest_to_height(self.closest_point(sphere.position)[1]);
+        self.get_collision_normal_and_penetration_with_sphere(&sphere)
+    }
+
+    /// The normal points from self towards the cylinder, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_cylinder(
+        &self,
+        cylinder: &Cylinder<T>,
+    ) -> ([T; 3], T) {
+        let up = [T::ZERO, T::ONE, T::ZERO];
+
+        // The cylinder's curved side is checked using the direction from its centre line to the closest point on the obb.
+        let closest_point = self.closest_point(cylinder.position);
+        let radial_axis = [
+            closest_point[0] - cylinder.position[0],
+            T::ZERO,
+            closest_point[2] - cylinder.position[2],
+        ];
+
+        let axes = [
+            self.axes[0],
+            self.axes[1],
+            self.axes[2],
+            up,
+            math::cross(up, self.axes[0]),
+            math::cross(up, self.axes[1]),
+            math::cross(up, self.axes[2]),
+            radial_axis,
+        ];
+
+        get_collision_normal_and_penetration_on_axes(
+            &axes,
+            math::sub_3d(cylinder.position, self.position),
+            |axis| self.projected_half_size(axis),
+            |axis| {
+                let horizontal_squared = T::ONE - axis[1] * axis[1];
+                let horizontal = if horizontal_squared > T::ZERO {
+                    horizontal_squared.sqrt()
+                } else {
+                    T::ZERO
+                };
+                cylinder.half_height * axis[1].abs() + cylinder.radius * horizontal
+            },
+        )
+    }
+}
+
+/// Projects both shapes onto every axis, and returns the axis they overlap the least on.
+/// If they don't overlap on an axis, the penetration is negative, and is the largest gap between them on any of the axes.
+/// The axes don't need to be normalised, and ones that are too short to have a direction are skipped.
+///
+/// Offset is the other shape's position minus self's position, and the returned normal points from self towards other.
+fn get_collision_normal_and_penetration_on_axes<T: Float>(
+    axes: &[[T; 3]],
+    offset: [T; 3],
+    self_projected_half_size: impl Fn([T; 3]) -> T,
+    other_projected_half_size: impl Fn([T; 3]) -> T,
+) -> ([T; 3], T) {
+    // Cross products of nearly parallel edges are tiny, and their direction is mostly rounding error.
+    let minimum_squared_length = T::from_f32(0.000001);
+
+    let mut normal = [T::ZERO, T::ONE, T::ZERO];
+    let mut smallest_penetration = T::MAX;
+
+    for axis in axes {
+        let squared_length = math::get_squared_magnitude_3d(*axis);
+        if squared_length < minimum_squared_length {
+            continue;
+        }
+        let axis = math::div_3d_by_1d(*axis, squared_length.sqrt());
+
+        let distance = math::dot(offset, axis);
+        let penetration =
+            self_projected_half_size(axis) + other_projected_half_size(axis) - distance.abs();
+
+        if penetration < smallest_penetration {
+            smallest_penetration = penetration;
+            normal = if distance.is_sign_positive() {
+                axis
+            } else {
+                math::neg_3d(axis)
+            };
+        }
+    }
+
+    (normal, smallest_penetration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::Bencher;
+
+    #[bench]
+    fn bench_obb_is_intersected_by_obb(b: &mut Bencher) {
+        b.iter(|| {
+            let obb1 = test::black_box(ObbCentredOrigin::from_quaternion(
+                [3.0, 2.0, 2.2389],
+                [1.5, 0.5, 2.0],
+                [0.9238795, 0.0, 0.3826834, 0.0],
+            ));
+
+            let obb2 = test::black_box(ObbCentredOrigin::from_quaternion(
+                [3.0, 4.0, 3.2389],
+                [0.75, 2.0, 1.0],
+                [0.8660254, 0.5, 0.0, 0.0],
+            ));
+
+            obb1.is_intersected_by_obb(obb2)
+        })
+    }
+
+    #[bench]
+    fn bench_obb_is_intersected_by_sphere(b: &mut Bencher) {
+        b.iter(|| {
+            let obb = test::black_box(ObbCentredOrigin::from_quaternion(
+                [3.0, 2.0, 2.2389],
+                [1.5, 0.5, 2.0],
+                [0.9238795, 0.0, 0.3826834, 0.0],
+            ));
+
+            let sphere = test::black_box(Sphere {
+                position: [3.0, 4.0, 3.2389],
+                radius: 0.75,
+            });
+
+            obb.is_intersected_by_sphere(sphere)
+        })
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::math::{self, Float};
+
+use super::{
+    bodies::Body,
+    constraints::Constraint,
+    solver::{BodyHandle, CpuSolver},
+};
+
+/// How the particles of a soft body are linked together.
+/// Each kind of link can be turned off with None.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SoftBodySettings<T> {
+    /// Links between direct neighbours, along the lattice's axes, or along the mesh's edges. These hold the shape together.
+    pub structural: Option<SoftBodyLink<T>>,
+    /// Links between diagonal neighbours in a lattice. These stop it squashing sideways. Meshes have none, as their triangles already can't shear.
+    pub shear: Option<SoftBodyLink<T>>,
+    /// Links that skip over a particle in a lattice, or between the far corners of 2 triangles that share an edge in a mesh. These stop it folding.
+    pub bend: Option<SoftBodyLink<T>>,
+}
+
+impl<T> SoftBodySettings<T>
+where
+    T: Float,
+{
+    /// Rigid structure and shear, with springy bending. Good for flags and curtains.
+    pub fn cloth(bend_stiffness: T, bend_damping: T) -> Self {
+        Self {
+            structural: Some(SoftBodyLink::Rigid),
+            shear: Some(SoftBodyLink::Rigid),
+            bend: Some(SoftBodyLink::Spring {
+                stiffness: bend_stiffness,
+                damping: bend_damping,
+            }),
+        }
+    }
+
+    /// Springs everywhere, so it wobbles. Good for jelly.
+    pub fn jelly(stiffness: T, damping: T) -> Self {
+        let spring = Some(SoftBodyLink::Spring { stiffness, damping });
+        Self {
+            structural: spring,
+            shear: spring,
+            bend: spring,
+        }
+    }
+}
+
+/// What constraint a link between 2 particles becomes. The rest length is always the distance between them when the soft body is made.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SoftBodyLink<T> {
+    /// A [Constraint::Distance].
+    Rigid,
+    /// A [Constraint::Spring].
+    Spring { stiffness: T, damping: T },
+}
+
+impl<T> SoftBodyLink<T>
+where
+    T: Float,
+{
+    fn constraint(self, lhs_body_index: usize, rhs_body_index: usize, length: T) -> Constraint<T> {
+        match self {
+            SoftBodyLink::Rigid => Constraint::Distance {
+                lhs_body_index,
+                rhs_body_index,
+                distance: length,
+            },
+            SoftBodyLink::Spring { stiffness, damping } => Constraint::Spring {
+                lhs_body_index,
+                rhs_body_index,
+                rest_length: length,
+                stiffness,
+                damping,
+            },
+        }
+    }
+}
+
+/// A lattice of particles in a [CpuSolver], linked together by constraints, with a mesh on top that follows them.
+///
+/// The particles are ordinary bodies, made by whatever function you give it, so they collide with everything else.
+/// Keep their size under half the distance between neighbouring particles, otherwise neighbours will push each other apart, fighting the links.
+///
+/// Call [SoftBody::update] after every solver update to get the new vertex positions and normals.
+/// Normals follow the winding of the triangles, pointing along (b - a) x (c - a).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SoftBody<T> {
+    /// The handle of each particle.
+    pub particles: Vec<BodyHandle>,
+    /// The index of the particle each vertex follows.
+    /// Vertices at the same position, such as along a uv seam, share a particle, so the mesh can't tear there.
+    vertex_particles: Vec<usize>,
+    /// Every 3 indices into the vertices are a triangle.
+    indices: Vec<u32>,
+    /// The uv coordinates of each vertex. Empty if there are none, as is the case for [SoftBody::lattice].
+    pub uvs: Vec<[f32; 2]>,
+    positions: Vec<[T; 3]>,
+    normals: Vec<[T; 3]>,
+}
+
+impl<T> SoftBody<T>
+where
+    T: Float,
+{
+    /// Makes a sheet of size[0] by size[1] particles, with particle [x, y] at origin + steps[0] * x + steps[1] * y.
+    /// The uvs go from 0 to 1 across the sheet.
+    pub fn cloth<B: Body<T>>(
+        solver: &mut CpuSolver<T, B>,
+        settings: SoftBodySettings<T>,
+        origin: [T; 3],
+        steps: [[T; 3]; 2],
+        size: [usize; 2],
+        new_particle: impl FnMut([T; 3]) -> B,
+    ) -> Self {
+        let lattice_size = [size[0], size[1], 1];
+        let particle_positions =
+            lattice_positions(origin, [steps[0], steps[1], [T::ZERO; 3]], lattice_size);
+        let particles = insert_particles(solver, &particle_positions, new_particle);
+        add_lattice_constraints(
+            solver,
+            settings,
+            &particles,
+            &particle_positions,
+            lattice_size,
+        );
+
+        let mut indices =
+            Vec::with_capacity(size[0].saturating_sub(1) * size[1].saturating_sub(1) * 6);
+        push_grid_triangles(&mut indices, [size[0], size[1]], |x, y| {
+            (x + y * size[0]) as u32
+        });
+
+        let uvs = (0..size[1])
+            .flat_map(|y| {
+                (0..size[0]).map(move |x| {
+                    [
+                        x as f32 / (size[0].max(2) - 1) as f32,
+                        y as f32 / (size[1].max(2) - 1) as f32,
+                    ]
+                })
+            })
+            .collect();
+
+        let mut soft_body = SoftBody {
+            vertex_particles: (0..particles.len()).collect(),
+            particles,
+            indices,
+            uvs,
+            positions: particle_positions,
+            normals: vec![],
+        };
+        soft_body.recalculate_normals();
+        soft_body
+    }
+
+    /// Makes a solid block of size[0] by size[1] by size[2] particles, with particle [x, y, z] at origin + steps[0] * x + steps[1] * y + steps[2] * z.
+    /// Only the outside of the block gets vertices, with the triangles wound so that the normals point outwards.
+    pub fn lattice<B: Body<T>>(
+        solver: &mut CpuSolver<T, B>,
+        settings: SoftBodySettings<T>,
+        origin: [T; 3],
+        steps: [[T; 3]; 3],
+        size: [usize; 3],
+        new_particle: impl FnMut([T; 3]) -> B,
+    ) -> Self {
+        let particle_positions = lattice_positions(origin, steps, size);
+        let particles = insert_particles(solver, &particle_positions, new_particle);
+        add_lattice_constraints(solver, settings, &particles, &particle_positions, size);
+
+        let particle_index =
+            |position: [usize; 3]| position[0] + (position[1] + position[2] * size[1]) * size[0];
+
+        // Only particles on the outside get a vertex.
+        let mut vertex_of_particle = vec![None; particles.len()];
+        let mut vertex_particles = vec![];
+        let mut indices = vec![];
+        for axis in 0..3 {
+            let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+            for side in [0, size[axis].saturating_sub(1)] {
+                let face_start = indices.len();
+                push_grid_triangles(&mut indices, [size[u_axis], size[v_axis]], |u, v| {
+                    let mut position = [0; 3];
+                    position[axis] = side;
+                    position[u_axis] = u;
+                    position[v_axis] = v;
+                    let particle_index = particle_index(position);
+                    *vertex_of_particle[particle_index].get_or_insert_with(|| {
+                        vertex_particles.push(particle_index);
+                        (vertex_particles.len() - 1) as u32
+                    })
+                });
+
+                // Which way round the triangles need to be depends on the steps, so it is easiest to check.
+                let outwards = if side == 0 {
+                    math::neg_3d(steps[axis])
+                } else {
+                    steps[axis]
+                };
+                if let Some(triangle) = indices.get(face_start..face_start + 3) {
+                    let [a, b, c] = [0, 1, 2].map(|corner| {
+                        particle_positions[vertex_particles[triangle[corner] as usize]]
+                    });
+                    let normal = math::cross(math::sub_3d(b, a), math::sub_3d(c, a));
+                    if math::dot(normal, outwards) < T::ZERO {
+                        for triangle in indices[face_start..].chunks_exact_mut(3) {
+                            triangle.swap(1, 2);
+                        }
+                    }
+                }
+
+                // A lattice 1 particle thick only has the one side.
+                if size[axis] == 1 {
+                    break;
+                }
+            }
+        }
+
+        let mut soft_body = SoftBody {
+            positions: vertex_particles
+                .iter()
+                .map(|particle_index| particle_positions[*particle_index])
+                .collect(),
+            vertex_particles,
+            particles,
+            indices,
+            uvs: vec![],
+            normals: vec![],
+        };
+        soft_body.recalculate_normals();
+        soft_body
+    }
+
+    /// Makes a particle for every distinct vertex position in the mesh, linking them along the triangles' edges.
+    /// Every 3 indices are a triangle. The uvs can be empty.
+    ///
+    /// A mesh is only a surface, so a closed one will collapse without bend links to hold it up, and even then it isn't very stiff.
+    /// Use [SoftBody::lattice] for anything that needs to be properly solid.
+    pub fn from_mesh<B: Body<T>>(
+        solver: &mut CpuSolver<T, B>,
+        settings: SoftBodySettings<T>,
+        vertex_positions: &[[T; 3]],
+        indices: &[u32],
+        uvs: &[[f32; 2]],
+        new_particle: impl FnMut([T; 3]) -> B,
+    ) -> Self {
+        let mut particle_of_position = HashMap::new();
+        let mut particle_positions = vec![];
+        let vertex_particles: Vec<usize> = vertex_positions
+            .iter()
+            .map(|position| {
+                *particle_of_position
+                    .entry(position.map(Float::to_bits_u64))
+                    .or_insert_with(|| {
+                        particle_positions.push(*position);
+                        particle_positions.len() - 1
+                    })
+            })
+            .collect();
+        let particles = insert_particles(solver, &particle_positions, new_particle);
+
+        // The far corner of every triangle that has used each edge so far. Edges are stored smallest particle first, so both directions match.
+        let mut edges: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for triangle in indices.chunks_exact(3) {
+            let corners = [0, 1, 2].map(|corner| vertex_particles[triangle[corner] as usize]);
+            for corner in 0..3 {
+                let (lhs, rhs) = (corners[corner], corners[(corner + 1) % 3]);
+                if lhs == rhs {
+                    continue;
+                }
+                edges
+                    .entry((lhs.min(rhs), lhs.max(rhs)))
+                    .or_default()
+                    .push(corners[(corner + 2) % 3]);
+            }
+        }
+
+        let mut links = vec![];
+        // Sorted, so that the constraints are always made in the same order.
+        let mut edges: Vec<_> = edges.into_iter().collect();
+        edges.sort_unstable_by_key(|(edge, _)| *edge);
+        for ((lhs, rhs), far_corners) in edges {
+            if let Some(structural) = settings.structural {
+                links.push((structural, lhs, rhs));
+            }
+            if let Some(bend) = settings.bend {
+                for (index, first) in far_corners.iter().enumerate() {
+                    for second in &far_corners[index + 1..] {
+                        if first != second {
+                            links.push((bend, *first, *second));
+                        }
+                    }
+                }
+            }
+        }
+        add_links(solver, &particles, &particle_positions, links);
+
+        let mut soft_body = SoftBody {
+            particles,
+            vertex_particles,
+            indices: indices.to_vec(),
+            uvs: uvs.to_vec(),
+            positions: vertex_positions.to_vec(),
+            normals: vec![],
+        };
+        soft_body.recalculate_normals();
+        soft_body
+    }
+
+    /// Moves the vertices to where their particles now are, and recalculates the normals.
+    /// Particles that have been removed from the solver leave their vertices where they were.
+    pub fn update<B: Body<T>>(&mut self, solver: &CpuSolver<T, B>) {
+        for (position, particle_index) in self.positions.iter_mut().zip(&self.vertex_particles) {
+            if let Some(particle) = solver.get(self.particles[*particle_index]) {
+                *position = particle.position_unchecked();
+            }
+        }
+        self.recalculate_normals();
+    }
+
+    /// Removes every particle from the solver. Their constraints go with them.
+    pub fn remove<B: Body<T>>(self, solver: &mut CpuSolver<T, B>) {
+        for particle in self.particles {
+            solver.remove(particle);
+        }
+    }
+
+    /// The position of each vertex, as of the last [SoftBody::update].
+    #[inline]
+    pub fn positions(&self) -> &[[T; 3]] {
+        &self.positions
+    }
+
+    /// The normal of each vertex, as of the last [SoftBody::update].
+    #[inline]
+    pub fn normals(&self) -> &[[T; 3]] {
+        &self.normals
+    }
+
+    /// Every 3 indices into the vertices are a triangle.
+    #[inline]
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /// Each vertex's normal is the average of the normals of the triangles around its particle, weighted by their area.
+    /// Going by particle rather than vertex means that there is no visible crease along uv seams.
+    fn recalculate_normals(&mut self) {
+        let mut particle_normals = vec![[T::ZERO; 3]; self.particles.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|corner| self.positions[triangle[corner] as usize]);
+            // The cross product's length is twice the triangle's area, which is what does the weighting.
+            let normal = math::cross(math::sub_3d(b, a), math::sub_3d(c, a));
+            for vertex_index in triangle {
+                let particle_normal =
+                    &mut particle_normals[self.vertex_particles[*vertex_index as usize]];
+                *particle_normal = math::add_3d(*particle_normal, normal);
+            }
+        }
+
+        self.normals.clear();
+        self.normals.extend(
+            self.vertex_particles
+                .iter()
+                .map(|particle_index| math::normalise_3d(particle_normals[*particle_index])),
+        );
+    }
+}
+
+/// Gets the position of every particle in a lattice, x first, then y, then z.
+fn lattice_positions<T: Float>(
+    origin: [T; 3],
+    steps: [[T; 3]; 3],
+    size: [usize; 3],
+) -> Vec<[T; 3]> {
+    let mut positions = Vec::with_capacity(size[0] * size[1] * size[2]);
+    for z in 0..size[2] {
+        for y in 0..size[1] {
+            for x in 0..size[0] {
+                let mut position = origin;
+                for (axis, steps_taken) in [x, y, z].into_iter().enumerate() {
+                    position = math::add_3d(
+                        position,
+                        math::mul_3d_by_1d(steps[axis], T::from_f64(steps_taken as f64)),
+                    );
+                }
+                positions.push(position);
+            }
+        }
+    }
+    positions
+}
+
+fn insert_particles<T: Float, B: Body<T>>(
+    solver: &mut CpuSolver<T, B>,
+    positions: &[[T; 3]],
+    mut new_particle: impl FnMut([T; 3]) -> B,
+) -> Vec<BodyHandle> {
+    positions
+        .iter()
+        .map(|position| solver.insert(new_particle(*position)))
+        .collect()
+}
+
+/// Links every particle to the ones around it. Which kind of link depends on the offset between them:
+/// 1 step along 1 axis is structural, 1 step along 2 or 3 axes is shear, and 2 steps along 1 axis is bend.
+fn add_lattice_constraints<T: Float, B: Body<T>>(
+    solver: &mut CpuSolver<T, B>,
+    settings: SoftBodySettings<T>,
+    particles: &[BodyHandle],
+    particle_positions: &[[T; 3]],
+    size: [usize; 3],
+) {
+    let mut offsets = vec![];
+    for x in -1..=1isize {
+        for y in -1..=1isize {
+            for z in -1..=1isize {
+                let offset = [x, y, z];
+                // Only half of the offsets are needed, as the other half would link the same particles the other way round.
+                let Some(first_non_zero) = offset.into_iter().find(|axis| *axis != 0) else {
+                    continue;
+                };
+                if first_non_zero < 0 {
+                    continue;
+                }
+                let link = match offset.iter().filter(|axis| **axis != 0).count() {
+                    1 => settings.structural,
+                    _ => settings.shear,
+                };
+                if let Some(link) = link {
+                    offsets.push((link, offset));
+                }
+            }
+        }
+    }
+    if let Some(bend) = settings.bend {
+        offsets.extend([[2, 0, 0], [0, 2, 0], [0, 0, 2]].map(|offset| (bend, offset)));
+    }
+
+    let particle_index =
+        |position: [usize; 3]| position[0] + (position[1] + position[2] * size[1]) * size[0];
+
+    let mut links = vec![];
+    for z in 0..size[2] {
+        for y in 0..size[1] {
+            for x in 0..size[0] {
+                for (link, offset) in &offsets {
+                    let mut other = [0; 3];
+                    let mut in_lattice = true;
+                    for axis in 0..3 {
+                        match [x, y, z][axis].checked_add_signed(offset[axis]) {
+                            Some(position) if position < size[axis] => other[axis] = position,
+                            _ => in_lattice = false,
+                        }
+                    }
+                    if in_lattice {
+                        links.push((*link, particle_index([x, y, z]), particle_index(other)));
+                    }
+                }
+            }
+        }
+    }
+    add_links(solver, particles, particle_positions, links);
+}
+
+/// Turns links between particle indices into constraints between body indices, using the particles' current distance apart as the rest length.
+fn add_links<T: Float, B: Body<T>>(
+    solver: &mut CpuSolver<T, B>,
+    particles: &[BodyHandle],
+    particle_positions: &[[T; 3]],
+    links: Vec<(SoftBodyLink<T>, usize, usize)>,
+) {
+    for (link, lhs, rhs) in links {
+        let (Some(lhs_body_index), Some(rhs_body_index)) = (
+            solver.body_index(particles[lhs]),
+            solver.body_index(particles[rhs]),
+        ) else {
+            continue;
+        };
+        let length = math::get_magnitude_3d(math::sub_3d(
+            particle_positions[rhs],
+            particle_positions[lhs],
+        ));
+        solver
+            .constraints
+            .push(link.constraint(lhs_body_index, rhs_body_index, length));
+    }
+}
+
+/// Adds 2 triangles for every square in a grid of size[0] by size[1] vertices.
+fn push_grid_triangles(
+    indices: &mut Vec<u32>,
+    size: [usize; 2],
+    mut vertex_index: impl FnMut(usize, usize) -> u32,
+) {
+    for v in 0..size[1].saturating_sub(1) {
+        for u in 0..size[0].saturating_sub(1) {
+            let corners = [
+                vertex_index(u, v),
+                vertex_index(u + 1, v),
+                vertex_index(u, v + 1),
+                vertex_index(u + 1, v + 1),
+            ];
+            indices.extend([corners[0], corners[1], corners[3]]);
+            indices.extend([corners[0], corners[3], corners[2]]);
+        }
+    }
+}
@@ -1,16 +1,30 @@
-use std::{
-    num::NonZeroU8,
-    sync::mpsc::{channel, Sender},
-};
+use std::{hash::Hasher, num::NonZeroU8};
 
 use crate::{math, physics::PhysicsSimulation};
 
-use super::bodies::Body;
+use super::{
+    aabb::AabbCentredOrigin,
+    bodies::{self, Body},
+    broadphase::{Broadphase, CommonBroadphase},
+    bvh::Bvh,
+    constraints::Constraint,
+    spatial_hash::SpatialHash,
+    sphere::Sphere,
+    sweep_and_prune::SweepAndPrune,
+    uniform_grid::{is_outside_of_grid_bounds, UniformGrid},
+    verlet::cuboid_arrays::CuboidArrays,
+};
 
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 extern crate test;
 
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize, B: Serialize + 'static",
+    deserialize = "T: Deserialize<'de>, B: Deserialize<'de> + 'static"
+))]
 pub struct Config<T, B>
 where
     T: math::Float,
@@ -22,7 +36,20 @@ where
     pub grid_origin: [T; 3],
     pub cell_size: [usize; 3],
     pub outside_of_grid_bounds_behaviour: OutsideOfGridBoundsBehaviour<T, B>,
+    /// How the solver finds which bodies are near each other. The grid settings are only used by [BroadphaseMethod::UniformGrid].
+    pub broadphase: BroadphaseMethod<T>,
+    pub steps: Steps,
+    /// Lets bodies that have stopped moving be skipped, until something wakes them. None means bodies never sleep.
+    pub sleeping: Option<Sleeping<T>>,
+    /// Whether the solver should guarantee the exact same results from the exact same inputs. See [CpuSolver::deterministic].
+    pub deterministic: bool,
     pub bodies: Vec<B>,
+    /// See [CpuSolver::constraints].
+    pub constraints: Vec<Constraint<T>>,
+    /// The collision layers of each body, by body index. Any body without any gets [CollisionLayers::ALL].
+    pub collision_layers: Vec<CollisionLayers>,
+    /// See [CpuSolver::cuboid_arrays].
+    pub cuboid_arrays: CuboidArrays<T>,
 }
 
 impl<T: math::Float, B: Body<T>> Config<T, B> {
@@ -66,18 +93,105 @@ impl<T: math::Float, B: Body<T>> Default for Config<T, B> {
             grid_origin: [T::ZERO; 3],
             cell_size: [5; 3],
             outside_of_grid_bounds_behaviour: OutsideOfGridBoundsBehaviour::ContinueUpdating,
+            broadphase: BroadphaseMethod::UniformGrid,
+            steps: Steps::default(),
+            sleeping: None,
+            deterministic: false,
             bodies: vec![],
+            constraints: vec![],
+            collision_layers: vec![],
+            cuboid_arrays: CuboidArrays::default(),
+        }
+    }
+}
+
+/// How the solver finds which bodies are near each other, before checking whether they actually collide.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BroadphaseMethod<T> {
+    /// A fixed size grid, set up with grid_size, grid_origin and cell_size.
+    /// Fast, but bodies outside of it don't collide, and it uses memory for every cell, even empty ones.
+    UniformGrid,
+    /// A [SpatialHash], which has no bounds, so bodies can go anywhere, and memory only grows with how many cells have bodies in them.
+    /// outside_of_grid_bounds_behaviour does nothing with this, as nothing is ever outside of it.
+    SpatialHash { cell_size: [T; 3] },
+    /// A [SweepAndPrune], which sorts bodies along one axis. It has no cells, so huge bodies, such as a big immovable floor, cost no more than small ones.
+    /// Slower than the cell based ones when every body is small and about the same size, as then they are all close together along any axis.
+    /// outside_of_grid_bounds_behaviour does nothing with this, as nothing is ever outside of it.
+    SweepAndPrune,
+    /// A [Bvh], which is a tree of aabbs. Like sweep and prune, huge bodies cost no more than small ones, and bodies only move around the tree once they leave their leaf, which is bigger than them by the margin.
+    /// Also slower than the cell based ones when every body is small and about the same size, but best for mixed sizes, and scenes that are mostly asleep or immovable.
+    /// outside_of_grid_bounds_behaviour does nothing with this, as nothing is ever outside of it.
+    Bvh { margin: T },
+}
+
+/// When bodies are put to sleep. Sleeping bodies aren't updated, and don't look for collisions themselves, so piles of resting bodies cost very little.
+///
+/// Bodies that touch each other form an island, and an island only sleeps once every body in it has rested for long enough.
+/// Once asleep, the whole island wakes up as soon as an awake body touches any of it, or when [CpuSolver::wake] or [CpuSolver::get_mut] is used on any of it.
+/// Bodies that don't collide with others, such as immovable ones, aren't part of any island, so they never keep an island awake or wake one up.
+///
+/// If you change a sleeping body through bodies directly, wake it with [CpuSolver::wake], otherwise the solver won't notice.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sleeping<T> {
+    /// How far a body can move in a substep while still counting as resting. See [Body::displacement].
+    pub displacement_threshold: T,
+    /// How long, in the same units as delta time, every body in an island must rest before the island goes to sleep.
+    pub time_until_sleep: T,
+}
+
+impl<T: math::Float> Default for Sleeping<T> {
+    fn default() -> Self {
+        Self {
+            displacement_threshold: T::from_f64(0.01),
+            time_until_sleep: T::from_f64(0.5),
+        }
+    }
+}
+
+/// How long a body has been resting, and which island it is asleep in, if any.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SleepState<T> {
+    resting_time: T,
+    island: Option<u32>,
+}
+
+impl<T: math::Float> Default for SleepState<T> {
+    fn default() -> Self {
+        Self {
+            resting_time: T::ZERO,
+            island: None,
         }
     }
 }
 
-// TODO: add Steps struct to solver.
+/// How many times the solver does each part of an update.
+/// More steps are more stable, especially with stacked bodies, but they cost more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Steps {
+    /// How many substeps each update is split into. Each substep updates the bodies with a fraction of the delta time.
     pub particle_updates: NonZeroU8,
+    /// How many times collisions are detected and responded to, each substep.
+    /// The bodies aren't placed back into the grid between these, as they only move slightly.
     pub penetration_removals: NonZeroU8,
 }
 
+impl Default for Steps {
+    fn default() -> Self {
+        Self {
+            particle_updates: NonZeroU8::MIN,
+            penetration_removals: NonZeroU8::MIN,
+        }
+    }
+}
+
 /// A generic solver capable of handling most basic physics simulations.
+///
+/// The whole solver can be saved and loaded with serde. Any function pointers in it have to be registered first, see [super::function_registry].
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize, B: Serialize + 'static",
+    deserialize = "T: Deserialize<'de>, B: Deserialize<'de> + 'static"
+))]
 pub struct CpuSolver<T, B>
 where
     T: math::Float,
@@ -87,49 +201,107 @@ where
     pub dampening: [T; 3], // Where 1.0 is no dampening. Perhaps displacement_kept is a better name?
 
     pub bodies: Vec<B>,
-
-    pub grid_size: [usize; 3], // This is in cell size units. This should probably be clarified.
-    pub cell_size: [usize; 3], // TODO: asap work out how the usize vs isize nonsense will work, as we want this to work for negatives. Perhaps we can plus some sort of offset for the particle?
-    pub grid_origin: [T; 3], // Remember that the origin is the bottom left corner of the grid, I think.
-    pub grid: Vec<Vec<usize>>,
+    /// Plain cuboids stored as a structure of arrays, and integrated with SIMD, alongside bodies. Cheaper than bodies for particle heavy scenes like rain.
+    /// They are only swept against bodies that stop sweeps, see [CuboidArrays]. They have the default collision layers, and queries don't find them.
+    pub cuboid_arrays: CuboidArrays<T>,
+    /// Constraints between bodies, by body index. They are relaxed after each penetration removal, so more penetration removals make them stiffer.
+    /// Constraints follow their bodies when the solver moves them, and are removed when one of their bodies is deleted.
+    pub constraints: Vec<Constraint<T>>,
+
+    /// Finds which bodies are near each other. Starts as whichever [Config::broadphase] chose, but can be swapped for any other, including your own, between updates.
+    pub broadphase: CommonBroadphase<T>,
     pub outside_of_grid_bounds_behaviour: OutsideOfGridBoundsBehaviour<T, B>,
+    pub steps: Steps,
+    /// See [Sleeping]. Setting this to None wakes every body at the start of the next update.
+    pub sleeping: Option<Sleeping<T>>,
+    /// The sleep state of each body, by body index. Bodies without one are awake. Kept in line with bodies, like collision_layers.
+    sleep_states: Vec<SleepState<T>>,
+    /// Each island that falls asleep gets the next island id.
+    next_island: u32,
+    /// Sleeping bodies are left in the broadphase between updates, but if body indices change, the whole broadphase needs redoing.
+    #[serde(skip)]
+    broadphase_is_stale: bool,
+    /// Runs everything on the current thread, so that the same inputs always give bit for bit the same bodies, no matter how many threads rayon has.
+    /// Use this for replays, lockstep networking and regression tests, along with [CpuSolver::state_hash] to check that nothing has diverged.
+    ///
+    /// Collisions are always responded to in the same order, so the multithreaded path should already be reproducible, but this mode doesn't rely on that.
+    pub deterministic: bool,
 
+    /// The pairs of bodies found colliding by the last detection pass, as (lhs_body_index, rhs_body_index). Each pair is only in here once.
     pub collisions: Vec<(usize, usize)>,
+    /// Every contact that started, persisted or ended during the last update.
+    pub contact_events: Vec<ContactEvent<T>>,
+    /// The collision layers of each body, by body index. Kept in line with bodies, whenever the solver moves or deletes them.
+    collision_layers: Vec<CollisionLayers>,
+    /// The contacts from the last update, with lhs_body_index always the smaller index. Kept so the next update can tell which contacts are new.
+    contacts: Vec<Contact<T>>,
+    /// The contacts found so far this update.
+    #[serde(skip)]
+    gathered_contacts: Vec<Contact<T>>,
+    /// Kept between updates, so that sweeping doesn't allocate every substep.
+    #[serde(skip)]
+    sweep_scratch: SweepScratch,
+    /// The hits from sweeping cuboid_arrays, as (cuboid_index, hit). Kept between updates for the same reason.
+    #[serde(skip)]
+    cuboid_arrays_hits: Vec<(usize, RaycastHit<T>)>,
+    /// Every body index that was moved or deleted during the last update. If you store body indices, you will want to go through these.
+    pub body_index_changes: Vec<BodyIndexChange>,
+    body_handles: BodyHandles,
 }
 
 impl<T: math::Float, B: Body<T>> PhysicsSimulation<T> for CpuSolver<T, B> {
-    /// Multithreaded! It suffers on small amounts of particles currently....
+    /// Multithreaded! Each thread detects collisions into its own buffer, so there is nothing to wait on, even with small amounts of particles.
     /// Also handles collisions slightly differently to usual.
     fn update(&mut self, delta_time: T) {
-        self.update_bodies(delta_time);
+        self.body_index_changes.clear();
+        self.contact_events.clear();
 
-        self.place_bodies_into_grid();
+        if self.sleeping.is_none() && !self.sleep_states.is_empty() {
+            self.sleep_states.clear();
+        }
 
-        let (collision_sender, collision_receiver) = channel();
-        self.detect_collisions_extra_experimental(&collision_sender);
-        drop(collision_sender);
+        let substep_delta_time = delta_time / T::from_f64(self.steps.particle_updates.get() as f64);
 
-        self.collisions.clear();
-        self.collisions.extend(collision_receiver.iter());
+        for _ in 0..self.steps.particle_updates.get() {
+            self.wake_islands_held_by_constraints();
+            self.apply_constraint_forces(substep_delta_time);
 
-        // By these 2 lines remove any duplicate collision pairs, that previously ruined everything.
-        self.collisions.par_sort_unstable();
-        self.collisions.dedup();
+            if self.deterministic {
+                self.serial_update_bodies(substep_delta_time);
+            } else {
+                self.update_bodies(substep_delta_time);
+            }
+            self.cuboid_arrays
+                .update(self.gravity, self.dampening, substep_delta_time);
+
+            self.handle_bodies_outside_of_grid_bounds();
+            let kept_sleeping_bodies = self.clear_broadphase();
+            self.place_bodies_into_broadphase(kept_sleeping_bodies);
+            // Swept bodies may be moved back a long way, but they are responded to straight away, so their place in the broadphase doesn't matter until the next substep.
+            self.sweep_continuous_bodies(substep_delta_time);
+            self.sweep_cuboid_arrays(substep_delta_time);
+
+            for penetration_removal in 0..self.steps.penetration_removals.get() {
+                if self.deterministic {
+                    self.serial_detect_collisions();
+                } else {
+                    self.detect_collisions();
+                }
 
-        self.respond_to_collisions(delta_time);
+                self.wake_islands_touched_by_awake_bodies();
 
-        for cell in &mut self.grid {
-            if cell.capacity() == 0 {
-                continue;
-            }
-            // This is meant to keep memory usage low, with only a minor performance cost, but I'm not convinced.
-            // Even though we check for 0, this still seems dodgy. Perhaps this should be a choice for the user.
-            if cell.len() <= cell.capacity() / 2 {
-                //println!("len: {}, capacity: {}", cell.len(), cell.capacity());
-                cell.shrink_to_fit();
+                // Later passes only find what the first pass didn't fully push apart.
+                if penetration_removal == 0 {
+                    self.gather_contacts();
+                }
+
+                self.respond_to_collisions(substep_delta_time);
+                self.relax_constraints();
             }
-            cell.clear();
         }
+
+        self.update_contact_events();
+        self.update_sleeping(delta_time);
     }
 }
 
@@ -139,381 +311,1819 @@ where
     B: Body<T>,
 {
     pub fn new(config: Config<T, B>) -> CpuSolver<T, B> {
+        let mut body_handles = BodyHandles::default();
+        for (body_index, body) in config.bodies.iter().enumerate() {
+            if !body.is_none() {
+                body_handles.allocate(body_index);
+            }
+        }
+
+        let broadphase = match config.broadphase {
+            BroadphaseMethod::UniformGrid => CommonBroadphase::UniformGrid(UniformGrid::new(
+                config.grid_size,
+                config.cell_size,
+                config.grid_origin,
+            )),
+            BroadphaseMethod::SpatialHash { cell_size } => {
+                CommonBroadphase::SpatialHash(SpatialHash::new(cell_size))
+            }
+            BroadphaseMethod::SweepAndPrune => {
+                CommonBroadphase::SweepAndPrune(SweepAndPrune::new())
+            }
+            BroadphaseMethod::Bvh { margin } => CommonBroadphase::Bvh(Bvh::new(margin)),
+        };
+
         CpuSolver {
             gravity: config.gravity,
             dampening: config.dampening,
 
             bodies: config.bodies,
+            cuboid_arrays: config.cuboid_arrays,
+            constraints: config.constraints,
 
-            grid_size: config.grid_size,
-            cell_size: config.cell_size,
-            grid_origin: config.grid_origin,
-            grid: vec![vec![]; config.grid_size[0] * config.grid_size[1] * config.grid_size[2]],
+            broadphase,
             outside_of_grid_bounds_behaviour: config.outside_of_grid_bounds_behaviour,
+            steps: config.steps,
+            sleeping: config.sleeping,
+            sleep_states: vec![],
+            next_island: 0,
+            broadphase_is_stale: false,
+            deterministic: config.deterministic,
 
             collisions: vec![],
+            contact_events: vec![],
+            contacts: vec![],
+            gathered_contacts: vec![],
+            sweep_scratch: SweepScratch::default(),
+            cuboid_arrays_hits: vec![],
+            collision_layers: config.collision_layers,
+            body_index_changes: vec![],
+            body_handles,
         }
     }
 
+    /// Applies the [OutsideOfGridBoundsBehaviour] to every body that is outside of the broadphase's bounds.
+    /// Any body indices that get moved or deleted are recorded in body_index_changes.
     #[inline]
-    fn place_bodies_into_grid(&mut self) {
-        let real_grid_width = self.grid_size[0] * self.cell_size[0];
-        let real_grid_height = self.grid_size[1] * self.cell_size[1];
-        let real_grid_length = self.grid_size[2] * self.cell_size[2];
-
-        for (body_index, body) in self.bodies.iter_mut().enumerate() {
-            if body.is_none() {
-                continue;
-            }
-
-            let body_position = body.position_unchecked();
-
-            let corrected_position = [
-                body_position[0] - self.grid_origin[0],
-                body_position[1] - self.grid_origin[1],
-                body_position[2] - self.grid_origin[2],
-            ];
-
-            let corrected_position_as_isize = [
-                corrected_position[0].to_isize(),
-                corrected_position[1].to_isize(),
-                corrected_position[2].to_isize(),
-            ];
-
-            let corrected_position_as_usize = [
-                corrected_position[0].to_usize(),
-                corrected_position[1].to_usize(),
-                corrected_position[2].to_usize(),
-            ];
-
-            let outside_side = [
-                corrected_position_as_isize[0] as usize > real_grid_width - 1,
-                corrected_position_as_isize[0] < 0,
-                corrected_position_as_isize[1] as usize > real_grid_height - 1,
-                corrected_position_as_isize[1] < 0,
-                corrected_position_as_isize[2] as usize > real_grid_length - 1,
-                corrected_position_as_isize[2] < 0,
-            ];
-
-            if outside_side[0]
-                || outside_side[1]
-                || outside_side[2]
-                || outside_side[3]
-                || outside_side[4]
-                || outside_side[5]
-            {
-                //println!("corrected position: {:?}", corrected_position); // Very useful debug!
-                // Perhaps have this per body? Nah too slow.
-                match self.outside_of_grid_bounds_behaviour {
-                    OutsideOfGridBoundsBehaviour::SwapDeleteParticle => {
-                        todo!();
-                        //self.particles.swap_remove(particle_index);
-                        //continue;
-                    }
-                    OutsideOfGridBoundsBehaviour::DeleteParticle => {
-                        todo!();
-                        //self.particles.remove(particle_index);
-                        //continue;
-                    }
-                    OutsideOfGridBoundsBehaviour::PutParticleInBounds => {
-                        todo!();
+    fn handle_bodies_outside_of_grid_bounds(&mut self) {
+        let Some((grid_origin, grid_max)) = self.broadphase.bounds() else {
+            // There are no bounds to be outside of.
+            return;
+        };
+
+        let real_grid_size = [
+            (grid_max[0] - grid_origin[0]).to_usize(),
+            (grid_max[1] - grid_origin[1]).to_usize(),
+            (grid_max[2] - grid_origin[2]).to_usize(),
+        ];
+
+        match self.outside_of_grid_bounds_behaviour {
+            OutsideOfGridBoundsBehaviour::SwapDeleteParticle => {
+                let mut body_index = 0;
+                while body_index < self.bodies.len() {
+                    let body = &self.bodies[body_index];
+                    if body.is_none()
+                        || !is_outside_of_grid_bounds(
+                            math::sub_3d(body.position_unchecked(), grid_origin),
+                            real_grid_size,
+                        )
+                    {
+                        body_index += 1;
+                        continue;
                     }
-                    OutsideOfGridBoundsBehaviour::TeleportParticleToPosition(_position) => {
-                        todo!()
-                        //particle.previous_position = position;
-                        //particle.position = position;
+
+                    let last_body_index = self.bodies.len() - 1;
+                    self.bodies.swap_remove(body_index);
+                    self.record_body_index_change(BodyIndexChange::Deleted(body_index));
+                    if body_index != last_body_index {
+                        self.record_body_index_change(BodyIndexChange::Moved {
+                            from: last_body_index,
+                            to: body_index,
+                        });
                     }
-                    OutsideOfGridBoundsBehaviour::ContinueUpdating => {
+                    // We don't increment the index, as the body that was swapped in still needs checking.
+                }
+            }
+            OutsideOfGridBoundsBehaviour::DeleteParticle => {
+                // Vec::remove would shift every body after it for every deletion, so instead we shift everything once.
+                let mut kept_bodies = 0;
+                for body_index in 0..self.bodies.len() {
+                    let body = &self.bodies[body_index];
+                    if !body.is_none()
+                        && is_outside_of_grid_bounds(
+                            math::sub_3d(body.position_unchecked(), grid_origin),
+                            real_grid_size,
+                        )
+                    {
+                        self.record_body_index_change(BodyIndexChange::Deleted(body_index));
                         continue;
                     }
-                    OutsideOfGridBoundsBehaviour::Custom(function) => {
-                        function(body_index, body);
-                        continue;
+
+                    if kept_bodies != body_index {
+                        self.bodies.swap(kept_bodies, body_index);
+                        self.record_body_index_change(BodyIndexChange::Moved {
+                            from: body_index,
+                            to: kept_bodies,
+                        });
                     }
+                    kept_bodies += 1;
                 }
+                self.bodies.truncate(kept_bodies);
             }
+            OutsideOfGridBoundsBehaviour::PutParticleInBounds => {
+                // The furthest a body can be from the origin, while still being inside the grid.
+                let max_corrected_position = [
+                    T::from_f64((real_grid_size[0] - 1) as f64),
+                    T::from_f64((real_grid_size[1] - 1) as f64),
+                    T::from_f64((real_grid_size[2] - 1) as f64),
+                ];
+
+                for body in &mut self.bodies {
+                    if body.is_none() {
+                        continue;
+                    }
 
-            let grid_cell_position = [
-                corrected_position_as_usize[0] / self.cell_size[0],
-                corrected_position_as_usize[1] / self.cell_size[1],
-                corrected_position_as_usize[2] / self.cell_size[2],
-            ];
-
-            let grid_cell_position_isize = [
-                grid_cell_position[0] as isize,
-                grid_cell_position[1] as isize,
-                grid_cell_position[2] as isize,
-            ];
-
-            let body_half_size = body.half_size_unchecked();
-            let body_half_size_isize = [
-                body_half_size[0]
-                    .ceil()
-                    .to_isize()
-                    .div_ceil(self.cell_size[0] as isize),
-                body_half_size[1]
-                    .ceil()
-                    .to_isize()
-                    .div_ceil(self.cell_size[1] as isize),
-                body_half_size[2]
-                    .ceil()
-                    .to_isize()
-                    .div_ceil(self.cell_size[2] as isize),
-            ];
-
-            //println!("body half size isize: {:?}",body_half_size_isize);
-
-            /*
-            if body_half_size_isize == [1, 1, 1] {
-                let grid_cell_index = math::index_from_position_3d(
-                    grid_cell_position,
-                    self.grid_size[0],
-                    self.grid_size[1],
-                );
+                    let mut corrected_position =
+                        math::sub_3d(body.position_unchecked(), grid_origin);
+                    if !is_outside_of_grid_bounds(corrected_position, real_grid_size) {
+                        continue;
+                    }
 
-                // If something is wrong, this debug information is usually helpful.
-                self.grid
-                    .get_mut(grid_cell_index)
-                    .unwrap_or_else(|| {
-                        println!("body_position: {:?}", body_position);
-                        println!(
-                            "corrected_position_as_isize: {:?}",
-                            corrected_position_as_isize
-                        );
-                        println!("grid_cell_position: {:?}", grid_cell_position);
-                        println!("self.grid_size: {:?}", self.grid_size);
-                        panic!()
-                    })
-                    .push(body_index);
+                    for axis in 0..3 {
+                        if corrected_position[axis] < T::ZERO {
+                            corrected_position[axis] = T::ZERO;
+                        } else if corrected_position[axis] > max_corrected_position[axis] {
+                            corrected_position[axis] = max_corrected_position[axis];
+                        }
+                    }
 
-                continue;
+                    body.set_position_unchecked(math::add_3d(corrected_position, grid_origin));
+                }
             }
-            */
+            OutsideOfGridBoundsBehaviour::TeleportParticleToPosition(position) => {
+                for body in &mut self.bodies {
+                    if body.is_none() {
+                        continue;
+                    }
 
-            for x in (grid_cell_position_isize[0] - body_half_size_isize[0])
-                ..(grid_cell_position_isize[0] + body_half_size_isize[0])
-            {
-                if x < 0 {
-                    continue;
-                }
-                if x >= self.grid_size[0] as isize {
-                    continue;
+                    if is_outside_of_grid_bounds(
+                        math::sub_3d(body.position_unchecked(), grid_origin),
+                        real_grid_size,
+                    ) {
+                        body.set_position_unchecked(position);
+                    }
                 }
-                for y in (grid_cell_position_isize[1] - body_half_size_isize[1])
-                    ..(grid_cell_position_isize[1] + body_half_size_isize[1])
-                {
-                    if y < 0 {
+            }
+            OutsideOfGridBoundsBehaviour::ContinueUpdating => (),
+            OutsideOfGridBoundsBehaviour::Custom(function) => {
+                for (body_index, body) in self.bodies.iter_mut().enumerate() {
+                    if body.is_none() {
                         continue;
                     }
-                    if y >= self.grid_size[1] as isize {
-                        continue;
+
+                    if is_outside_of_grid_bounds(
+                        math::sub_3d(body.position_unchecked(), grid_origin),
+                        real_grid_size,
+                    ) {
+                        function(body_index, body);
                     }
-                    for z in (grid_cell_position_isize[2] - body_half_size_isize[2])
-                        ..(grid_cell_position_isize[2] + body_half_size_isize[2])
-                    {
-                        if z < 0 {
-                            continue;
+                }
+            }
+        }
+
+        // Any deleted bodies have had their collision layers and sleep states moved over already.
+        self.collision_layers.truncate(self.bodies.len());
+        self.sleep_states.truncate(self.bodies.len());
+    }
+
+    /// Records the change, and keeps the body handles pointing at the right bodies.
+    #[inline]
+    fn record_body_index_change(&mut self, body_index_change: BodyIndexChange) {
+        self.body_handles.apply_body_index_change(body_index_change);
+        self.body_index_changes.push(body_index_change);
+
+        match body_index_change {
+            BodyIndexChange::Moved { from, to } => {
+                // Bodies without collision layers are on every layer, so that is what the index gets if the moved body had none.
+                if from < self.collision_layers.len() || to < self.collision_layers.len() {
+                    self.set_collision_layers(to, self.collision_layers(from));
+                }
+                // A body without a sleep state is awake, so it mustn't inherit whatever state was left at its new index.
+                match self.sleep_states.get(from) {
+                    Some(&sleep_state) => {
+                        if self.sleep_states.len() <= to {
+                            self.sleep_states.resize(to + 1, SleepState::default());
                         }
-                        if z >= self.grid_size[2] as isize {
-                            continue;
+                        self.sleep_states[to] = sleep_state;
+                    }
+                    None => {
+                        if let Some(sleep_state) = self.sleep_states.get_mut(to) {
+                            *sleep_state = SleepState::default();
                         }
-                        let current_grid_cell_position = [x as usize, y as usize, z as usize];
-                        let grid_cell_index = math::index_from_position_3d(
-                            current_grid_cell_position,
-                            self.grid_size[0],
-                            self.grid_size[1],
-                        );
-
-                        // If something is wrong, this debug information is usually helpful.
-                        self.grid
-                            .get_mut(grid_cell_index)
-                            .unwrap_or_else(|| {
-                                println!("body_position: {:?}", body_position);
-                                println!(
-                                    "corrected_position_as_isize: {:?}",
-                                    corrected_position_as_isize
-                                );
-                                println!("grid_cell_position: {:?}", grid_cell_position);
-                                println!(
-                                    "current_grid_cell_position: {:?}",
-                                    current_grid_cell_position
-                                );
-                                println!("self.grid_size: {:?}", self.grid_size);
-                                panic!()
-                            })
-                            .push(body_index);
                     }
                 }
             }
+            BodyIndexChange::Deleted(body_index) => {
+                if let Some(collision_layers) = self.collision_layers.get_mut(body_index) {
+                    *collision_layers = CollisionLayers::ALL;
+                }
+                if let Some(sleep_state) = self.sleep_states.get_mut(body_index) {
+                    *sleep_state = SleepState::default();
+                }
+            }
         }
+        self.broadphase_is_stale = true;
+
+        self.constraints
+            .retain_mut(|constraint| constraint.apply_body_index_change(body_index_change));
+
+        // Contacts with a deleted body end without an event, as the body they would refer to is gone.
+        apply_body_index_change_to_contacts(&mut self.contacts, body_index_change);
+        apply_body_index_change_to_contacts(&mut self.gathered_contacts, body_index_change);
     }
 
-    #[inline]
-    #[allow(dead_code)]
-    fn serial_update_bodies(&mut self, delta_time: T) {
-        self.bodies.iter_mut().for_each(|body| {
-            if body.is_none() {
-                return;
-            }
+    /// Adds a body to the solver, returning a handle to it.
+    /// The handle stays valid until the body is removed, no matter how many other bodies get moved or removed.
+    pub fn insert(&mut self, body: B) -> BodyHandle {
+        let body_index = self.bodies.len();
+        self.bodies.push(body);
+        self.body_handles.allocate(body_index)
+    }
 
-            body.update(self.gravity, self.dampening, delta_time);
-        });
+    /// Removes the body the handle points to, returning it. Returns None if the handle is stale.
+    ///
+    /// The last body is moved into the removed body's index, so like the delete [OutsideOfGridBoundsBehaviour]s this is recorded in body_index_changes.
+    /// Those changes are only kept until the next update starts.
+    pub fn remove(&mut self, handle: BodyHandle) -> Option<B> {
+        let body_index = self.body_handles.body_index(handle)?;
+
+        let last_body_index = self.bodies.len() - 1;
+        let body = self.bodies.swap_remove(body_index);
+        self.record_body_index_change(BodyIndexChange::Deleted(body_index));
+        if body_index != last_body_index {
+            self.record_body_index_change(BodyIndexChange::Moved {
+                from: last_body_index,
+                to: body_index,
+            });
+        }
+        self.collision_layers.truncate(self.bodies.len());
+        self.sleep_states.truncate(self.bodies.len());
+
+        Some(body)
     }
 
-    #[inline]
-    fn update_bodies(&mut self, delta_time: T) {
-        self.bodies.par_iter_mut().for_each(|body| {
-            if body.is_none() {
-                return;
-            }
+    /// Gets the body the handle points to. Returns None if the handle is stale.
+    pub fn get(&self, handle: BodyHandle) -> Option<&B> {
+        Some(&self.bodies[self.body_handles.body_index(handle)?])
+    }
 
-            body.update(self.gravity, self.dampening, delta_time);
-        });
+    /// Mutably gets the body the handle points to. Returns None if the handle is stale.
+    /// The body is woken up, as whatever you do to it might move it.
+    pub fn get_mut(&mut self, handle: BodyHandle) -> Option<&mut B> {
+        let body_index = self.body_handles.body_index(handle)?;
+        self.wake(body_index);
+        Some(&mut self.bodies[body_index])
     }
 
-    #[inline]
-    fn detect_collisions_extra_experimental(&self, collision_sender: &Sender<(usize, usize)>) {
-        //TODO: check whether this is the right place to par_iter, rather than one of the other for loops.
-        (&self.grid).into_par_iter().for_each(|cell| {
-            for lhs_body_index in cell {
-                if !self.bodies[*lhs_body_index].collide_with_others() {
-                    continue;
-                }
-                for rhs_body_index in cell {
-                    if lhs_body_index == rhs_body_index {
-                        continue;
-                    }
+    /// Whether the handle still points to a body.
+    pub fn contains(&self, handle: BodyHandle) -> bool {
+        self.body_handles.body_index(handle).is_some()
+    }
 
-                    if self.bodies[*lhs_body_index].detect_collision(&self.bodies[*rhs_body_index])
-                    {
-                        collision_sender
-                            .send((*lhs_body_index, *rhs_body_index))
-                            .unwrap();
-                    }
-                }
-            }
-        });
+    /// Gets the current index of the body in bodies. Useful for working with collisions, which store indices.
+    /// Returns None if the handle is stale.
+    pub fn body_index(&self, handle: BodyHandle) -> Option<usize> {
+        self.body_handles.body_index(handle)
     }
 
-    #[inline]
-    fn respond_to_collisions(&mut self, delta_time: T) {
-        for (lhs_body_index, rhs_body_index) in &self.collisions {
-            let lhs_body_index = *lhs_body_index;
-            let rhs_body_index = *rhs_body_index;
+    /// Gets the handle of the body at the index.
+    /// Returns None if there is no body there, or if the body was pushed directly into bodies, rather than inserted.
+    pub fn handle(&self, body_index: usize) -> Option<BodyHandle> {
+        self.body_handles.handle(body_index)
+    }
 
-            // This code is simple and elegant. By splitting at the largest index, it allows us to safely and &mutably yoink the verlet bodies.
-            if lhs_body_index > rhs_body_index {
-                let (lhs_bodies, rhs_bodies) = self.bodies.split_at_mut(lhs_body_index);
-                //rhs_bodies[0].collide(&mut lhs_bodies[rhs_body_index], rhs_body_index, delta_time);
-                rhs_bodies[0].respond_to_collision(
-                    &mut lhs_bodies[rhs_body_index],
-                    lhs_body_index,
-                    rhs_body_index,
-                    delta_time,
-                );
-            } else {
-                let (lhs_bodies, rhs_bodies) = self.bodies.split_at_mut(rhs_body_index);
-                //lhs_bodies[lhs_body_index].collide(&mut rhs_bodies[0], rhs_body_index, delta_time);
-                lhs_bodies[lhs_body_index].respond_to_collision(
-                    &mut rhs_bodies[0],
-                    lhs_body_index,
-                    rhs_body_index,
-                    delta_time,
-                );
+    /// Gets the collision layers of the body at the index.
+    pub fn collision_layers(&self, body_index: usize) -> CollisionLayers {
+        self.collision_layers
+            .get(body_index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets the collision layers of the body at the index. They follow the body when the solver moves it to another index.
+    pub fn set_collision_layers(&mut self, body_index: usize, collision_layers: CollisionLayers) {
+        if self.collision_layers.len() <= body_index {
+            self.collision_layers
+                .resize(body_index + 1, CollisionLayers::ALL);
+        }
+        self.collision_layers[body_index] = collision_layers;
+    }
+
+    /// Whether the body at the index is asleep. See [Sleeping].
+    pub fn is_asleep(&self, body_index: usize) -> bool {
+        is_asleep(&self.sleep_states, body_index)
+    }
+
+    /// Wakes up the body at the index, along with the rest of its island.
+    /// Use this after applying an impulse to a body, or changing it in any other way, through bodies directly.
+    pub fn wake(&mut self, body_index: usize) {
+        if let Some(island) = self
+            .sleep_states
+            .get(body_index)
+            .and_then(|sleep_state| sleep_state.island)
+        {
+            self.wake_islands(&[island]);
+        }
+    }
+
+    /// Wakes every body in the islands. This has to look through every body, so islands are woken in batches.
+    fn wake_islands(&mut self, islands: &[u32]) {
+        for sleep_state in &mut self.sleep_states {
+            if let Some(island) = sleep_state.island {
+                if islands.contains(&island) {
+                    *sleep_state = SleepState::default();
+                }
             }
         }
     }
-}
 
-/// If a body is outside of the grid, what should it do?
-pub enum OutsideOfGridBoundsBehaviour<T: math::Float, B: Body<T>> {
-    SwapDeleteParticle,
-    DeleteParticle,
-    PutParticleInBounds,
-    TeleportParticleToPosition([T; 3]),
-    ContinueUpdating,
-    Custom(fn(usize, &mut B)),
-    // replace with body?
-}
+    /// Any sleeping body an awake body collided with wakes up, along with its island, so it can respond to the collision.
+    #[inline]
+    fn wake_islands_touched_by_awake_bodies(&mut self) {
+        if self.sleeping.is_none() {
+            return;
+        }
 
-#[cfg(test)]
-mod tests {
-    use crate::physics::physics_3d::bodies::CommonBody;
-    use crate::physics::physics_3d::verlet::bodies::Cuboid;
-    use crate::physics::physics_3d::verlet::Particle;
+        // Sleeping bodies don't detect collisions themselves, so the awake body is always lhs.
+        let mut islands = vec![];
+        for &(_, rhs_body_index) in &self.collisions {
+            if let Some(island) = self
+                .sleep_states
+                .get(rhs_body_index)
+                .and_then(|sleep_state| sleep_state.island)
+            {
+                if !islands.contains(&island) {
+                    islands.push(island);
+                }
+            }
+        }
 
-    use crate::math::Float;
+        if !islands.is_empty() {
+            self.wake_islands(&islands);
+        }
+    }
 
-    use super::*;
-    use rand::thread_rng;
-    use rand::Rng;
-    use test::Bencher;
+    /// Any sleeping body constrained to an awake body wakes up, along with its island, as the awake body might pull on it.
+    #[inline]
+    fn wake_islands_held_by_constraints(&mut self) {
+        if self.sleeping.is_none() {
+            return;
+        }
 
-    fn create_test_solver<T: Float + rand::distributions::uniform::SampleUniform>(
-        amount: usize,
-        gravity: T,
-    ) -> CpuSolver<T, CommonBody<T>> {
-        let mut verlet_bodies = Vec::with_capacity(amount);
-        let mut rng = thread_rng();
+        let mut islands = vec![];
+        for constraint in &self.constraints {
+            let (lhs_body_index, Some(rhs_body_index)) = constraint.body_indices() else {
+                continue;
+            };
 
-        for _ in 0..amount {
-            verlet_bodies
-                .push_within_capacity(CommonBody::Cuboid(Cuboid {
-                    particle: Particle::from_position([
-                        rng.gen_range(T::from_f64(-50.0)..T::from_f64(50.0)),
-                        rng.gen_range(T::from_f64(-50.0)..T::from_f64(50.0)),
-                        rng.gen_range(T::from_f64(-50.0)..T::from_f64(50.0)),
-                    ]),
+            for (body_index, other_body_index) in [
+                (lhs_body_index, rhs_body_index),
+                (rhs_body_index, lhs_body_index),
+            ] {
+                if !self.is_dynamic_and_awake(other_body_index) {
+                    continue;
+                }
+                if let Some(island) = self
+                    .sleep_states
+                    .get(body_index)
+                    .and_then(|sleep_state| sleep_state.island)
+                {
+                    if !islands.contains(&island) {
+                        islands.push(island);
+                    }
+                }
+            }
+        }
 
-                    half_size: [T::from_f64(0.5); 3],
-                }))
-                .unwrap();
+        if !islands.is_empty() {
+            self.wake_islands(&islands);
         }
+    }
 
-        CpuSolver::new(Config {
-            gravity: [T::ZERO, gravity, T::ZERO],
-            dampening: [T::from_f64(0.8), T::ONE, T::from_f64(0.8)],
-            grid_size: [10, 10, 10],
-            grid_origin: [T::from_f64(-50.0), T::from_f64(-50.0), T::from_f64(-50.0)],
-            cell_size: [10, 10, 10],
-            outside_of_grid_bounds_behaviour: OutsideOfGridBoundsBehaviour::ContinueUpdating,
-            bodies: verlet_bodies,
+    /// Whether the body at the index exists, collides with others, and is awake.
+    #[inline]
+    fn is_dynamic_and_awake(&self, body_index: usize) -> bool {
+        self.bodies.get(body_index).is_some_and(|body| {
+            !body.is_none() && body.collide_with_others() && !self.is_asleep(body_index)
         })
     }
 
-    // (10,22), (1000,2_468), (5000,12_518), (10_000,28_238), (20_000,54_449), (50_000,171_110), (100_000,672_048)
-    #[bench]
-    fn bench_cpu_solver_serial_update_100_000_particles(b: &mut Bencher) {
-        let mut solver = create_test_solver::<f32>(100_000, 0.0);
-        b.iter(|| {
-            solver.serial_update_bodies(0.04);
-        })
+    /// Whether any body the constraint links is asleep. Those constraints are skipped, as any awake body they link to has already woken the sleeping one.
+    #[inline]
+    fn constraint_is_asleep(&self, constraint: &Constraint<T>) -> bool {
+        let (lhs_body_index, rhs_body_index) = constraint.body_indices();
+        self.is_asleep(lhs_body_index)
+            || rhs_body_index.is_some_and(|rhs_body_index| self.is_asleep(rhs_body_index))
     }
 
-    // (10,7_581), (1000,21_242), (5000,32_108), (10_000,69_903), (20_000,171_272), (50_000,385_621), (100_000,821_128)
-    #[bench]
-    fn bench_cpu_solver_update_100_000_particles(b: &mut Bencher) {
-        let mut solver = create_test_solver::<f32>(100_000, 0.0);
-        b.iter(|| {
-            solver.update_bodies(0.04);
-        })
+    #[inline]
+    fn apply_constraint_forces(&mut self, delta_time: T) {
+        for constraint_index in 0..self.constraints.len() {
+            let constraint = self.constraints[constraint_index];
+            if self.constraint_is_asleep(&constraint) {
+                continue;
+            }
+            constraint.apply_force(&mut self.bodies, delta_time);
+        }
     }
 
-    #[bench]
-    fn bench_cpu_solver_30000_particles(b: &mut Bencher) {
-        let mut solver = create_test_solver(30000, 0.0);
-        b.iter(|| {
-            solver.update(0.04);
-        })
+    #[inline]
+    fn relax_constraints(&mut self) {
+        // Pins go last, so that pinned bodies end up exactly where they were pinned, rather than being dragged slightly by whatever is attached to them.
+        for pins in [false, true] {
+            for constraint_index in 0..self.constraints.len() {
+                let constraint = self.constraints[constraint_index];
+                if matches!(constraint, Constraint::Pin { .. }) != pins
+                    || self.constraint_is_asleep(&constraint)
+                {
+                    continue;
+                }
+                constraint.relax(&mut self.bodies);
+            }
+        }
     }
 
-    #[bench]
-    fn bench_cpu_solver_1000_none_particles(b: &mut Bencher) {
-        let mut verlet_bodies = Vec::with_capacity(1000);
+    /// Works out which bodies have rested for long enough, joins touching bodies into islands, and puts every island that is entirely resting to sleep.
+    fn update_sleeping(&mut self, delta_time: T) {
+        let Some(sleeping) = self.sleeping else {
+            return;
+        };
+        self.sleep_states
+            .resize(self.bodies.len(), SleepState::default());
 
-        for _ in 0..1000 {
-            verlet_bodies.push(CommonBody::None);
-        }
+        let displacement_threshold_squared =
+            sleeping.displacement_threshold * sleeping.displacement_threshold;
+        let mut island_parents: Vec<usize> = (0..self.bodies.len()).collect();
+
+        for (body, sleep_state) in self.bodies.iter().zip(&mut self.sleep_states) {
+            if body.is_none() || sleep_state.island.is_some() {
+                continue;
+            }
+
+            match body.displacement() {
+                Some(displacement)
+                    if math::dot(displacement, displacement) <= displacement_threshold_squared =>
+                {
+                    sleep_state.resting_time += delta_time;
+                }
+                _ => sleep_state.resting_time = T::ZERO,
+            }
+        }
+
+        // Bodies that don't collide with others, like the floor, would join everything into one island.
+        for contact in &self.contacts {
+            let (lhs_body_index, rhs_body_index) = contact.body_indices();
+            if self.is_asleep(lhs_body_index)
+                || self.is_asleep(rhs_body_index)
+                || !self.bodies[lhs_body_index].collide_with_others()
+                || !self.bodies[rhs_body_index].collide_with_others()
+            {
+                continue;
+            }
+
+            let lhs_root = find_island_root(&mut island_parents, lhs_body_index);
+            let rhs_root = find_island_root(&mut island_parents, rhs_body_index);
+            island_parents[lhs_root] = rhs_root;
+        }
+        // Constrained bodies pull on each other, so they have to sleep and wake together too.
+        for constraint in &self.constraints {
+            let (lhs_body_index, Some(rhs_body_index)) = constraint.body_indices() else {
+                continue;
+            };
+            if !self.is_dynamic_and_awake(lhs_body_index)
+                || !self.is_dynamic_and_awake(rhs_body_index)
+            {
+                continue;
+            }
+
+            let lhs_root = find_island_root(&mut island_parents, lhs_body_index);
+            let rhs_root = find_island_root(&mut island_parents, rhs_body_index);
+            island_parents[lhs_root] = rhs_root;
+        }
+
+        let mut island_is_resting = vec![true; self.bodies.len()];
+        for body_index in 0..self.bodies.len() {
+            let body = &self.bodies[body_index];
+            if body.is_none() || !body.collide_with_others() || self.is_asleep(body_index) {
+                continue;
+            }
+
+            let is_resting = body.displacement().is_some()
+                && self.sleep_states[body_index].resting_time >= sleeping.time_until_sleep;
+            if !is_resting {
+                let root = find_island_root(&mut island_parents, body_index);
+                island_is_resting[root] = false;
+            }
+        }
+
+        let mut island_of_root: Vec<Option<u32>> = vec![None; self.bodies.len()];
+        for body_index in 0..self.bodies.len() {
+            let body = &self.bodies[body_index];
+            if body.is_none() || !body.collide_with_others() || self.is_asleep(body_index) {
+                continue;
+            }
+
+            let root = find_island_root(&mut island_parents, body_index);
+            if !island_is_resting[root] {
+                continue;
+            }
+
+            let island = *island_of_root[root].get_or_insert_with(|| {
+                self.next_island = self.next_island.wrapping_add(1);
+                self.next_island
+            });
+            self.sleep_states[body_index].island = Some(island);
+
+            // Stops it, so it doesn't carry on with whatever was left of its velocity when it wakes.
+            let body = &mut self.bodies[body_index];
+            body.set_position_unchecked(body.position_unchecked());
+        }
+    }
+
+    /// Follows a body index through this update's body_index_changes.
+    /// Returns None if the body was deleted.
+    pub fn updated_body_index(&self, mut body_index: usize) -> Option<usize> {
+        for body_index_change in &self.body_index_changes {
+            match *body_index_change {
+                BodyIndexChange::Deleted(deleted_index) => {
+                    if deleted_index == body_index {
+                        return None;
+                    }
+                }
+                BodyIndexChange::Moved { from, to } => {
+                    if from == body_index {
+                        body_index = to;
+                    }
+                }
+            }
+        }
+        Some(body_index)
+    }
+
+    /// Finds the closest body the ray hits, ignoring any body the filter returns false for.
+    /// Direction doesn't need to be normalised, but the distance of the hit is in world units.
+    ///
+    /// Like all queries, this uses the broadphase from the last update, so bodies that were inserted or teleported since then can be missed.
+    pub fn raycast(
+        &self,
+        origin: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        filter: impl FnMut(usize, &B) -> bool,
+    ) -> Option<RaycastHit<T>> {
+        self.shape_cast(origin, [T::ZERO; 3], direction, max_distance, filter)
+    }
+
+    /// Finds every body the ray hits, ignoring any body the filter returns false for.
+    /// The hits are sorted from closest to furthest.
+    pub fn raycast_all(
+        &self,
+        origin: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        mut filter: impl FnMut(usize, &B) -> bool,
+    ) -> Vec<RaycastHit<T>> {
+        let mut hits = vec![];
+        self.cast_through_broadphase(
+            origin,
+            [T::ZERO; 3],
+            direction,
+            max_distance,
+            false,
+            &mut filter,
+            &mut BodyIndexSet::default(),
+            &mut |hit| hits.push(hit),
+        );
+        hits.sort_unstable_by(|lhs, rhs| {
+            lhs.distance
+                .partial_cmp(&rhs.distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        hits
+    }
+
+    /// Sweeps an aabb from origin along the direction, and finds the closest body it hits, ignoring any body the filter returns false for.
+    /// The hit's point is where the centre of the aabb is when it hits the body.
+    pub fn shape_cast(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        filter: impl FnMut(usize, &B) -> bool,
+    ) -> Option<RaycastHit<T>> {
+        self.shape_cast_with_scratch(
+            origin,
+            half_size,
+            direction,
+            max_distance,
+            filter,
+            &mut BodyIndexSet::default(),
+        )
+    }
+
+    /// Like [CpuSolver::shape_cast], but reuses tested_body_indices, so that casting many times doesn't allocate every time.
+    #[inline]
+    fn shape_cast_with_scratch(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        mut filter: impl FnMut(usize, &B) -> bool,
+        tested_body_indices: &mut BodyIndexSet,
+    ) -> Option<RaycastHit<T>> {
+        let mut closest_hit = None;
+        self.cast_through_broadphase(
+            origin,
+            half_size,
+            direction,
+            max_distance,
+            true,
+            &mut filter,
+            tested_body_indices,
+            // Every hit is closer than the one before it.
+            &mut |hit| closest_hit = Some(hit),
+        );
+        closest_hit
+    }
+
+    /// Casts against every body the broadphase gives, along the ray, giving each hit to on_hit.
+    /// If closest_only is true, only hits closer than every previous hit are given, and the broadphase can stop once no closer hit is possible.
+    #[allow(clippy::too_many_arguments)]
+    fn cast_through_broadphase(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        mut max_distance: T,
+        closest_only: bool,
+        filter: &mut impl FnMut(usize, &B) -> bool,
+        tested_body_indices: &mut BodyIndexSet,
+        on_hit: &mut impl FnMut(RaycastHit<T>),
+    ) {
+        let direction = math::normalise_3d(direction);
+
+        // Bodies can be given more than once, such as by every cell they are in, so we keep track of which ones have already been cast against.
+        tested_body_indices.reset(self.bodies.len());
+
+        self.broadphase.cast(
+            origin,
+            half_size,
+            direction,
+            max_distance,
+            &mut |body_index| {
+                // The broadphase is from the last update, so bodies might have been removed since.
+                if body_index >= self.bodies.len() || !tested_body_indices.insert(body_index) {
+                    return max_distance;
+                }
+
+                let body = &self.bodies[body_index];
+                if body.is_none() || !filter(body_index, body) {
+                    return max_distance;
+                }
+
+                let Some((normal, distance)) =
+                    body.cast_aabb(origin, half_size, direction, max_distance)
+                else {
+                    return max_distance;
+                };
+
+                on_hit(RaycastHit {
+                    body_index,
+                    point: math::add_3d(origin, math::mul_3d_by_1d(direction, distance)),
+                    normal,
+                    distance,
+                });
+
+                if closest_only {
+                    max_distance = distance;
+                }
+                max_distance
+            },
+        );
+    }
+
+    /// Fills results with the index of every body that overlaps the aabb.
+    /// Results is cleared first, so the same buffer can be reused every frame without allocating.
+    ///
+    /// Like all queries, this uses the broadphase from the last update, so bodies that were inserted or teleported since then can be missed.
+    pub fn query_aabb(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>) {
+        self.query_broadphase_candidates(aabb, results);
+        results.retain(|body_index| self.bodies[*body_index].is_intersected_by_aabb(aabb));
+    }
+
+    /// Fills results with the index of every body that overlaps the sphere.
+    /// Results is cleared first, so the same buffer can be reused every frame without allocating.
+    pub fn query_sphere(&self, sphere: Sphere<T>, results: &mut Vec<usize>) {
+        self.query_broadphase_candidates(
+            AabbCentredOrigin {
+                position: sphere.position,
+                half_size: [sphere.radius; 3],
+            },
+            results,
+        );
+        results.retain(|body_index| self.bodies[*body_index].is_intersected_by_sphere(sphere));
+    }
+
+    /// Fills results with the index of every body the point is inside of.
+    /// Results is cleared first, so the same buffer can be reused every frame without allocating.
+    pub fn query_point(&self, point: [T; 3], results: &mut Vec<usize>) {
+        self.query_aabb(
+            AabbCentredOrigin {
+                position: point,
+                half_size: [T::ZERO; 3],
+            },
+            results,
+        );
+    }
+
+    /// Fills results with every body the broadphase thinks might overlap the aabb, with no duplicates.
+    fn query_broadphase_candidates(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>) {
+        results.clear();
+        self.broadphase.extend_with_candidates(aabb, results);
+
+        // Bodies can be given more than once, such as by every cell they are in, so this removes the duplicates.
+        results.sort_unstable();
+        results.dedup();
+
+        // The broadphase is from the last update, so bodies might have been removed since.
+        results.retain(|body_index| {
+            *body_index < self.bodies.len() && !self.bodies[*body_index].is_none()
+        });
+    }
+
+    /// The broadphase is cleared right before bodies are placed into it, rather than at the end of an update, so that queries can use it between updates.
+    /// Sleeping bodies don't move, so they are left in, unless body indices have changed. Returns whether they were left in.
+    #[inline]
+    fn clear_broadphase(&mut self) -> bool {
+        let keep_sleeping_bodies = self.sleeping.is_some() && !self.broadphase_is_stale;
+        self.broadphase_is_stale = false;
+
+        if keep_sleeping_bodies {
+            let sleep_states = &self.sleep_states;
+            self.broadphase
+                .retain(&|body_index| is_asleep(sleep_states, body_index));
+        } else {
+            self.broadphase.clear();
+        }
+        keep_sleeping_bodies
+    }
+
+    /// If the sleeping bodies were kept in the broadphase, only the awake ones are placed.
+    #[inline]
+    fn place_bodies_into_broadphase(&mut self, kept_sleeping_bodies: bool) {
+        for (body_index, body) in self.bodies.iter().enumerate() {
+            if body.is_none() || (kept_sleeping_bodies && is_asleep(&self.sleep_states, body_index))
+            {
+                continue;
+            }
+
+            self.broadphase.insert(
+                body_index,
+                AabbCentredOrigin {
+                    position: body.position_unchecked(),
+                    half_size: body.half_size_unchecked(),
+                },
+            );
+        }
+        self.broadphase.finish_inserting();
+    }
+
+    #[inline]
+    fn serial_update_bodies(&mut self, delta_time: T) {
+        let sleep_states = &self.sleep_states;
+        self.bodies
+            .iter_mut()
+            .enumerate()
+            .for_each(|(body_index, body)| {
+                if body.is_none() || is_asleep(sleep_states, body_index) {
+                    return;
+                }
+
+                body.update(self.gravity, self.dampening, delta_time);
+            });
+    }
+
+    #[inline]
+    fn update_bodies(&mut self, delta_time: T) {
+        let sleep_states = &self.sleep_states;
+        self.bodies
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(body_index, body)| {
+                if body.is_none() || is_asleep(sleep_states, body_index) {
+                    return;
+                }
+
+                body.update(self.gravity, self.dampening, delta_time);
+            });
+    }
+
+    /// Sweeps every body using continuous collision detection from where it was to where it is, against the bodies that stop sweeps.
+    /// If the sweep hits one, the body is put back to where it first touched it, keeping its velocity, and collides with it, so that it can't tunnel through.
+    ///
+    /// A body moving less than its own smallest half size can't tunnel, so isn't swept.
+    fn sweep_continuous_bodies(&mut self, delta_time: T) {
+        let mut scratch = std::mem::take(&mut self.sweep_scratch);
+        for body_index in 0..self.bodies.len() {
+            let body = &self.bodies[body_index];
+            if body.is_none()
+                || !body.continuous_collision_detection()
+                || self.is_asleep(body_index)
+            {
+                continue;
+            }
+            let half_size = body.half_size_unchecked();
+            let Some(particle) = self.bodies[body_index].particle_mut() else {
+                continue;
+            };
+            let displacement = particle.calculate_displacement();
+            let previous_position = particle.previous_position;
+
+            let distance = math::get_magnitude_3d(displacement);
+            let smallest_half_size = half_size.into_iter().fold(T::MAX, |smallest, half_size| {
+                if half_size < smallest {
+                    half_size
+                } else {
+                    smallest
+                }
+            });
+            if distance <= smallest_half_size {
+                continue;
+            }
+
+            let Some(hit) = self.sweep(
+                previous_position,
+                half_size,
+                displacement,
+                distance,
+                self.collision_layers(body_index),
+                Some(body_index),
+                &mut scratch,
+            ) else {
+                continue;
+            };
+
+            let particle = self.bodies[body_index].particle_mut().unwrap();
+            particle.position = hit.point;
+            particle.previous_position = math::sub_3d(hit.point, displacement);
+
+            if body_index > hit.body_index {
+                let (lhs_bodies, rhs_bodies) = self.bodies.split_at_mut(body_index);
+                rhs_bodies[0].respond_to_collision(
+                    &mut lhs_bodies[hit.body_index],
+                    body_index,
+                    hit.body_index,
+                    delta_time,
+                );
+            } else {
+                let (lhs_bodies, rhs_bodies) = self.bodies.split_at_mut(hit.body_index);
+                lhs_bodies[body_index].respond_to_collision(
+                    &mut rhs_bodies[0],
+                    body_index,
+                    hit.body_index,
+                    delta_time,
+                );
+            }
+
+            // Leaves a sliver of space, so that the collision isn't found again and responded to a second time.
+            if let Some(particle) = self.bodies[body_index].particle_mut() {
+                particle.apply_uniform_position_change(math::mul_3d_by_1d(
+                    hit.normal,
+                    T::from_f32(0.0001),
+                ));
+            }
+        }
+        self.sweep_scratch = scratch;
+    }
+
+    /// Sweeps every cuboid in cuboid_arrays from where it was to where it is, against the bodies that stop sweeps, like [CpuSolver::sweep_continuous_bodies].
+    /// This is the only collision they get, so they can rest on what they land on.
+    fn sweep_cuboid_arrays(&mut self, delta_time: T) {
+        if self.cuboid_arrays.is_empty() {
+            return;
+        }
+
+        let mut scratch = std::mem::take(&mut self.sweep_scratch);
+        let mut hits = std::mem::take(&mut self.cuboid_arrays_hits);
+        hits.clear();
+
+        let cuboid_arrays = &self.cuboid_arrays;
+        let sweep_cuboid = |scratch: &mut SweepScratch, cuboid_index: usize| {
+            let particle = cuboid_arrays.particle(cuboid_index);
+            let displacement = particle.calculate_displacement();
+            let distance = math::get_magnitude_3d(displacement);
+            if distance == T::ZERO {
+                return None;
+            }
+            let hit = self.sweep(
+                particle.previous_position,
+                cuboid_arrays.half_size(cuboid_index),
+                displacement,
+                distance,
+                CollisionLayers::default(),
+                None,
+                scratch,
+            )?;
+            Some((cuboid_index, hit))
+        };
+        // Sweeping only reads, so it can be done on multiple threads, each with its own scratch, leaving the responses for afterwards.
+        if self.deterministic {
+            hits.extend(
+                (0..cuboid_arrays.len())
+                    .filter_map(|cuboid_index| sweep_cuboid(&mut scratch, cuboid_index)),
+            );
+        } else {
+            hits.par_extend(
+                (0..cuboid_arrays.len())
+                    .into_par_iter()
+                    .map_init(SweepScratch::default, sweep_cuboid)
+                    .flatten(),
+            );
+        }
+
+        for &(cuboid_index, hit) in &hits {
+            let mut particle = self.cuboid_arrays.particle(cuboid_index);
+            let displacement = particle.calculate_displacement();
+            particle.position = hit.point;
+            particle.previous_position = math::sub_3d(hit.point, displacement);
+
+            let material = self
+                .cuboid_arrays
+                .material(cuboid_index)
+                .combine(&self.bodies[hit.body_index].material());
+            bodies::respond_to_collision_with_immovable(
+                &mut particle,
+                math::neg_3d(hit.normal),
+                T::ZERO,
+                material,
+                delta_time,
+            );
+
+            // Leaves a sliver of space, so that the cuboid doesn't start its next sweep inside the body.
+            particle
+                .apply_uniform_position_change(math::mul_3d_by_1d(hit.normal, T::from_f32(0.0001)));
+            self.cuboid_arrays.set_particle(cuboid_index, particle);
+        }
+
+        self.sweep_scratch = scratch;
+        self.cuboid_arrays_hits = hits;
+    }
+
+    /// Sweeps an aabb along the displacement against the bodies that stop sweeps, skipping ignored_body_index, and any body not in collision_layers.
+    /// Hits the aabb is moving away from, or along, such as the floor it is sliding on, are skipped.
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn sweep(
+        &self,
+        previous_position: [T; 3],
+        half_size: [T; 3],
+        displacement: [T; 3],
+        distance: T,
+        collision_layers: CollisionLayers,
+        ignored_body_index: Option<usize>,
+        scratch: &mut SweepScratch,
+    ) -> Option<RaycastHit<T>> {
+        let direction = math::div_3d_by_1d(displacement, distance);
+
+        let SweepScratch {
+            tested_body_indices,
+            skipped_body_indices,
+        } = scratch;
+        skipped_body_indices.clear();
+        loop {
+            let hit = self.shape_cast_with_scratch(
+                previous_position,
+                half_size,
+                direction,
+                distance,
+                |rhs_body_index, rhs| {
+                    Some(rhs_body_index) != ignored_body_index
+                        && rhs.stops_sweeps()
+                        && !skipped_body_indices.contains(&rhs_body_index)
+                        && collision_layers.collides_with(self.collision_layers(rhs_body_index))
+                },
+                tested_body_indices,
+            )?;
+            if math::dot(hit.normal, direction) < T::ZERO {
+                return Some(hit);
+            }
+            skipped_body_indices.push(hit.body_index);
+        }
+    }
+
+    /// Detects collisions on multiple threads. The broadphase finds pairs into a buffer per thread, and each pair is then tested on whichever thread gets it.
+    #[inline]
+    fn detect_collisions(&mut self) {
+        let can_detect = |body_index: usize| self.can_detect(body_index);
+        let pairs = self.broadphase.par_find_pairs(&can_detect);
+
+        self.collisions = pairs
+            .par_iter()
+            .flatten()
+            .filter_map(|&(lhs_body_index, rhs_body_index)| {
+                self.narrowphase(lhs_body_index, rhs_body_index)
+            })
+            .collect();
+
+        // Pairs can come out in a different order every run, such as from the spatial hash's cells, so the collisions are sorted to keep them reproducible.
+        self.collisions.par_sort_unstable();
+    }
+
+    /// Detects collisions on the current thread, straight into self.collisions.
+    #[inline]
+    fn serial_detect_collisions(&mut self) {
+        let mut collisions = std::mem::take(&mut self.collisions);
+        collisions.clear();
+
+        self.broadphase.find_pairs(
+            &|body_index| self.can_detect(body_index),
+            &mut |lhs_body_index, rhs_body_index| {
+                if let Some(collision) = self.narrowphase(lhs_body_index, rhs_body_index) {
+                    collisions.push(collision);
+                }
+            },
+        );
+        collisions.sort_unstable();
+
+        self.collisions = collisions;
+    }
+
+    /// Whether the body looks for collisions itself. Sleeping bodies only get collided with by awake ones, which wakes them.
+    #[inline]
+    fn can_detect(&self, body_index: usize) -> bool {
+        self.bodies[body_index].collide_with_others() && !self.is_asleep(body_index)
+    }
+
+    /// Tests whether a pair from the broadphase actually collides, returning it as (lhs_body_index, rhs_body_index) if so.
+    /// Whichever body can detect collisions is lhs, preferring the smaller index.
+    #[inline]
+    fn narrowphase(&self, body_index: usize, other_body_index: usize) -> Option<(usize, usize)> {
+        let (lhs_body_index, rhs_body_index) = if self.can_detect(body_index)
+            && (body_index < other_body_index || !self.can_detect(other_body_index))
+        {
+            (body_index, other_body_index)
+        } else {
+            (other_body_index, body_index)
+        };
+
+        if !self
+            .collision_layers(lhs_body_index)
+            .collides_with(self.collision_layers(rhs_body_index))
+        {
+            return None;
+        }
+
+        self.bodies[lhs_body_index]
+            .detect_collision(&self.bodies[rhs_body_index])
+            .then_some((lhs_body_index, rhs_body_index))
+    }
+
+    /// Hashes the state of every body, using [Body::hash_state].
+    /// If two solvers give the same hash, their bodies are almost certainly bit for bit the same, so this is a cheap way to check whether replays or lockstep peers have diverged.
+    ///
+    /// The hash is the same on every platform and every version of Rust, unlike [std::collections::hash_map::DefaultHasher].
+    pub fn state_hash(&self) -> u64 {
+        let mut state = StateHasher::default();
+        state.write_u64(self.bodies.len() as u64);
+        for body in &self.bodies {
+            body.hash_state(&mut state);
+        }
+        // Sleeping changes what happens next, so it is part of the state, but only if it is being used.
+        if self.sleeping.is_some() {
+            for body_index in 0..self.bodies.len() {
+                let sleep_state = self
+                    .sleep_states
+                    .get(body_index)
+                    .copied()
+                    .unwrap_or_default();
+                state.write_u64(sleep_state.resting_time.to_bits_u64());
+                state.write_u8(sleep_state.island.is_some() as u8);
+            }
+        }
+        // Only hashed when used, so that solvers without any keep the same hash.
+        if !self.cuboid_arrays.is_empty() {
+            state.write_u64(self.cuboid_arrays.len() as u64);
+            for cuboid_index in 0..self.cuboid_arrays.len() {
+                let particle = self.cuboid_arrays.particle(cuboid_index);
+                bodies::hash_floats(&mut state, &particle.position);
+                bodies::hash_floats(&mut state, &particle.previous_position);
+                bodies::hash_floats(&mut state, &particle.acceleration);
+                bodies::hash_floats(&mut state, &self.cuboid_arrays.half_size(cuboid_index));
+            }
+        }
+        state.finish()
+    }
+
+    /// Records the contacts from this pass's collisions, to be turned into contact events at the end of the update.
+    /// Must be done before responding, as that pushes the bodies apart.
+    #[inline]
+    fn gather_contacts(&mut self) {
+        for &(lhs_body_index, rhs_body_index) in &self.collisions {
+            let (normal, penetration) = self.bodies[lhs_body_index]
+                .get_collision_normal_and_penetration(&self.bodies[rhs_body_index]);
+            self.gathered_contacts.push(
+                Contact {
+                    lhs_body_index,
+                    rhs_body_index,
+                    normal,
+                    penetration,
+                }
+                .ordered(),
+            );
+        }
+    }
+
+    /// Compares the contacts gathered this update with the last update's contacts, to work out the contact events.
+    #[inline]
+    fn update_contact_events(&mut self) {
+        let mut previous_contacts = std::mem::take(&mut self.contacts);
+        let mut contacts = std::mem::take(&mut self.gathered_contacts);
+
+        // Moved bodies might have swapped the order of a contact.
+        for contact in &mut previous_contacts {
+            *contact = contact.ordered();
+        }
+        previous_contacts.sort_unstable_by_key(Contact::body_indices);
+
+        // Each substep can gather the same contact, and we only want the latest.
+        contacts.reverse();
+        contacts.sort_by_key(Contact::body_indices);
+        contacts.dedup_by_key(|contact| contact.body_indices());
+
+        // Both are sorted, so we can walk through them together.
+        let mut previous_contacts = previous_contacts.into_iter().peekable();
+        let mut sleeping_contacts = vec![];
+        for contact in &contacts {
+            while let Some(previous_contact) = previous_contacts
+                .next_if(|previous| previous.body_indices() < contact.body_indices())
+            {
+                self.end_contact(previous_contact, &mut sleeping_contacts);
+            }
+
+            if previous_contacts
+                .next_if(|previous| previous.body_indices() == contact.body_indices())
+                .is_some()
+            {
+                self.contact_events
+                    .push(ContactEvent::ContactPersisted(*contact));
+            } else {
+                self.contact_events
+                    .push(ContactEvent::ContactStarted(*contact));
+            }
+        }
+        for previous_contact in previous_contacts {
+            self.end_contact(previous_contact, &mut sleeping_contacts);
+        }
+
+        // These break the order, but the contacts get sorted again at the start of the next update anyway.
+        contacts.extend(sleeping_contacts);
+        self.contacts = contacts;
+    }
+
+    /// Gives a contact that wasn't found this update a ContactEnded event, unless neither body could have found it, because they are asleep or don't collide with others.
+    /// Those contacts are kept, without any events, until one of the bodies wakes up.
+    #[inline]
+    fn end_contact(&mut self, contact: Contact<T>, sleeping_contacts: &mut Vec<Contact<T>>) {
+        let can_find_contacts = |body_index: usize| {
+            let body = &self.bodies[body_index];
+            !body.is_none() && body.collide_with_others() && !self.is_asleep(body_index)
+        };
+        let is_body = |body_index: usize| !self.bodies[body_index].is_none();
+
+        if self.sleeping.is_some()
+            && is_body(contact.lhs_body_index)
+            && is_body(contact.rhs_body_index)
+            && !can_find_contacts(contact.lhs_body_index)
+            && !can_find_contacts(contact.rhs_body_index)
+        {
+            sleeping_contacts.push(contact);
+        } else {
+            self.contact_events
+                .push(ContactEvent::ContactEnded(contact));
+        }
+    }
+
+    #[inline]
+    fn respond_to_collisions(&mut self, delta_time: T) {
+        for (lhs_body_index, rhs_body_index) in &self.collisions {
+            let lhs_body_index = *lhs_body_index;
+            let rhs_body_index = *rhs_body_index;
+
+            // This code is simple and elegant. By splitting at the largest index, it allows us to safely and &mutably yoink the verlet bodies.
+            if lhs_body_index > rhs_body_index {
+                let (lhs_bodies, rhs_bodies) = self.bodies.split_at_mut(lhs_body_index);
+                //rhs_bodies[0].collide(&mut lhs_bodies[rhs_body_index], rhs_body_index, delta_time);
+                rhs_bodies[0].respond_to_collision(
+                    &mut lhs_bodies[rhs_body_index],
+                    lhs_body_index,
+                    rhs_body_index,
+                    delta_time,
+                );
+            } else {
+                let (lhs_bodies, rhs_bodies) = self.bodies.split_at_mut(rhs_body_index);
+                //lhs_bodies[lhs_body_index].collide(&mut rhs_bodies[0], rhs_body_index, delta_time);
+                lhs_bodies[lhs_body_index].respond_to_collision(
+                    &mut rhs_bodies[0],
+                    lhs_body_index,
+                    rhs_body_index,
+                    delta_time,
+                );
+            }
+        }
+    }
+}
+
+/// Whether the body at the index is asleep. Bodies without a sleep state are awake.
+#[inline]
+fn is_asleep<T>(sleep_states: &[SleepState<T>], body_index: usize) -> bool {
+    sleep_states
+        .get(body_index)
+        .is_some_and(|sleep_state| sleep_state.island.is_some())
+}
+
+/// Finds the body that represents the island the body is in, flattening the path to it along the way.
+#[inline]
+fn find_island_root(island_parents: &mut [usize], body_index: usize) -> usize {
+    let mut root = body_index;
+    while island_parents[root] != root {
+        root = island_parents[root];
+    }
+
+    let mut body_index = body_index;
+    while island_parents[body_index] != root {
+        let parent = island_parents[body_index];
+        island_parents[body_index] = root;
+        body_index = parent;
+    }
+    root
+}
+
+/// A 64 bit FNV-1a hasher, which writes integers as little endian, so that [CpuSolver::state_hash] doesn't depend on the platform.
+struct StateHasher(u64);
+
+impl Default for StateHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StateHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    #[inline]
+    fn write_u16(&mut self, integer: u16) {
+        self.write(&integer.to_le_bytes());
+    }
+
+    #[inline]
+    fn write_u32(&mut self, integer: u32) {
+        self.write(&integer.to_le_bytes());
+    }
+
+    #[inline]
+    fn write_u64(&mut self, integer: u64) {
+        self.write(&integer.to_le_bytes());
+    }
+
+    #[inline]
+    fn write_usize(&mut self, integer: usize) {
+        self.write_u64(integer as u64);
+    }
+}
+
+/// Which layers a body is on, and which layers it collides with.
+/// Two bodies only collide if each of them is on a layer the other collides with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollisionLayers {
+    /// The layers the body is on. One bit per layer.
+    pub layers: u32,
+    /// The layers the body collides with. One bit per layer.
+    pub mask: u32,
+}
+
+impl CollisionLayers {
+    /// On every layer, and collides with every layer.
+    pub const ALL: CollisionLayers = CollisionLayers {
+        layers: u32::MAX,
+        mask: u32::MAX,
+    };
+
+    /// On no layers, and collides with no layers. Nothing will collide with it.
+    pub const NONE: CollisionLayers = CollisionLayers { layers: 0, mask: 0 };
+
+    pub const fn new(layers: u32, mask: u32) -> CollisionLayers {
+        CollisionLayers { layers, mask }
+    }
+
+    #[inline]
+    pub const fn collides_with(self, other: CollisionLayers) -> bool {
+        self.mask & other.layers != 0 && other.mask & self.layers != 0
+    }
+}
+
+impl Default for CollisionLayers {
+    fn default() -> Self {
+        CollisionLayers::ALL
+    }
+}
+
+/// Where a raycast or shape cast hit a body.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit<T>
+where
+    T: math::Float,
+{
+    pub body_index: usize,
+    /// Where the ray hit. For shape casts, this is where the centre of the cast aabb is when it hits.
+    pub point: [T; 3],
+    /// The normal of the body's surface that was hit.
+    pub normal: [T; 3],
+    /// How far along the ray the hit was.
+    pub distance: T,
+}
+
+/// Keeps the contacts pointing at the right bodies, removing any with a deleted body.
+fn apply_body_index_change_to_contacts<T: math::Float>(
+    contacts: &mut Vec<Contact<T>>,
+    body_index_change: BodyIndexChange,
+) {
+    match body_index_change {
+        BodyIndexChange::Deleted(body_index) => contacts.retain(|contact| {
+            contact.lhs_body_index != body_index && contact.rhs_body_index != body_index
+        }),
+        BodyIndexChange::Moved { from, to } => {
+            for contact in contacts {
+                if contact.lhs_body_index == from {
+                    contact.lhs_body_index = to;
+                }
+                if contact.rhs_body_index == from {
+                    contact.rhs_body_index = to;
+                }
+            }
+        }
+    }
+}
+
+/// Two bodies touching. lhs_body_index is always the smaller index.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Contact<T>
+where
+    T: math::Float,
+{
+    pub lhs_body_index: usize,
+    pub rhs_body_index: usize,
+    /// Points from the lhs body towards the rhs body.
+    pub normal: [T; 3],
+    /// How far the bodies are inside each other.
+    pub penetration: T,
+}
+
+impl<T> Contact<T>
+where
+    T: math::Float,
+{
+    #[inline]
+    fn body_indices(&self) -> (usize, usize) {
+        (self.lhs_body_index, self.rhs_body_index)
+    }
+
+    /// Swaps the bodies around if needed, so that lhs_body_index is the smaller index.
+    #[inline]
+    fn ordered(self) -> Self {
+        if self.lhs_body_index <= self.rhs_body_index {
+            return self;
+        }
+        Contact {
+            lhs_body_index: self.rhs_body_index,
+            rhs_body_index: self.lhs_body_index,
+            normal: math::neg_3d(self.normal),
+            penetration: self.penetration,
+        }
+    }
+}
+
+/// How a contact between two bodies changed during an update.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ContactEvent<T>
+where
+    T: math::Float,
+{
+    /// The bodies weren't touching last update, but are now.
+    ContactStarted(Contact<T>),
+    /// The bodies were touching last update, and still are.
+    ContactPersisted(Contact<T>),
+    /// The bodies were touching last update, but aren't now. The contact is how they were touching last update.
+    ContactEnded(Contact<T>),
+}
+
+/// What happened to a body's index, when the solver moved or deleted it.
+/// These must be applied in order, as a later change can refer to an index an earlier change moved a body to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BodyIndexChange {
+    /// The body at this index was deleted.
+    Deleted(usize),
+    /// The body at from is now at to.
+    Moved { from: usize, to: usize },
+}
+
+/// A set of body indices that can be emptied without going through every body, so that it can be reused by lots of small casts, like sweeps.
+#[derive(Debug, Default)]
+struct BodyIndexSet {
+    /// The generation each body index was last inserted in. Anything from an older generation isn't in the set.
+    generations: Vec<u32>,
+    generation: u32,
+}
+
+impl BodyIndexSet {
+    /// Empties the set, and makes room for every body index below body_count.
+    #[inline]
+    fn reset(&mut self, body_count: usize) {
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            // Old generations would come round again, so they are forgotten.
+            self.generations.fill(0);
+            self.generation = 1;
+        }
+        if self.generations.len() < body_count {
+            self.generations.resize(body_count, 0);
+        }
+    }
+
+    /// Adds the body index, returning whether it wasn't already in the set.
+    #[inline]
+    fn insert(&mut self, body_index: usize) -> bool {
+        let generation = &mut self.generations[body_index];
+        let inserted = *generation != self.generation;
+        *generation = self.generation;
+        inserted
+    }
+}
+
+/// Buffers a thread reuses between sweeps, so that sweeping doesn't allocate for every body.
+#[derive(Debug, Default)]
+struct SweepScratch {
+    tested_body_indices: BodyIndexSet,
+    skipped_body_indices: Vec<usize>,
+}
+
+/// A handle to a body in a [CpuSolver].
+/// Unlike a body index, it doesn't change when other bodies are moved or removed, and it knows when the body it pointed to has been removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BodyHandle {
+    slot: usize,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BodySlot {
+    /// Incremented every time the slot is freed, so old handles to the slot can be detected.
+    generation: u32,
+    body_index: Option<usize>,
+}
+
+/// Keeps track of which body each handle points to.
+/// Slots are reused once their body has been removed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BodyHandles {
+    slots: Vec<BodySlot>,
+    free_slots: Vec<usize>,
+    /// The slot of each body, indexed by body index. Bodies pushed directly into bodies don't have one.
+    slot_of_body: Vec<Option<usize>>,
+}
+
+impl BodyHandles {
+    fn allocate(&mut self, body_index: usize) -> BodyHandle {
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot].body_index = Some(body_index);
+                slot
+            }
+            None => {
+                self.slots.push(BodySlot {
+                    generation: 0,
+                    body_index: Some(body_index),
+                });
+                self.slots.len() - 1
+            }
+        };
+
+        if self.slot_of_body.len() <= body_index {
+            self.slot_of_body.resize(body_index + 1, None);
+        }
+        self.slot_of_body[body_index] = Some(slot);
+
+        BodyHandle {
+            slot,
+            generation: self.slots[slot].generation,
+        }
+    }
+
+    fn body_index(&self, handle: BodyHandle) -> Option<usize> {
+        let slot = self.slots.get(handle.slot)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.body_index
+    }
+
+    fn handle(&self, body_index: usize) -> Option<BodyHandle> {
+        let slot = (*self.slot_of_body.get(body_index)?)?;
+        Some(BodyHandle {
+            slot,
+            generation: self.slots[slot].generation,
+        })
+    }
+
+    fn apply_body_index_change(&mut self, body_index_change: BodyIndexChange) {
+        match body_index_change {
+            BodyIndexChange::Deleted(body_index) => {
+                let Some(slot) = self.slot_of_body.get_mut(body_index).and_then(Option::take)
+                else {
+                    return;
+                };
+
+                self.slots[slot].body_index = None;
+                self.slots[slot].generation = self.slots[slot].generation.wrapping_add(1);
+                self.free_slots.push(slot);
+            }
+            BodyIndexChange::Moved { from, to } => {
+                let Some(slot) = self.slot_of_body.get_mut(from).and_then(Option::take) else {
+                    return;
+                };
+
+                if self.slot_of_body.len() <= to {
+                    self.slot_of_body.resize(to + 1, None);
+                }
+                self.slot_of_body[to] = Some(slot);
+                self.slots[slot].body_index = Some(to);
+            }
+        }
+    }
+}
+
+/// If a body is outside of the grid, what should it do?
+/// Both delete behaviours record what happened to the body indices in [CpuSolver::body_index_changes].
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize, B: 'static",
+    deserialize = "T: Deserialize<'de>, B: 'static"
+))]
+pub enum OutsideOfGridBoundsBehaviour<T: math::Float, B: Body<T>> {
+    SwapDeleteParticle,
+    DeleteParticle,
+    PutParticleInBounds,
+    TeleportParticleToPosition([T; 3]),
+    ContinueUpdating,
+    /// Saved as the name it was registered under, so it must be registered with [super::function_registry::register_function].
+    Custom(#[serde(with = "super::function_registry::serde_function")] fn(usize, &mut B)),
+    // replace with body?
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::physics::physics_3d::bodies::{
+        CollisionRecorderCuboid, CommonBody, ImmovableCuboid,
+    };
+    use crate::physics::physics_3d::function_registry::{
+        register_function, registered_function_name,
+    };
+    use crate::physics::physics_3d::material::PhysicsMaterial;
+    use crate::physics::physics_3d::verlet;
+    use crate::physics::physics_3d::verlet::bodies::Cuboid;
+    use crate::physics::physics_3d::verlet::Particle;
+
+    use crate::math::{Fixed, Float};
+
+    use super::*;
+    use rand::thread_rng;
+    use rand::Rng;
+    use test::Bencher;
+
+    fn create_test_solver<T: Float + rand::distributions::uniform::SampleUniform>(
+        amount: usize,
+        gravity: T,
+    ) -> CpuSolver<T, CommonBody<T>> {
+        let mut verlet_bodies = Vec::with_capacity(amount);
+        let mut rng = thread_rng();
+
+        for _ in 0..amount {
+            verlet_bodies
+                .push_within_capacity(CommonBody::Cuboid(Cuboid {
+                    particle: Particle::from_position([
+                        rng.gen_range(T::from_f64(-50.0)..T::from_f64(50.0)),
+                        rng.gen_range(T::from_f64(-50.0)..T::from_f64(50.0)),
+                        rng.gen_range(T::from_f64(-50.0)..T::from_f64(50.0)),
+                    ]),
+
+                    half_size: [T::from_f64(0.5); 3],
+                    material: PhysicsMaterial::default(),
+                    continuous_collision_detection: false,
+                }))
+                .unwrap();
+        }
+
+        create_solver(gravity, verlet_bodies)
+    }
+
+    /// The solver [create_test_solver] uses, with the bodies given.
+    fn create_solver<T: Float>(
+        gravity: T,
+        bodies: Vec<CommonBody<T>>,
+    ) -> CpuSolver<T, CommonBody<T>> {
+        CpuSolver::new(Config {
+            gravity: [T::ZERO, gravity, T::ZERO],
+            dampening: [T::from_f64(0.8), T::ONE, T::from_f64(0.8)],
+            grid_size: [10, 10, 10],
+            grid_origin: [T::from_f64(-50.0), T::from_f64(-50.0), T::from_f64(-50.0)],
+            cell_size: [10, 10, 10],
+            outside_of_grid_bounds_behaviour: OutsideOfGridBoundsBehaviour::ContinueUpdating,
+            broadphase: BroadphaseMethod::UniformGrid,
+            steps: Steps::default(),
+            sleeping: None,
+            deterministic: false,
+            bodies,
+            constraints: vec![],
+            collision_layers: vec![],
+            cuboid_arrays: CuboidArrays::default(),
+        })
+    }
+
+    /// Adds an immovable floor that is much bigger than everything else, which cell based broadphases have to put in a huge amount of cells.
+    fn add_huge_floor<T: Float>(solver: &mut CpuSolver<T, CommonBody<T>>) {
+        solver.insert(CommonBody::ImmovableCuboid(ImmovableCuboid {
+            aabb: AabbCentredOrigin {
+                position: [T::ZERO, T::from_f64(50.0), T::ZERO],
+                half_size: [T::from_f64(500.0), T::from_f64(0.5), T::from_f64(500.0)],
+            },
+            material: PhysicsMaterial::default(),
+        }));
+    }
+
+    // (10,22), (1000,2_468), (5000,12_518), (10_000,28_238), (20_000,54_449), (50_000,171_110), (100_000,672_048)
+    #[bench]
+    fn bench_cpu_solver_serial_update_100_000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver::<f32>(100_000, 0.0);
+        b.iter(|| {
+            solver.serial_update_bodies(0.04);
+        })
+    }
+
+    // (10,7_581), (1000,21_242), (5000,32_108), (10_000,69_903), (20_000,171_272), (50_000,385_621), (100_000,821_128)
+    #[bench]
+    fn bench_cpu_solver_update_100_000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver::<f32>(100_000, 0.0);
+        b.iter(|| {
+            solver.update_bodies(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_update_100_000_cuboid_arrays(b: &mut Bencher) {
+        let mut solver = create_test_solver::<f32>(0, 0.0);
+        let mut rng = thread_rng();
+        for _ in 0..100_000 {
+            solver.cuboid_arrays.push(Cuboid {
+                particle: Particle::from_position([
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                    rng.gen_range(-50.0..50.0),
+                ]),
+                half_size: [0.5; 3],
+                material: PhysicsMaterial::default(),
+                continuous_collision_detection: false,
+            });
+        }
+        b.iter(|| {
+            solver
+                .cuboid_arrays
+                .update(solver.gravity, solver.dampening, 0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_spatial_hash_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        solver.broadphase = CommonBroadphase::SpatialHash(SpatialHash::new([10.0; 3]));
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_sweep_and_prune_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        solver.broadphase = CommonBroadphase::SweepAndPrune(SweepAndPrune::new());
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_bvh_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        solver.broadphase = CommonBroadphase::Bvh(Bvh::new(0.1));
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_spatial_hash_huge_floor_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        add_huge_floor(&mut solver);
+        solver.broadphase = CommonBroadphase::SpatialHash(SpatialHash::new([10.0; 3]));
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_sweep_and_prune_huge_floor_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        add_huge_floor(&mut solver);
+        solver.broadphase = CommonBroadphase::SweepAndPrune(SweepAndPrune::new());
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_bvh_huge_floor_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        add_huge_floor(&mut solver);
+        solver.broadphase = CommonBroadphase::Bvh(Bvh::new(0.1));
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_30000_sleeping_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        solver.sleeping = Some(Sleeping::default());
+        // Nothing is moving, so everything will be asleep after this.
+        for _ in 0..20 {
+            solver.update(0.04);
+        }
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_deterministic_30000_particles(b: &mut Bencher) {
+        let mut solver = create_test_solver(30000, 0.0);
+        solver.deterministic = true;
+        b.iter(|| {
+            solver.update(0.04);
+        })
+    }
+
+    #[bench]
+    fn bench_cpu_solver_state_hash_100_000_particles(b: &mut Bencher) {
+        let solver = create_test_solver::<f32>(100_000, 0.0);
+        b.iter(|| solver.state_hash())
+    }
+
+    #[bench]
+    fn bench_cpu_solver_1000_none_particles(b: &mut Bencher) {
+        let mut verlet_bodies = Vec::with_capacity(1000);
+
+        for _ in 0..1000 {
+            verlet_bodies.push(CommonBody::None);
+        }
 
         let mut solver = CpuSolver::new(Config {
             gravity: [0.0, 50.0, 0.0],
@@ -522,10 +2132,736 @@ mod tests {
             grid_origin: [-50.0, -50.0, -50.0],
             cell_size: [10, 10, 10],
             outside_of_grid_bounds_behaviour: OutsideOfGridBoundsBehaviour::ContinueUpdating,
+            broadphase: BroadphaseMethod::UniformGrid,
+            steps: Steps::default(),
+            sleeping: None,
+            deterministic: false,
             bodies: verlet_bodies,
+            constraints: vec![],
+            collision_layers: vec![],
+            cuboid_arrays: CuboidArrays::default(),
         });
         b.iter(|| {
             solver.update(0.04);
         })
     }
+
+    fn create_cuboid<T: Float>(position: [T; 3]) -> CommonBody<T> {
+        CommonBody::Cuboid(Cuboid {
+            particle: Particle::from_position(position),
+            half_size: [T::from_f64(0.5); 3],
+            material: PhysicsMaterial::default(),
+            continuous_collision_detection: false,
+        })
+    }
+
+    /// An immovable floor with its top at y 9.5.
+    fn create_floor<T: Float>() -> CommonBody<T> {
+        CommonBody::ImmovableCuboid(ImmovableCuboid {
+            aabb: AabbCentredOrigin {
+                position: [T::ZERO, T::from_f64(10.0), T::ZERO],
+                half_size: [T::from_f64(20.0), T::from_f64(0.5), T::from_f64(20.0)],
+            },
+            material: PhysicsMaterial::default(),
+        })
+    }
+
+    /// A pile of cuboids falling onto a floor, so that there are plenty of collisions. Unlike [create_test_solver], it is the same every time.
+    fn create_pile_solver<T: Float>() -> CpuSolver<T, CommonBody<T>> {
+        let mut solver = create_solver(T::from_f64(10.0), vec![]);
+        solver.insert(create_floor());
+        for i in 0..512 {
+            // Offsets every cuboid slightly differently, so the pile doesn't stay perfectly stacked.
+            let offset = (i * 7919 % 100) as f64 * 0.002;
+            solver.insert(create_cuboid([
+                T::from_f64((i % 8) as f64 * 1.05 + offset),
+                T::from_f64((i / 8 % 8) as f64 * 1.05 - offset),
+                T::from_f64((i / 64) as f64 * 1.05 + offset),
+            ]));
+        }
+        solver
+    }
+
+    /// Runs the pile on its own thread pool, and gives the state hash at the end.
+    fn pile_state_hash<T: Float>(deterministic: bool, threads: usize) -> u64 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| {
+                let mut solver = create_pile_solver::<T>();
+                solver.deterministic = deterministic;
+                let initial_state_hash = solver.state_hash();
+                for _ in 0..100 {
+                    solver.update(T::from_f64(0.04));
+                }
+                assert_ne!(solver.state_hash(), initial_state_hash);
+                solver.state_hash()
+            })
+    }
+
+    #[test]
+    fn same_state_hash_on_any_amount_of_threads() {
+        let state_hash = pile_state_hash::<f32>(true, 1);
+        assert_eq!(pile_state_hash::<f32>(true, 4), state_hash);
+        assert_eq!(pile_state_hash::<f32>(false, 1), state_hash);
+        assert_eq!(pile_state_hash::<f32>(false, 4), state_hash);
+    }
+
+    #[test]
+    fn fixed_point_is_bit_exact() {
+        let state_hash = pile_state_hash::<Fixed>(true, 1);
+        assert_eq!(pile_state_hash::<Fixed>(false, 4), state_hash);
+        // Fixed point maths is plain integer maths, so this is the same on every platform.
+        assert_eq!(state_hash, 10478037862201704574);
+    }
+
+    #[test]
+    fn fixed_point_cuboid_lands_on_the_floor() {
+        let mut solver = create_pile_solver::<Fixed>();
+        solver.bodies.truncate(2);
+        for _ in 0..200 {
+            solver.update(Fixed::from_f64(0.04));
+        }
+        let position = solver.bodies[1].position_unchecked();
+        // The floor's top is at 9.5, so the cuboid should rest with its centre at 9.
+        assert!((position[1].to_f64() - 9.0).abs() < 0.01, "{position:?}");
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        fn teleport_to_origin(_body_index: usize, body: &mut CommonBody<f32>) {
+            body.set_position_unchecked([0.0; 3]);
+        }
+
+        let mut solver = create_pile_solver::<f32>();
+        solver.outside_of_grid_bounds_behaviour =
+            OutsideOfGridBoundsBehaviour::Custom(teleport_to_origin);
+        let outside_body = solver.insert(CommonBody::Cuboid(Cuboid {
+            particle: Particle::from_position([0.0, -100.0, 0.0]),
+            half_size: [0.5; 3],
+            material: PhysicsMaterial::default(),
+            continuous_collision_detection: false,
+        }));
+
+        let error = serde_json::to_string(&solver).unwrap_err();
+        assert!(error.to_string().contains("hasn't been registered"));
+
+        register_function::<fn(usize, &mut CommonBody<f32>)>(
+            "serde_round_trip_teleport_to_origin",
+            teleport_to_origin,
+        );
+        for _ in 0..10 {
+            solver.update(0.04);
+        }
+
+        let json = serde_json::to_string(&solver).unwrap();
+        let mut deserialized_solver: CpuSolver<f32, CommonBody<f32>> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized_solver.state_hash(), solver.state_hash());
+        assert!(matches!(
+            deserialized_solver.outside_of_grid_bounds_behaviour,
+            OutsideOfGridBoundsBehaviour::Custom(function)
+                if registered_function_name(function) == Some("serde_round_trip_teleport_to_origin")
+        ));
+
+        // Puts the body back outside, so the callback has to run after deserializing too.
+        deserialized_solver
+            .get_mut(outside_body)
+            .unwrap()
+            .set_position_unchecked([0.0, -100.0, 0.0]);
+        solver
+            .get_mut(outside_body)
+            .unwrap()
+            .set_position_unchecked([0.0, -100.0, 0.0]);
+        for _ in 0..50 {
+            solver.update(0.04);
+            deserialized_solver.update(0.04);
+        }
+        assert_eq!(deserialized_solver.state_hash(), solver.state_hash());
+        assert!(
+            deserialized_solver
+                .get(outside_body)
+                .unwrap()
+                .position_unchecked()[1]
+                > -50.0
+        );
+
+        let unregistered_json = json.replace(
+            "serde_round_trip_teleport_to_origin",
+            "serde_round_trip_never_registered",
+        );
+        let error = serde_json::from_str::<CpuSolver<f32, CommonBody<f32>>>(&unregistered_json)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("has been registered as"));
+    }
+
+    #[test]
+    fn continuous_collision_detection_stops_capsules_cylinders_and_players_tunnelling() {
+        // Falls 6 units in a substep, which is further than the body is tall.
+        let falling_body = |continuous_collision_detection: bool| {
+            let mut particle = Particle::from_position([0.0, -3.0, 0.0]);
+            particle.previous_position = [0.0, -9.0, 0.0];
+            [
+                CommonBody::Player(verlet::bodies::Player {
+                    particle,
+                    mass: 1.0,
+                    material: PhysicsMaterial::default(),
+                    half_size: [0.5, 1.0, 0.5],
+                    dampening: [1.0; 3],
+                    grounded: false,
+                    continuous_collision_detection,
+                }),
+                CommonBody::Capsule(verlet::bodies::Capsule {
+                    particle,
+                    radius: 0.5,
+                    half_height: 0.5,
+                    material: PhysicsMaterial::default(),
+                    continuous_collision_detection,
+                }),
+                CommonBody::Cylinder(verlet::bodies::Cylinder {
+                    particle,
+                    radius: 0.5,
+                    half_height: 1.0,
+                    material: PhysicsMaterial::default(),
+                    continuous_collision_detection,
+                }),
+            ]
+        };
+
+        for continuous_collision_detection in [false, true] {
+            for body in falling_body(continuous_collision_detection) {
+                let mut solver = create_test_solver::<f32>(0, 0.0);
+                solver.insert(CommonBody::ImmovableCuboid(ImmovableCuboid {
+                    aabb: AabbCentredOrigin {
+                        position: [0.0; 3],
+                        half_size: [5.0, 0.05, 5.0],
+                    },
+                    material: PhysicsMaterial::default(),
+                }));
+                let body = solver.insert(body);
+
+                solver.update(0.04);
+
+                let tunnelled = solver.get(body).unwrap().position_unchecked()[1] > 0.0;
+                assert_eq!(tunnelled, !continuous_collision_detection);
+            }
+        }
+    }
+
+    #[test]
+    fn player_stands_on_bodies_with_smaller_indices() {
+        let bodies_to_stand_on = [
+            CommonBody::Cuboid(Cuboid {
+                particle: Particle::from_position([0.0, 9.0, 0.0]),
+                half_size: [0.5; 3],
+                material: PhysicsMaterial::default(),
+                continuous_collision_detection: false,
+            }),
+            CommonBody::Sphere(verlet::bodies::Sphere {
+                particle: Particle::from_position([0.0, 9.0, 0.0]),
+                radius: 0.5,
+                material: PhysicsMaterial::default(),
+                continuous_collision_detection: false,
+            }),
+        ];
+
+        for body_to_stand_on in bodies_to_stand_on {
+            let mut solver = create_solver(10.0_f32, vec![]);
+            solver.insert(body_to_stand_on);
+            solver.insert(CommonBody::ImmovableCuboid(ImmovableCuboid {
+                aabb: AabbCentredOrigin {
+                    position: [0.0, 10.0, 0.0],
+                    half_size: [5.0, 0.5, 5.0],
+                },
+                material: PhysicsMaterial::default(),
+            }));
+            let player = solver.insert(CommonBody::Player(verlet::bodies::Player {
+                particle: Particle::from_position([0.0, 7.6, 0.0]),
+                mass: 1.0,
+                material: PhysicsMaterial::default(),
+                half_size: [0.5, 1.0, 0.5],
+                dampening: [0.8, 1.0, 0.8],
+                grounded: false,
+                continuous_collision_detection: false,
+            }));
+
+            // The body is pushed into the floor and back out every other update, so the player is only grounded every other update.
+            let mut grounded_updates = 0;
+            for _ in 0..50 {
+                solver.update(0.04);
+                if let Some(CommonBody::Player(player)) = solver.get(player) {
+                    grounded_updates += player.grounded as usize;
+                }
+            }
+            assert!(grounded_updates >= 20, "{grounded_updates}");
+
+            // The body's top is at 8.5, so the player should be standing with its centre at 7.5, rather than having fallen through onto the floor.
+            let player_position = solver.get(player).unwrap().position_unchecked();
+            assert!(
+                (player_position[1] - 7.5).abs() < 0.1,
+                "{player_position:?}"
+            );
+        }
+    }
+
+    /// Puts the body next to a collision recorder, and checks that the recorder records it without pushing it.
+    fn assert_recorded_by_collision_recorder_cuboid(body: CommonBody<f32>) {
+        fn save_collision(_body: &mut CommonBody<f32>) -> bool {
+            true
+        }
+
+        let mut solver = create_test_solver::<f32>(0, 0.0);
+        let recorder = solver.insert(CommonBody::CollisionRecorderCuboid(
+            CollisionRecorderCuboid {
+                aabb: AabbCentredOrigin {
+                    position: [0.0; 3],
+                    half_size: [1.0; 3],
+                },
+                save_collision,
+                stored_collider_index: None,
+            },
+        ));
+        let position = body.position_unchecked();
+        let body = solver.insert(body);
+
+        solver.update(0.04);
+
+        let Some(CommonBody::CollisionRecorderCuboid(recorder)) = solver.get(recorder) else {
+            panic!("the recorder should still be there");
+        };
+        assert_eq!(recorder.stored_collider_index, solver.body_index(body));
+        assert_eq!(solver.get(body).unwrap().position_unchecked(), position);
+    }
+
+    #[test]
+    fn sphere_is_recorded_by_collision_recorder_cuboid() {
+        assert_recorded_by_collision_recorder_cuboid(CommonBody::Sphere(verlet::bodies::Sphere {
+            particle: Particle::from_position([1.2, 0.0, 0.0]),
+            radius: 0.5,
+            material: PhysicsMaterial::default(),
+            continuous_collision_detection: false,
+        }));
+    }
+
+    #[test]
+    fn capsule_is_recorded_by_collision_recorder_cuboid() {
+        assert_recorded_by_collision_recorder_cuboid(CommonBody::Capsule(
+            verlet::bodies::Capsule {
+                particle: Particle::from_position([1.2, 0.0, 0.0]),
+                radius: 0.5,
+                half_height: 0.5,
+                material: PhysicsMaterial::default(),
+                continuous_collision_detection: false,
+            },
+        ));
+    }
+
+    #[test]
+    fn body_moved_into_a_removed_sleeping_body_index_stays_awake() {
+        let mut solver = create_solver(10.0_f32, vec![]);
+        solver.sleeping = Some(Sleeping::default());
+        solver.insert(create_floor());
+        let sleeping_bodies: Vec<_> = (0..4)
+            .map(|i| solver.insert(create_cuboid([i as f32 * 2.0 - 3.0, 9.0, 0.0])))
+            .collect();
+        for _ in 0..50 {
+            solver.update(0.04);
+        }
+        assert!(solver.is_asleep(solver.body_index(sleeping_bodies[0]).unwrap()));
+
+        let falling_body = solver.insert(create_cuboid([0.0, 0.0, 5.0]));
+        solver.remove(sleeping_bodies[0]);
+        let falling_body_index = solver.body_index(falling_body).unwrap();
+        assert_eq!(falling_body_index, 1);
+        assert!(!solver.is_asleep(falling_body_index));
+
+        solver.update(0.04);
+        assert!(solver.get(falling_body).unwrap().position_unchecked()[1] > 0.0);
+    }
+
+    #[test]
+    fn collision_layers_of_a_removed_body_dont_leak_into_the_moved_body() {
+        const PROJECTILES: CollisionLayers = CollisionLayers::new(0b10, 0b01);
+
+        let mut solver = create_solver(0.0_f32, vec![]);
+        let bodies: Vec<_> = (0..5)
+            .map(|i| solver.insert(create_cuboid([i as f32 * 2.0, 0.0, 0.0])))
+            .collect();
+        solver.set_collision_layers(1, PROJECTILES);
+
+        solver.remove(bodies[1]);
+
+        let moved_body_index = solver.body_index(bodies[4]).unwrap();
+        assert_eq!(moved_body_index, 1);
+        assert_eq!(
+            solver.collision_layers(moved_body_index),
+            CollisionLayers::ALL
+        );
+    }
+
+    #[test]
+    fn collision_layers_filter_pairs() {
+        const PLAYERS: CollisionLayers = CollisionLayers::new(0b01, 0b11);
+        const PROJECTILES: CollisionLayers = CollisionLayers::new(0b10, 0b01);
+
+        // Projectiles hit players, but not each other.
+        for (lhs_collision_layers, rhs_collision_layers, collide) in [
+            (PLAYERS, PLAYERS, true),
+            (PLAYERS, PROJECTILES, true),
+            (PROJECTILES, PROJECTILES, false),
+            (PLAYERS, CollisionLayers::NONE, false),
+        ] {
+            let mut solver = create_solver(0.0_f32, vec![]);
+            let lhs = solver.insert(create_cuboid([0.0, 0.0, 0.0]));
+            let rhs = solver.insert(create_cuboid([0.5, 0.0, 0.0]));
+            solver.set_collision_layers(0, lhs_collision_layers);
+            solver.set_collision_layers(1, rhs_collision_layers);
+
+            solver.update(0.04);
+
+            let pushed_apart = solver.get(rhs).unwrap().position_unchecked()[0]
+                - solver.get(lhs).unwrap().position_unchecked()[0]
+                > 0.5;
+            assert_eq!(pushed_apart, collide);
+        }
+    }
+
+    #[test]
+    fn handles_follow_moved_bodies_and_go_stale_when_removed() {
+        let mut solver = create_solver(0.0_f32, vec![]);
+        let a = solver.insert(create_cuboid([0.0, 0.0, 0.0]));
+        let _b = solver.insert(create_cuboid([2.0, 0.0, 0.0]));
+        let c = solver.insert(create_cuboid([4.0, 0.0, 0.0]));
+
+        assert!(solver.remove(a).is_some());
+        assert!(solver.get(a).is_none());
+        assert!(solver.remove(a).is_none());
+
+        // C was the last body, so it was moved into A's index.
+        assert_eq!(solver.body_index(c), Some(0));
+        assert_eq!(solver.get(c).unwrap().position_unchecked(), [4.0, 0.0, 0.0]);
+
+        let d = solver.insert(create_cuboid([6.0, 0.0, 0.0]));
+        assert_eq!(d.slot, a.slot);
+        assert!(d.generation > a.generation);
+        assert!(solver.get(a).is_none());
+        assert!(!solver.contains(a));
+        assert_eq!(solver.get(d).unwrap().position_unchecked(), [6.0, 0.0, 0.0]);
+    }
+
+    /// Puts every other body outside of the grid, and checks where the rest end up after the outside of grid bounds behaviour deletes them.
+    fn assert_outside_of_grid_bounds_deletion(
+        outside_of_grid_bounds_behaviour: OutsideOfGridBoundsBehaviour<f32, CommonBody<f32>>,
+        expected_body_index_changes: &[BodyIndexChange],
+    ) {
+        let mut solver = create_solver(0.0_f32, vec![]);
+        solver.outside_of_grid_bounds_behaviour = outside_of_grid_bounds_behaviour;
+        let positions: Vec<_> = (0..6)
+            .map(|i| {
+                let outside_of_grid_bounds = i % 2 == 1;
+                [
+                    if outside_of_grid_bounds { 100.0 } else { 0.0 },
+                    0.0,
+                    i as f32 * 2.0,
+                ]
+            })
+            .collect();
+        let handles: Vec<_> = positions
+            .iter()
+            .map(|&position| solver.insert(create_cuboid(position)))
+            .collect();
+
+        solver.update(0.04);
+
+        assert_eq!(solver.body_index_changes, expected_body_index_changes);
+        assert_eq!(solver.bodies.len(), 3);
+        for (old_body_index, (handle, position)) in handles.iter().zip(&positions).enumerate() {
+            let updated_body_index = solver.updated_body_index(old_body_index);
+            assert_eq!(updated_body_index, solver.body_index(*handle));
+            if position[0] == 100.0 {
+                assert_eq!(updated_body_index, None);
+            } else {
+                let updated_body_index = updated_body_index.unwrap();
+                assert_eq!(
+                    solver.bodies[updated_body_index].position_unchecked(),
+                    *position
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn swap_delete_particle_records_body_index_changes() {
+        assert_outside_of_grid_bounds_deletion(
+            OutsideOfGridBoundsBehaviour::SwapDeleteParticle,
+            &[
+                BodyIndexChange::Deleted(1),
+                BodyIndexChange::Moved { from: 5, to: 1 },
+                BodyIndexChange::Deleted(1),
+                BodyIndexChange::Moved { from: 4, to: 1 },
+                BodyIndexChange::Deleted(3),
+            ],
+        );
+    }
+
+    #[test]
+    fn delete_particle_records_body_index_changes() {
+        // Deletions use the indices from before the bodies were shifted down, in between the moves.
+        assert_outside_of_grid_bounds_deletion(
+            OutsideOfGridBoundsBehaviour::DeleteParticle,
+            &[
+                BodyIndexChange::Deleted(1),
+                BodyIndexChange::Moved { from: 2, to: 1 },
+                BodyIndexChange::Deleted(3),
+                BodyIndexChange::Moved { from: 4, to: 2 },
+                BodyIndexChange::Deleted(5),
+            ],
+        );
+    }
+
+    /// A solver using each broadphase, with a body above the origin, one below it, and one off to the side, ready to be queried.
+    fn create_raycast_solvers() -> Vec<CpuSolver<f32, CommonBody<f32>>> {
+        let broadphases = [
+            CommonBroadphase::UniformGrid(UniformGrid::new([10; 3], [10; 3], [-50.0; 3])),
+            CommonBroadphase::SpatialHash(SpatialHash::new([10.0; 3])),
+            CommonBroadphase::SweepAndPrune(SweepAndPrune::new()),
+            CommonBroadphase::Bvh(Bvh::new(0.1)),
+        ];
+        broadphases
+            .into_iter()
+            .map(|broadphase| {
+                let mut solver = create_solver(0.0, vec![]);
+                solver.broadphase = broadphase;
+                solver.insert(create_cuboid([0.0, -5.0, 0.0]));
+                solver.insert(create_cuboid([0.0, 5.0, 0.0]));
+                solver.insert(create_cuboid([10.0, 0.0, 0.0]));
+                solver.update(0.04);
+                solver
+            })
+            .collect()
+    }
+
+    #[test]
+    fn raycast_finds_the_closest_hit_the_filter_allows() {
+        for solver in create_raycast_solvers() {
+            let hit = solver
+                .raycast([0.0, -20.0, 0.0], [0.0, 2.0, 0.0], 100.0, |_, _| true)
+                .unwrap();
+            assert_eq!(hit.body_index, 0);
+            assert_eq!(hit.distance, 14.5);
+            assert_eq!(hit.point, [0.0, -5.5, 0.0]);
+            assert_eq!(hit.normal, [0.0, -1.0, 0.0]);
+
+            let hit = solver
+                .raycast(
+                    [0.0, -20.0, 0.0],
+                    [0.0, 1.0, 0.0],
+                    100.0,
+                    |body_index, _| body_index != 0,
+                )
+                .unwrap();
+            assert_eq!(hit.body_index, 1);
+            assert_eq!(hit.distance, 24.5);
+
+            assert!(solver
+                .raycast([0.0, -20.0, 0.0], [0.0, 1.0, 0.0], 10.0, |_, _| true)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn raycast_all_sorts_hits_from_closest_to_furthest() {
+        for solver in create_raycast_solvers() {
+            let hits = solver.raycast_all([0.0, 20.0, 0.0], [0.0, -1.0, 0.0], 100.0, |_, _| true);
+            let hits: Vec<_> = hits
+                .iter()
+                .map(|hit| (hit.body_index, hit.distance))
+                .collect();
+            assert_eq!(hits, [(1, 14.5), (0, 24.5)]);
+        }
+    }
+
+    #[test]
+    fn raycast_starting_inside_a_body_hits_it_straight_away() {
+        for solver in create_raycast_solvers() {
+            let hit = solver
+                .raycast([0.0, -5.0, 0.0], [0.0, 1.0, 0.0], 100.0, |_, _| true)
+                .unwrap();
+            assert_eq!(hit.body_index, 0);
+            assert_eq!(hit.distance, 0.0);
+        }
+    }
+
+    #[test]
+    fn raycast_into_and_out_of_the_grid() {
+        for solver in create_raycast_solvers() {
+            // Leaves the grid without hitting anything.
+            assert!(solver
+                .raycast([0.0, 5.0, -40.0], [0.0, 0.0, -1.0], 100.0, |_, _| true)
+                .is_none());
+
+            // Starts outside the grid, and enters it.
+            let hit = solver
+                .raycast([0.0, 5.0, -100.0], [0.0, 0.0, 1.0], 200.0, |_, _| true)
+                .unwrap();
+            assert_eq!(hit.body_index, 1);
+            assert_eq!(hit.distance, 99.5);
+        }
+    }
+
+    #[test]
+    fn shape_cast_hits_where_the_aabb_touches_the_body() {
+        for solver in create_raycast_solvers() {
+            let hit = solver
+                .shape_cast(
+                    [0.0, -20.0, 0.0],
+                    [0.5; 3],
+                    [0.0, 1.0, 0.0],
+                    100.0,
+                    |_, _| true,
+                )
+                .unwrap();
+            assert_eq!(hit.body_index, 0);
+            assert_eq!(hit.distance, 14.0);
+            assert_eq!(hit.point, [0.0, -6.0, 0.0]);
+
+            // Too narrow to reach the body off to the side, until it is wider.
+            let origin = [0.0, 0.0, -20.0];
+            let direction = [0.0, 0.0, 1.0];
+            assert!(solver
+                .shape_cast(origin, [9.0, 0.4, 0.4], direction, 40.0, |_, _| true)
+                .is_none());
+            let hit = solver
+                .shape_cast(origin, [9.6, 0.4, 0.4], direction, 40.0, |_, _| true)
+                .unwrap();
+            assert_eq!(hit.body_index, 2);
+        }
+    }
+
+    /// Bodies would bounce off of a floor with any restitution, ending and starting their contact with it again.
+    fn create_mud_floor() -> CommonBody<f32> {
+        let mut floor = create_floor();
+        if let CommonBody::ImmovableCuboid(floor) = &mut floor {
+            floor.material = PhysicsMaterial::mud();
+        }
+        floor
+    }
+
+    /// Turns the contact events from the last update into one letter each: Started, Persisted and Ended.
+    fn contact_event_letters<T: Float>(solver: &CpuSolver<T, CommonBody<T>>) -> String {
+        solver
+            .contact_events
+            .iter()
+            .map(|contact_event| match contact_event {
+                ContactEvent::ContactStarted(_) => 'S',
+                ContactEvent::ContactPersisted(_) => 'P',
+                ContactEvent::ContactEnded(_) => 'E',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn contact_starts_persists_and_ends_once_each() {
+        let mut solver = create_solver(10.0_f32, vec![]);
+        solver.steps.particle_updates = NonZeroU8::new(4).unwrap();
+        solver.insert(create_mud_floor());
+        let body = solver.insert(create_cuboid([0.0, 7.0, 0.0]));
+
+        let mut events = String::new();
+        for _ in 0..50 {
+            solver.update(0.04);
+            events += &contact_event_letters(&solver);
+        }
+
+        // Takes the body away from the floor.
+        let particle = solver.get_mut(body).unwrap().particle_mut().unwrap();
+        particle.position = [0.0, 0.0, 0.0];
+        particle.previous_position = [0.0, 0.0, 0.0];
+        for _ in 0..5 {
+            solver.update(0.04);
+            events += &contact_event_letters(&solver);
+        }
+
+        let persisted = events.len() - 2;
+        assert_eq!(events, format!("S{}E", "P".repeat(persisted)),);
+        assert!(persisted > 30, "{events}");
+    }
+
+    #[test]
+    fn only_the_latest_substep_contact_is_kept() {
+        let mut solver = create_test_solver::<f32>(2, 0.0);
+        let contact = |lhs_body_index, rhs_body_index, penetration| Contact {
+            lhs_body_index,
+            rhs_body_index,
+            normal: [0.0, 1.0, 0.0],
+            penetration,
+        };
+        // Each substep gathers the contact again, sometimes with the bodies the other way around.
+        solver.gathered_contacts = vec![
+            contact(0, 1, 0.3),
+            contact(1, 0, 0.2).ordered(),
+            contact(0, 1, 0.1),
+        ];
+
+        solver.update_contact_events();
+
+        assert_eq!(
+            solver.contact_events,
+            [ContactEvent::ContactStarted(contact(0, 1, 0.1))]
+        );
+    }
+
+    #[test]
+    fn sleeping_contacts_are_kept_without_events() {
+        let mut solver = create_solver(10.0_f32, vec![]);
+        solver.sleeping = Some(Sleeping::default());
+        solver.insert(create_mud_floor());
+        let body = solver.insert(create_cuboid([0.0, 8.9, 0.0]));
+
+        let mut events = String::new();
+        for _ in 0..50 {
+            solver.update(0.04);
+            events += &contact_event_letters(&solver);
+        }
+        let body_index = solver.body_index(body).unwrap();
+        assert!(solver.is_asleep(body_index));
+        assert_eq!(contact_event_letters(&solver), "");
+        assert!(!events.contains('E'), "{events}");
+        assert_eq!(solver.contacts.len(), 1);
+
+        // Nothing found the contact while the body slept, but it didn't end either, so it carries on as soon as the body wakes.
+        solver.wake(body_index);
+        solver.update(0.04);
+        assert_eq!(contact_event_letters(&solver), "P");
+    }
+
+    #[test]
+    fn cuboid_arrays_land_on_the_floor_like_cuboid_bodies() {
+        for deterministic in [false, true] {
+            let mut solver = create_solver(10.0_f32, vec![]);
+            solver.deterministic = deterministic;
+            solver.insert(create_mud_floor());
+            let body = solver.insert(create_cuboid([5.0, 7.0, 0.0]));
+            let cuboid_index = solver.cuboid_arrays.push(Cuboid {
+                particle: Particle::from_position([0.0, 7.0, 0.0]),
+                half_size: [0.5; 3],
+                material: PhysicsMaterial::default(),
+                continuous_collision_detection: false,
+            });
+
+            for _ in 0..100 {
+                solver.update(0.04);
+            }
+
+            let body_position = solver.get(body).unwrap().position_unchecked();
+            let cuboid_position = solver.cuboid_arrays.position(cuboid_index);
+            assert!((body_position[1] - 9.0).abs() < 0.01, "{body_position:?}");
+            assert!(
+                (cuboid_position[1] - body_position[1]).abs() < 0.01,
+                "{cuboid_position:?}"
+            );
+        }
+    }
 }
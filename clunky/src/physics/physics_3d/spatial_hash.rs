@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+
+use ahash::RandomState;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::math::Float;
+
+use super::{
+    aabb::{AabbCentredOrigin, AabbMinMax},
+    broadphase::{self, Broadphase, CellScratch},
+};
+
+/// A broadphase that only stores the cells that have bodies in them.
+/// Unlike the grid, it has no size or origin, so bodies can go anywhere, negatives included, and memory only grows with the number of occupied cells.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpatialHash<T>
+where
+    T: Float,
+{
+    pub cell_size: [T; 3],
+    #[serde(with = "cells")]
+    cells: HashMap<[isize; 3], Vec<usize>, RandomState>,
+    /// The smallest and largest cell positions that have bodies in them. Nothing outside of these needs looking at.
+    occupied_range: Option<([isize; 3], [isize; 3])>,
+    /// The aabb each body was inserted with, by body index.
+    aabbs: Vec<AabbMinMax<T>>,
+}
+
+impl<T> SpatialHash<T>
+where
+    T: Float,
+{
+    pub fn new(cell_size: [T; 3]) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::default(),
+            occupied_range: None,
+            aabbs: vec![],
+        }
+    }
+
+    /// Gets the position of the cell the position is in. Cell [0, 0, 0] starts at the world origin.
+    #[inline]
+    pub fn cell_position(&self, position: [T; 3]) -> [isize; 3] {
+        let mut cell_position = [0; 3];
+        for axis in 0..3 {
+            let cell = position[axis] / self.cell_size[axis];
+            // to_isize truncates, so this floors it for negatives.
+            let truncated_cell = cell.to_isize();
+            cell_position[axis] = if T::from_f64(truncated_cell as f64) > cell {
+                truncated_cell - 1
+            } else {
+                truncated_cell
+            };
+        }
+        cell_position
+    }
+
+    /// Gets the first and last cell positions an aabb touches.
+    #[inline]
+    pub fn cell_range(&self, aabb: AabbCentredOrigin<T>) -> ([isize; 3], [isize; 3]) {
+        (
+            self.cell_position([
+                aabb.position[0] - aabb.half_size[0],
+                aabb.position[1] - aabb.half_size[1],
+                aabb.position[2] - aabb.half_size[2],
+            ]),
+            self.cell_position([
+                aabb.position[0] + aabb.half_size[0],
+                aabb.position[1] + aabb.half_size[1],
+                aabb.position[2] + aabb.half_size[2],
+            ]),
+        )
+    }
+
+    /// Gets the smallest and largest cell positions that have bodies in them, should there be any bodies.
+    #[inline]
+    pub fn occupied_range(&self) -> Option<([isize; 3], [isize; 3])> {
+        self.occupied_range
+    }
+
+    /// Gets the body indices in the cell. Cells that don't exist are empty.
+    #[inline]
+    pub fn cell(&self, cell_position: [isize; 3]) -> &[usize] {
+        match self.cells.get(&cell_position) {
+            Some(cell) => cell,
+            None => &[],
+        }
+    }
+
+    /// Iterates through every cell that exists, along with its position, in no particular order.
+    #[inline]
+    pub fn cells(&self) -> impl Iterator<Item = ([isize; 3], &[usize])> {
+        self.cells
+            .iter()
+            .map(|(cell_position, cell)| (*cell_position, cell.as_slice()))
+    }
+
+    /// Iterates through every cell that exists, along with its position, in no particular order, on multiple threads.
+    #[inline]
+    pub fn par_cells(&self) -> impl ParallelIterator<Item = ([isize; 3], &[usize])> {
+        self.cells
+            .par_iter()
+            .map(|(cell_position, cell)| (*cell_position, cell.as_slice()))
+    }
+}
+
+impl<T> Broadphase<T> for SpatialHash<T>
+where
+    T: Float,
+{
+    /// Keeps only the body indices the function returns true for. Like [SpatialHash::clear], cells that were already empty are removed.
+    fn retain(&mut self, keep: &dyn Fn(usize) -> bool) {
+        let mut occupied_range: Option<([isize; 3], [isize; 3])> = None;
+        self.cells.retain(|cell_position, cell| {
+            let was_occupied = !cell.is_empty();
+            cell.retain(|body_index| keep(*body_index));
+
+            if !cell.is_empty() {
+                occupied_range = Some(match occupied_range {
+                    Some((min_occupied, max_occupied)) => (
+                        [
+                            min_occupied[0].min(cell_position[0]),
+                            min_occupied[1].min(cell_position[1]),
+                            min_occupied[2].min(cell_position[2]),
+                        ],
+                        [
+                            max_occupied[0].max(cell_position[0]),
+                            max_occupied[1].max(cell_position[1]),
+                            max_occupied[2].max(cell_position[2]),
+                        ],
+                    ),
+                    None => (*cell_position, *cell_position),
+                });
+            }
+            was_occupied
+        });
+        self.occupied_range = occupied_range;
+    }
+
+    /// Empties every cell. Cells that were already empty are removed entirely, so cells that bodies have left don't hang around forever.
+    fn clear(&mut self) {
+        self.cells.retain(|_, cell| {
+            let was_occupied = !cell.is_empty();
+            cell.clear();
+            was_occupied
+        });
+        self.occupied_range = None;
+    }
+
+    /// Puts the body index in every cell the aabb touches.
+    fn insert(&mut self, body_index: usize, aabb: AabbCentredOrigin<T>) {
+        broadphase::store_aabb(&mut self.aabbs, body_index, aabb.into());
+
+        let (min_cell_position, max_cell_position) = self.cell_range(aabb);
+
+        for x in min_cell_position[0]..=max_cell_position[0] {
+            for y in min_cell_position[1]..=max_cell_position[1] {
+                for z in min_cell_position[2]..=max_cell_position[2] {
+                    self.cells.entry([x, y, z]).or_default().push(body_index);
+                }
+            }
+        }
+
+        self.occupied_range = Some(match self.occupied_range {
+            Some((min_occupied, max_occupied)) => (
+                [
+                    min_occupied[0].min(min_cell_position[0]),
+                    min_occupied[1].min(min_cell_position[1]),
+                    min_occupied[2].min(min_cell_position[2]),
+                ],
+                [
+                    max_occupied[0].max(max_cell_position[0]),
+                    max_occupied[1].max(max_cell_position[1]),
+                    max_occupied[2].max(max_cell_position[2]),
+                ],
+            ),
+            None => (min_cell_position, max_cell_position),
+        });
+    }
+
+    /// The cells come out in a different order every run, so the pairs do too.
+    fn find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        on_pair: &mut dyn FnMut(usize, usize),
+    ) {
+        let mut scratch = CellScratch::default();
+        for (cell_position, cell) in self.cells() {
+            broadphase::find_pairs_in_cell(
+                cell,
+                &self.aabbs,
+                can_detect,
+                &mut scratch,
+                |point| self.cell_position(point) == cell_position,
+                &mut *on_pair,
+            );
+        }
+    }
+
+    fn par_find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+    ) -> Vec<Vec<(usize, usize)>> {
+        self.par_cells()
+            .fold(
+                || (vec![], CellScratch::default()),
+                |(mut pairs, mut scratch), (cell_position, cell)| {
+                    broadphase::find_pairs_in_cell(
+                        cell,
+                        &self.aabbs,
+                        can_detect,
+                        &mut scratch,
+                        |point| self.cell_position(point) == cell_position,
+                        |lhs_body_index, rhs_body_index| {
+                            pairs.push((lhs_body_index, rhs_body_index))
+                        },
+                    );
+                    (pairs, scratch)
+                },
+            )
+            .map(|(pairs, _)| pairs)
+            .collect()
+    }
+
+    fn extend_with_candidates(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>) {
+        let Some((min_occupied, max_occupied)) = self.occupied_range else {
+            return;
+        };
+        let (mut min_cell_position, mut max_cell_position) = self.cell_range(aabb);
+        // Huge aabbs would otherwise look through a huge amount of cells that can't exist.
+        for axis in 0..3 {
+            min_cell_position[axis] = min_cell_position[axis].max(min_occupied[axis]);
+            max_cell_position[axis] = max_cell_position[axis].min(max_occupied[axis]);
+        }
+
+        for x in min_cell_position[0]..=max_cell_position[0] {
+            for y in min_cell_position[1]..=max_cell_position[1] {
+                for z in min_cell_position[2]..=max_cell_position[2] {
+                    results.extend_from_slice(self.cell([x, y, z]));
+                }
+            }
+        }
+    }
+
+    /// Walks through the box of cells that have bodies in them, as if it were a grid.
+    fn cast(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        cast_against: &mut dyn FnMut(usize) -> T,
+    ) {
+        let Some((min_occupied, max_occupied)) = self.occupied_range else {
+            return;
+        };
+        broadphase::cast_through_cells(
+            origin,
+            half_size,
+            direction,
+            max_distance,
+            [
+                T::from_f64(min_occupied[0] as f64) * self.cell_size[0],
+                T::from_f64(min_occupied[1] as f64) * self.cell_size[1],
+                T::from_f64(min_occupied[2] as f64) * self.cell_size[2],
+            ],
+            self.cell_size,
+            [
+                max_occupied[0] - min_occupied[0] + 1,
+                max_occupied[1] - min_occupied[1] + 1,
+                max_occupied[2] - min_occupied[2] + 1,
+            ],
+            |[x, y, z]| {
+                self.cell([
+                    min_occupied[0] + x,
+                    min_occupied[1] + y,
+                    min_occupied[2] + z,
+                ])
+            },
+            cast_against,
+        );
+    }
+}
+
+/// Map keys have to be strings in some formats, such as json, so the cells are saved as a list instead.
+mod cells {
+    use std::collections::HashMap;
+
+    use ahash::RandomState;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(
+        cells: &HashMap<[isize; 3], Vec<usize>, RandomState>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(cells.iter().filter(|(_, cell)| !cell.is_empty()))
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<HashMap<[isize; 3], Vec<usize>, RandomState>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<([isize; 3], Vec<usize>)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_position_floors_negatives() {
+        let spatial_hash = SpatialHash::new([2.0_f32; 3]);
+
+        assert_eq!(spatial_hash.cell_position([-0.5, 0.5, 1.99]), [-1, 0, 0]);
+        assert_eq!(
+            spatial_hash.cell_position([-2.0, -4.0, -3.99]),
+            [-1, -2, -2]
+        );
+        assert_eq!(spatial_hash.cell_position([0.0, 2.0, -0.0]), [0, 1, 0]);
+    }
+
+    #[test]
+    fn finds_pairs_straddling_the_origin() {
+        let lhs_aabb = AabbCentredOrigin {
+            position: [-0.3_f32; 3],
+            half_size: [0.25; 3],
+        };
+        let rhs_aabb = AabbCentredOrigin {
+            position: [0.2; 3],
+            half_size: [0.3; 3],
+        };
+
+        let mut spatial_hash = SpatialHash::new([1.0; 3]);
+        assert_eq!(spatial_hash.cell_range(lhs_aabb), ([-1; 3], [-1; 3]));
+        assert_eq!(spatial_hash.cell_range(rhs_aabb), ([-1; 3], [0; 3]));
+
+        spatial_hash.insert(0, lhs_aabb);
+        spatial_hash.insert(1, rhs_aabb);
+        spatial_hash.finish_inserting();
+
+        let mut pairs = vec![];
+        spatial_hash.find_pairs(&|_| true, &mut |lhs_body_index, rhs_body_index| {
+            pairs.push((lhs_body_index, rhs_body_index))
+        });
+        assert_eq!(pairs, [(0, 1)]);
+    }
+}
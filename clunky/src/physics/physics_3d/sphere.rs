@@ -0,0 +1,148 @@
+use crate::math::{self, Float};
+use serde::{Deserialize, Serialize};
+
+use super::aabb::AabbCentredOrigin;
+
+extern crate test;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Sphere<T>
+where
+    T: Float,
+{
+    pub position: [T; 3],
+    pub radius: T,
+}
+
+impl<T> Sphere<T>
+where
+    T: Float,
+{
+    pub fn is_intersected_by_point(&self, point: [T; 3]) -> bool {
+        math::get_squared_magnitude_3d(math::sub_3d(point, self.position))
+            <= self.radius * self.radius
+    }
+
+    pub fn is_intersected_by_sphere(&self, sphere: Sphere<T>) -> bool {
+        let combined_radius = self.radius + sphere.radius;
+        math::get_squared_magnitude_3d(math::sub_3d(sphere.position, self.position))
+            <= combined_radius * combined_radius
+    }
+
+    pub fn is_intersected_by_aabb(&self, aabb: AabbCentredOrigin<T>) -> bool {
+        let closest_point = closest_point_on_aabb(&aabb, self.position);
+        self.is_intersected_by_point(closest_point)
+    }
+
+    /// The normal points from self towards other, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_sphere(
+        &self,
+        other: &Sphere<T>,
+    ) -> ([T; 3], T) {
+        let difference = math::sub_3d(other.position, self.position);
+        let distance = math::get_magnitude_3d(difference);
+
+        // If they are perfectly on top of each other, any direction will do.
+        let normal = if distance == T::ZERO {
+            [T::ZERO, T::ONE, T::ZERO]
+        } else {
+            math::div_3d_by_1d(difference, distance)
+        };
+
+        (normal, self.radius + other.radius - distance)
+    }
+
+    /// The normal points from self towards the aabb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_aabb(
+        &self,
+        aabb: &AabbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        let closest_point = closest_point_on_aabb(aabb, self.position);
+        let difference = math::sub_3d(closest_point, self.position);
+        let distance = math::get_magnitude_3d(difference);
+
+        if distance != T::ZERO {
+            return (
+                math::div_3d_by_1d(difference, distance),
+                self.radius - distance,
+            );
+        }
+
+        // The centre is inside the aabb, so we push it out of whichever face is closest.
+        let mut normal = [T::ZERO; 3];
+        let mut smallest_distance_to_face = T::MAX;
+        for axis in 0..3 {
+            let offset = self.position[axis] - aabb.position[axis];
+            let distance_to_face = aabb.half_size[axis] - offset.abs();
+            if distance_to_face < smallest_distance_to_face {
+                smallest_distance_to_face = distance_to_face;
+                normal = [T::ZERO; 3];
+                // The aabb is on the opposite side to the face we are leaving through.
+                normal[axis] = if offset.is_sign_positive() {
+                    -T::ONE
+                } else {
+                    T::ONE
+                };
+            }
+        }
+
+        (normal, self.radius + smallest_distance_to_face)
+    }
+}
+
+/// Gets the point on or in the aabb that is closest to the point.
+pub fn closest_point_on_aabb<T: Float>(aabb: &AabbCentredOrigin<T>, point: [T; 3]) -> [T; 3] {
+    let mut closest_point = point;
+    for (axis, coordinate) in closest_point.iter_mut().enumerate() {
+        let min = aabb.position[axis] - aabb.half_size[axis];
+        let max = aabb.position[axis] + aabb.half_size[axis];
+        if *coordinate < min {
+            *coordinate = min;
+        } else if *coordinate > max {
+            *coordinate = max;
+        }
+    }
+    closest_point
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::Bencher;
+
+    #[bench]
+    fn bench_sphere_is_intersected_by_sphere(b: &mut Bencher) {
+        b.iter(|| {
+            let sphere1 = test::black_box(Sphere {
+                position: [3.0, 2.0, 2.2389],
+                radius: 1.5,
+            });
+
+            let sphere2 = test::black_box(Sphere {
+                position: [3.0, 4.0, 3.2389],
+                radius: 0.75,
+            });
+
+            sphere1.is_intersected_by_sphere(sphere2)
+        })
+    }
+
+    #[bench]
+    fn bench_sphere_is_intersected_by_aabb(b: &mut Bencher) {
+        b.iter(|| {
+            let sphere = test::black_box(Sphere {
+                position: [3.0, 2.0, 2.2389],
+                radius: 1.5,
+            });
+
+            let aabb = test::black_box(AabbCentredOrigin {
+                position: [3.0, 2.0, 5.1],
+                half_size: [10.0, 5.0, 2.2389],
+            });
+
+            sphere.is_intersected_by_aabb(aabb)
+        })
+    }
+}
@@ -0,0 +1,184 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::math::Float;
+
+use super::{
+    aabb::{AabbCentredOrigin, AabbMinMax},
+    broadphase::Broadphase,
+};
+
+/// A broadphase that sorts every body by the minimum of its aabb along one axis, so that only bodies whose aabbs overlap along that axis get compared.
+/// It has no cells, so huge bodies cost no more than small ones, and bodies can go anywhere.
+/// The axis is whichever one the bodies are most spread out along, chosen again every time bodies are inserted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweepAndPrune<T>
+where
+    T: Float,
+{
+    entries: Vec<Entry<T>>,
+    axis: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Entry<T>
+where
+    T: Float,
+{
+    body_index: usize,
+    aabb: AabbMinMax<T>,
+}
+
+impl<T> Default for SweepAndPrune<T>
+where
+    T: Float,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SweepAndPrune<T>
+where
+    T: Float,
+{
+    pub fn new() -> Self {
+        Self {
+            entries: vec![],
+            axis: 0,
+        }
+    }
+
+    /// The axis the bodies are sorted along.
+    #[inline]
+    pub fn axis(&self) -> usize {
+        self.axis
+    }
+
+    /// Gives every pair that the entry at the index is the first of, by sweeping along the sorted entries until they stop overlapping along the axis.
+    #[inline]
+    fn find_pairs_from(
+        &self,
+        entry_index: usize,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        mut on_pair: impl FnMut(usize, usize),
+    ) {
+        let lhs = self.entries[entry_index];
+        let lhs_can_detect = can_detect(lhs.body_index);
+
+        for rhs in &self.entries[entry_index + 1..] {
+            if rhs.aabb.min[self.axis] > lhs.aabb.max[self.axis] {
+                return;
+            }
+            if !lhs_can_detect && !can_detect(rhs.body_index) {
+                continue;
+            }
+            if lhs.aabb.is_intersected_by_aabb(rhs.aabb) {
+                on_pair(lhs.body_index, rhs.body_index);
+            }
+        }
+    }
+}
+
+impl<T> Broadphase<T> for SweepAndPrune<T>
+where
+    T: Float,
+{
+    fn retain(&mut self, keep: &dyn Fn(usize) -> bool) {
+        self.entries.retain(|entry| keep(entry.body_index));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn insert(&mut self, body_index: usize, aabb: AabbCentredOrigin<T>) {
+        self.entries.push(Entry {
+            body_index,
+            aabb: aabb.into(),
+        });
+    }
+
+    /// Picks the axis the aabb centres are most spread out along, then sorts along it.
+    /// Ties are sorted by body index, so the order is the same every run.
+    fn finish_inserting(&mut self) {
+        let Some(first) = self.entries.first() else {
+            return;
+        };
+
+        // The centres are doubled, but that doesn't change which axis is most spread out.
+        let centre = |aabb: AabbMinMax<T>, axis: usize| aabb.min[axis] + aabb.max[axis];
+        let mut min_centre = [T::ZERO; 3];
+        let mut max_centre = [T::ZERO; 3];
+        for axis in 0..3 {
+            min_centre[axis] = centre(first.aabb, axis);
+            max_centre[axis] = min_centre[axis];
+        }
+        for entry in &self.entries {
+            for axis in 0..3 {
+                let centre = centre(entry.aabb, axis);
+                if centre < min_centre[axis] {
+                    min_centre[axis] = centre;
+                }
+                if centre > max_centre[axis] {
+                    max_centre[axis] = centre;
+                }
+            }
+        }
+
+        self.axis = 0;
+        for axis in 1..3 {
+            if max_centre[axis] - min_centre[axis] > max_centre[self.axis] - min_centre[self.axis] {
+                self.axis = axis;
+            }
+        }
+
+        let axis = self.axis;
+        self.entries.sort_unstable_by(|lhs, rhs| {
+            lhs.aabb.min[axis]
+                .partial_cmp(&rhs.aabb.min[axis])
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(lhs.body_index.cmp(&rhs.body_index))
+        });
+    }
+
+    fn find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        on_pair: &mut dyn FnMut(usize, usize),
+    ) {
+        for entry_index in 0..self.entries.len() {
+            self.find_pairs_from(entry_index, can_detect, &mut *on_pair);
+        }
+    }
+
+    fn par_find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+    ) -> Vec<Vec<(usize, usize)>> {
+        (0..self.entries.len())
+            .into_par_iter()
+            .fold(Vec::new, |mut pairs, entry_index| {
+                self.find_pairs_from(entry_index, can_detect, |lhs_body_index, rhs_body_index| {
+                    pairs.push((lhs_body_index, rhs_body_index))
+                });
+                pairs
+            })
+            .collect()
+    }
+
+    fn extend_with_candidates(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>) {
+        let aabb = AabbMinMax::from(aabb);
+        // Nothing sorted after this can reach back to the aabb.
+        let end = self
+            .entries
+            .partition_point(|entry| entry.aabb.min[self.axis] <= aabb.max[self.axis]);
+
+        results.extend(
+            self.entries[..end]
+                .iter()
+                .filter(|entry| entry.aabb.is_intersected_by_aabb(aabb))
+                .map(|entry| entry.body_index),
+        );
+    }
+}
@@ -0,0 +1,646 @@
+use crate::math::{self, Float};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+
+use super::{
+    aabb::{AabbCentredOrigin, AabbMinMax},
+    bodies::CAST_ITERATIONS,
+    capsule::Capsule,
+    cylinder::Cylinder,
+    obb::ObbCentredOrigin,
+    sphere::Sphere,
+};
+
+/// The most triangles a leaf of the bvh holds.
+const TRIANGLES_PER_LEAF: usize = 4;
+/// Each level of the bvh halves the triangles, so no bvh that fits in memory gets anywhere near this deep.
+const MAX_BVH_DEPTH: usize = 64;
+
+/// A mesh of triangles, for accurate level geometry, such as sculpted terrain and ramps.
+/// Triangles are two sided, and the mesh has no inside, so it doesn't need to be closed.
+///
+/// The triangles are sorted into a bounding volume hierarchy (bvh), so that only the triangles near a shape are checked against it.
+/// The vertices are in world space.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriangleMesh<T>
+where
+    T: Float,
+{
+    vertices: Vec<[T; 3]>,
+    /// Sorted so that each leaf of the bvh holds a run of them.
+    triangles: Vec<[u32; 3]>,
+    nodes: Vec<BvhNode<T>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BvhNode<T>
+where
+    T: Float,
+{
+    bounds: AabbMinMax<T>,
+    /// For leaves, the index of the first triangle. For branches, the index of the second child, as the first child is always straight after its parent.
+    first: u32,
+    /// How many triangles the leaf has. Branches have none.
+    triangle_count: u32,
+}
+
+impl<T> TriangleMesh<T>
+where
+    T: Float,
+{
+    /// Every 3 indices make a triangle. Triangles with no area are left out, as they have no normal to push anything out along.
+    pub fn new(vertices: Vec<[T; 3]>, indices: &[u32]) -> Self {
+        let triangles = indices
+            .chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+            .filter(|triangle| {
+                let [a, b, c] = triangle.map(|index| vertices[index as usize]);
+                math::get_squared_magnitude_3d(math::cross(math::sub_3d(b, a), math::sub_3d(c, a)))
+                    > T::ZERO
+            })
+            .collect();
+
+        let mut triangle_mesh = Self {
+            vertices,
+            triangles,
+            nodes: vec![],
+        };
+        if !triangle_mesh.triangles.is_empty() {
+            triangle_mesh.build_bvh(0, triangle_mesh.triangles.len());
+        }
+        triangle_mesh
+    }
+
+    /// Reads a mesh from gltf, the same way [crate::meshes::get_indices_from_gltf] does, combining all of its primitives.
+    /// The mesh is placed the same way Matrix4::from_translation(translation) * Matrix4::from_quaternion(rotation) * Matrix4::from_scale(scale) would place it when rendering.
+    pub fn from_gltf(
+        gltf: &[u8],
+        mesh_index: usize,
+        translation: [T; 3],
+        rotation: [T; 4],
+        scale: [T; 3],
+    ) -> Self {
+        let (gltf, buffers, _) = gltf::import_slice(gltf).unwrap();
+
+        let mesh = gltf.meshes().nth(mesh_index).unwrap();
+        // Only the axes are wanted, so the half size doesn't matter.
+        let placement = ObbCentredOrigin::from_quaternion(translation, scale, rotation);
+
+        let mut vertices = vec![];
+        let mut indices = vec![];
+
+        for primitive in mesh.primitives() {
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+            let first_vertex = vertices.len() as u32;
+            for position in reader.read_positions().unwrap() {
+                vertices.push(placement.to_world(math::mul_3d(
+                    [
+                        T::from_f32(position[0]),
+                        T::from_f32(position[1]),
+                        T::from_f32(position[2]),
+                    ],
+                    scale,
+                )));
+            }
+
+            match reader.read_indices() {
+                Some(primitive_indices) => {
+                    for index in primitive_indices.into_u32() {
+                        indices.push(first_vertex + index);
+                    }
+                }
+                // Without indices, every 3 vertices make a triangle.
+                None => indices.extend(first_vertex..vertices.len() as u32),
+            }
+        }
+
+        Self::new(vertices, &indices)
+    }
+
+    pub fn vertices(&self) -> &[[T; 3]] {
+        &self.vertices
+    }
+
+    /// The indices of each triangle's vertices. These are in the bvh's order, rather than the order they were given in.
+    pub fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+
+    /// The smallest aabb that contains every triangle. An empty mesh is a point at the origin.
+    pub fn bounds(&self) -> AabbCentredOrigin<T> {
+        match self.nodes.first() {
+            Some(root) => centred_bounds(root.bounds),
+            None => AabbCentredOrigin {
+                position: [T::ZERO; 3],
+                half_size: [T::ZERO; 3],
+            },
+        }
+    }
+
+    /// Moves every vertex by the translation.
+    pub fn translate(&mut self, translation: [T; 3]) {
+        for vertex in &mut self.vertices {
+            *vertex = math::add_3d(*vertex, translation);
+        }
+        for node in &mut self.nodes {
+            node.bounds.min = math::add_3d(node.bounds.min, translation);
+            node.bounds.max = math::add_3d(node.bounds.max, translation);
+        }
+    }
+
+    /// The normal points from self towards the sphere, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_sphere(
+        &self,
+        sphere: &Sphere<T>,
+    ) -> ([T; 3], T) {
+        self.deepest_contact(
+            AabbCentredOrigin {
+                position: sphere.position,
+                half_size: [sphere.radius; 3],
+            },
+            |triangle| get_collision_normal_and_penetration_with_sphere(triangle, sphere),
+        )
+    }
+
+    /// The normal points from self towards the aabb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_aabb(
+        &self,
+        aabb: &AabbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        self.get_collision_normal_and_penetration_with_obb(&ObbCentredOrigin::from_aabb(aabb))
+    }
+
+    /// The normal points from self towards the obb, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_obb(
+        &self,
+        obb: &ObbCentredOrigin<T>,
+    ) -> ([T; 3], T) {
+        self.deepest_contact(
+            AabbCentredOrigin {
+                position: obb.position,
+                half_size: obb.enclosing_half_size(),
+            },
+            |triangle| get_collision_normal_and_penetration_with_obb(triangle, obb),
+        )
+    }
+
+    /// The normal points from self towards the capsule, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    pub fn get_collision_normal_and_penetration_with_capsule(
+        &self,
+        capsule: &Capsule<T>,
+    ) -> ([T; 3], T) {
+        self.deepest_contact(
+            AabbCentredOrigin {
+                position: capsule.position,
+                half_size: [
+                    capsule.radius,
+                    capsule.half_height + capsule.radius,
+                    capsule.radius,
+                ],
+            },
+            |triangle| get_collision_normal_and_penetration_with_capsule(triangle, capsule),
+        )
+    }
+
+    /// The normal points from self towards the cylinder, and the penetration is how far they are inside each other.
+    /// To separate them, move self by normal * -penetration.
+    ///
+    /// The cylinder is treated as the aabb around it.
+    pub fn get_collision_normal_and_penetration_with_cylinder(
+        &self,
+        cylinder: &Cylinder<T>,
+    ) -> ([T; 3], T) {
+        self.get_collision_normal_and_penetration_with_aabb(&AabbCentredOrigin {
+            position: cylinder.position,
+            half_size: [cylinder.radius, cylinder.half_height, cylinder.radius],
+        })
+    }
+
+    /// Sweeps an aabb along a normalised direction, returning the normal of the triangle it hit, and how far the aabb travelled first.
+    /// If the aabb starts inside the mesh, the distance is 0.
+    pub fn cast_aabb(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+    ) -> Option<([T; 3], T)> {
+        // Shrinks to the closest hit so far, so that the bvh can skip anything further away.
+        let max_distance = Cell::new(max_distance);
+        let mut closest_hit = None;
+
+        self.for_each_triangle_in(
+            |bounds| {
+                AabbCentredOrigin {
+                    position: bounds.position,
+                    half_size: math::add_3d(bounds.half_size, half_size),
+                }
+                .get_ray_intersection(origin, direction, max_distance.get())
+                .is_some()
+            },
+            |triangle| {
+                if let Some(hit) = cast_aabb_at_triangle(
+                    triangle,
+                    origin,
+                    half_size,
+                    direction,
+                    max_distance.get(),
+                ) {
+                    max_distance.set(hit.1);
+                    closest_hit = Some(hit);
+                }
+            },
+        );
+
+        closest_hit
+    }
+
+    /// Finds the deepest contact between a shape and the triangles near it. If no triangles are near it, the penetration is -T::MAX.
+    #[inline]
+    fn deepest_contact(
+        &self,
+        shape_bounds: AabbCentredOrigin<T>,
+        mut get_collision_normal_and_penetration: impl FnMut([[T; 3]; 3]) -> ([T; 3], T),
+    ) -> ([T; 3], T) {
+        let mut deepest = ([T::ZERO; 3], -T::MAX);
+        self.for_each_triangle_in(
+            |bounds| bounds.is_intersected_by_aabb(shape_bounds),
+            |triangle| {
+                let contact = get_collision_normal_and_penetration(triangle);
+                if contact.1 > deepest.1 {
+                    deepest = contact;
+                }
+            },
+        );
+        deepest
+    }
+
+    /// Walks down the bvh, into every node whose bounds are accepted, calling on_triangle with each triangle in the accepted leaves.
+    fn for_each_triangle_in(
+        &self,
+        mut accept_bounds: impl FnMut(AabbCentredOrigin<T>) -> bool,
+        mut on_triangle: impl FnMut([[T; 3]; 3]),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut stack = [0; MAX_BVH_DEPTH + 1];
+        let mut stack_length = 1;
+        while stack_length > 0 {
+            stack_length -= 1;
+            let node_index = stack[stack_length];
+            let node = &self.nodes[node_index];
+            if !accept_bounds(centred_bounds(node.bounds)) {
+                continue;
+            }
+
+            if node.triangle_count == 0 {
+                stack[stack_length] = node_index + 1;
+                stack[stack_length + 1] = node.first as usize;
+                stack_length += 2;
+                continue;
+            }
+
+            let first = node.first as usize;
+            for triangle in &self.triangles[first..first + node.triangle_count as usize] {
+                on_triangle(triangle.map(|index| self.vertices[index as usize]));
+            }
+        }
+    }
+
+    /// Builds the node for the triangles from first up to end, and the nodes below it, splitting them in half along the longest axis each time.
+    fn build_bvh(&mut self, first: usize, end: usize) {
+        let node_index = self.nodes.len();
+        let bounds = self.triangles[first..end]
+            .iter()
+            .flatten()
+            .map(|index| self.vertices[*index as usize])
+            .fold(
+                AabbMinMax {
+                    min: [T::MAX; 3],
+                    max: [-T::MAX; 3],
+                },
+                |bounds, vertex| AabbMinMax {
+                    min: [
+                        min(bounds.min[0], vertex[0]),
+                        min(bounds.min[1], vertex[1]),
+                        min(bounds.min[2], vertex[2]),
+                    ],
+                    max: [
+                        max(bounds.max[0], vertex[0]),
+                        max(bounds.max[1], vertex[1]),
+                        max(bounds.max[2], vertex[2]),
+                    ],
+                },
+            );
+        self.nodes.push(BvhNode {
+            bounds,
+            first: first as u32,
+            triangle_count: (end - first) as u32,
+        });
+
+        if end - first <= TRIANGLES_PER_LEAF {
+            return;
+        }
+
+        let size = math::sub_3d(bounds.max, bounds.min);
+        let axis = if size[0] > size[1] && size[0] > size[2] {
+            0
+        } else if size[1] > size[2] {
+            1
+        } else {
+            2
+        };
+        let vertices = &self.vertices;
+        // The sum of the vertices is 3 times the centre, which sorts the same.
+        let centre = |triangle: &[u32; 3]| {
+            triangle
+                .iter()
+                .fold(T::ZERO, |sum, index| sum + vertices[*index as usize][axis])
+        };
+        self.triangles[first..end].sort_unstable_by(|lhs, rhs| {
+            centre(lhs)
+                .partial_cmp(&centre(rhs))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let middle = (first + end) / 2;
+        self.build_bvh(first, middle);
+        let second_child = self.nodes.len();
+        self.build_bvh(middle, end);
+
+        self.nodes[node_index].first = second_child as u32;
+        self.nodes[node_index].triangle_count = 0;
+    }
+}
+
+#[inline]
+pub(super) fn centred_bounds<T: Float>(bounds: AabbMinMax<T>) -> AabbCentredOrigin<T> {
+    let half_size = math::mul_3d_by_1d(math::sub_3d(bounds.max, bounds.min), T::from_f32(0.5));
+    AabbCentredOrigin {
+        position: math::add_3d(bounds.min, half_size),
+        half_size,
+    }
+}
+
+#[inline]
+pub(super) fn min<T: Float>(lhs: T, rhs: T) -> T {
+    if lhs < rhs {
+        lhs
+    } else {
+        rhs
+    }
+}
+
+#[inline]
+pub(super) fn max<T: Float>(lhs: T, rhs: T) -> T {
+    if lhs > rhs {
+        lhs
+    } else {
+        rhs
+    }
+}
+
+/// Gets the point on the triangle that is closest to the point.
+/// From Real-Time Collision Detection by Christer Ericson, which works out which part of the triangle is closest using the regions around its corners and edges.
+pub(super) fn closest_point<T: Float>(triangle: [[T; 3]; 3], point: [T; 3]) -> [T; 3] {
+    let [a, b, c] = triangle;
+    let ab = math::sub_3d(b, a);
+    let ac = math::sub_3d(c, a);
+
+    let ap = math::sub_3d(point, a);
+    let d1 = math::dot(ab, ap);
+    let d2 = math::dot(ac, ap);
+    if d1 <= T::ZERO && d2 <= T::ZERO {
+        return a;
+    }
+
+    let bp = math::sub_3d(point, b);
+    let d3 = math::dot(ab, bp);
+    let d4 = math::dot(ac, bp);
+    if d3 >= T::ZERO && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= T::ZERO && d1 >= T::ZERO && d3 <= T::ZERO {
+        return math::add_3d(a, math::mul_3d_by_1d(ab, d1 / (d1 - d3)));
+    }
+
+    let cp = math::sub_3d(point, c);
+    let d5 = math::dot(ab, cp);
+    let d6 = math::dot(ac, cp);
+    if d6 >= T::ZERO && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= T::ZERO && d2 >= T::ZERO && d6 <= T::ZERO {
+        return math::add_3d(a, math::mul_3d_by_1d(ac, d2 / (d2 - d6)));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= T::ZERO && d4 - d3 >= T::ZERO && d5 - d6 >= T::ZERO {
+        return math::add_3d(
+            b,
+            math::mul_3d_by_1d(math::sub_3d(c, b), (d4 - d3) / ((d4 - d3) + (d5 - d6))),
+        );
+    }
+
+    let denominator = T::ONE / (va + vb + vc);
+    math::add_3d(
+        a,
+        math::add_3d(
+            math::mul_3d_by_1d(ab, vb * denominator),
+            math::mul_3d_by_1d(ac, vc * denominator),
+        ),
+    )
+}
+
+/// The normal points from the triangle towards the sphere.
+pub(super) fn get_collision_normal_and_penetration_with_sphere<T: Float>(
+    triangle: [[T; 3]; 3],
+    sphere: &Sphere<T>,
+) -> ([T; 3], T) {
+    let difference = math::sub_3d(sphere.position, closest_point(triangle, sphere.position));
+    let distance = math::get_magnitude_3d(difference);
+
+    if distance != T::ZERO {
+        return (
+            math::div_3d_by_1d(difference, distance),
+            sphere.radius - distance,
+        );
+    }
+
+    // The centre is on the triangle, so push it out of whichever side the triangle faces.
+    let [a, b, c] = triangle;
+    (
+        math::normalise_3d(math::cross(math::sub_3d(b, a), math::sub_3d(c, a))),
+        sphere.radius,
+    )
+}
+
+/// The normal points from the triangle towards the capsule.
+pub(super) fn get_collision_normal_and_penetration_with_capsule<T: Float>(
+    triangle: [[T; 3]; 3],
+    capsule: &Capsule<T>,
+) -> ([T; 3], T) {
+    // Find the sphere on the capsule's segment that is closest to the triangle. Going back and forth twice gets close enough.
+    let mut sphere = capsule.sphere_closest_to_height(closest_point(triangle, capsule.position)[1]);
+    sphere = capsule.sphere_closest_to_height(closest_point(triangle, sphere.position)[1]);
+    get_collision_normal_and_penetration_with_sphere(triangle, &sphere)
+}
+
+/// Uses the separating axis theorem, like [ObbCentredOrigin::get_collision_normal_and_penetration_with_obb].
+/// The triangle isn't symmetrical around its centre, so each axis checks how far the obb would have to move either way to stop overlapping it.
+///
+/// The normal points from the triangle towards the obb.
+pub(super) fn get_collision_normal_and_penetration_with_obb<T: Float>(
+    triangle: [[T; 3]; 3],
+    obb: &ObbCentredOrigin<T>,
+) -> ([T; 3], T) {
+    let [a, b, c] = triangle;
+    let edges = [
+        math::normalise_3d(math::sub_3d(b, a)),
+        math::normalise_3d(math::sub_3d(c, b)),
+        math::normalise_3d(math::sub_3d(a, c)),
+    ];
+
+    let mut axes = [[T::ZERO; 3]; 13];
+    // The face normal goes first, so that when another axis is just as good, things get pushed straight out of the face.
+    axes[0] = math::cross(edges[0], edges[1]);
+    axes[1..4].copy_from_slice(&obb.axes);
+    for obb_axis in 0..3 {
+        for edge in 0..3 {
+            axes[4 + obb_axis * 3 + edge] = math::cross(obb.axes[obb_axis], edges[edge]);
+        }
+    }
+
+    // Cross products of nearly parallel edges are tiny, and their direction is mostly rounding error.
+    let minimum_squared_length = T::from_f32(0.000001);
+
+    let mut normal = [T::ZERO, T::ONE, T::ZERO];
+    let mut smallest_penetration = T::MAX;
+
+    for axis in axes {
+        let squared_length = math::get_squared_magnitude_3d(axis);
+        if squared_length < minimum_squared_length {
+            continue;
+        }
+        let axis = math::div_3d_by_1d(axis, squared_length.sqrt());
+
+        let projections = triangle.map(|vertex| math::dot(vertex, axis));
+        let triangle_min = min(projections[0], min(projections[1], projections[2]));
+        let triangle_max = max(projections[0], max(projections[1], projections[2]));
+
+        let obb_centre = math::dot(obb.position, axis);
+        let obb_reach = obb.projected_half_size(axis);
+
+        let push_along_axis = triangle_max - (obb_centre - obb_reach);
+        let push_against_axis = (obb_centre + obb_reach) - triangle_min;
+
+        let (penetration, axis_normal) = if push_along_axis < push_against_axis {
+            (push_along_axis, axis)
+        } else {
+            (push_against_axis, math::neg_3d(axis))
+        };
+        if penetration < smallest_penetration {
+            smallest_penetration = penetration;
+            normal = axis_normal;
+        }
+    }
+
+    (normal, smallest_penetration)
+}
+
+/// Creeps the aabb forward by the distance between it and the triangle until they touch, the same way [super::bodies::CommonBody] casts against shapes other than aabbs.
+pub(super) fn cast_aabb_at_triangle<T: Float>(
+    triangle: [[T; 3]; 3],
+    origin: [T; 3],
+    half_size: [T; 3],
+    direction: [T; 3],
+    max_distance: T,
+) -> Option<([T; 3], T)> {
+    let [a, b, c] = triangle;
+    let triangle_bounds = centred_bounds(AabbMinMax {
+        min: [
+            min(a[0], min(b[0], c[0])),
+            min(a[1], min(b[1], c[1])),
+            min(a[2], min(b[2], c[2])),
+        ],
+        max: [
+            max(a[0], max(b[0], c[0])),
+            max(a[1], max(b[1], c[1])),
+            max(a[2], max(b[2], c[2])),
+        ],
+    });
+    let (_, mut distance) = AabbCentredOrigin {
+        position: triangle_bounds.position,
+        half_size: math::add_3d(triangle_bounds.half_size, half_size),
+    }
+    .get_ray_intersection(origin, direction, max_distance)?;
+
+    // The aabb can't touch the triangle before it touches the triangle's plane, so skip straight there.
+    // This makes hits on the face exact, however shallow the angle, which creeping alone is very slow at.
+    let face_normal = math::normalise_3d(math::cross(math::sub_3d(b, a), math::sub_3d(c, a)));
+    let reach = (0..3).fold(T::ZERO, |reach, axis| {
+        reach + face_normal[axis].abs() * half_size[axis]
+    });
+    let height_above_plane = math::dot(math::sub_3d(origin, a), face_normal);
+    if height_above_plane.abs() > reach {
+        let approach = math::dot(direction, face_normal);
+        // Moving away from, or along, the plane.
+        if approach == T::ZERO
+            || approach.is_sign_positive() == height_above_plane.is_sign_positive()
+        {
+            return None;
+        }
+        let side = if height_above_plane.is_sign_positive() {
+            reach
+        } else {
+            -reach
+        };
+        let distance_to_plane = (side - height_above_plane) / approach;
+        if distance_to_plane > max_distance {
+            return None;
+        }
+        distance = max(distance, distance_to_plane);
+    }
+
+    // How close counts as touching.
+    let tolerance = T::from_f32(0.0001);
+
+    for _ in 0..CAST_ITERATIONS {
+        let (collision_normal, penetration) = get_collision_normal_and_penetration_with_obb(
+            triangle,
+            &ObbCentredOrigin::from_aabb(&AabbCentredOrigin {
+                position: math::add_3d(origin, math::mul_3d_by_1d(direction, distance)),
+                half_size,
+            }),
+        );
+
+        if penetration >= -tolerance {
+            // Triangles have no thickness, so a shape that is only just touching could be pushed out of either side.
+            // It came from the side it is moving away from though.
+            if math::dot(collision_normal, direction) > T::ZERO {
+                return Some((math::neg_3d(collision_normal), distance));
+            }
+            return Some((collision_normal, distance));
+        }
+
+        // A negative penetration is never more than the distance between the shapes, so moving by it can't skip past the triangle.
+        distance -= penetration;
+        if distance > max_distance {
+            return None;
+        }
+    }
+
+    // Still not touching, so it is most likely skimming past the triangle.
+    None
+}
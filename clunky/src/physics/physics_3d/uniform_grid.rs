@@ -0,0 +1,302 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::math::{self, Float};
+
+use super::{
+    aabb::{AabbCentredOrigin, AabbMinMax},
+    broadphase::{self, Broadphase, CellScratch},
+};
+
+/// A broadphase made of a fixed size grid of cells, where each body is put in every cell its aabb touches.
+/// Fast, but bodies outside of it don't collide, it uses memory for every cell, even empty ones, and huge bodies end up in a huge amount of cells.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UniformGrid<T>
+where
+    T: Float,
+{
+    pub grid_size: [usize; 3], // This is in cell size units. This should probably be clarified.
+    pub cell_size: [usize; 3],
+    pub grid_origin: [T; 3], // Remember that the origin is the bottom left corner of the grid, I think.
+    pub cells: Vec<Vec<usize>>,
+    /// The aabb each body was inserted with, by body index.
+    aabbs: Vec<AabbMinMax<T>>,
+}
+
+impl<T> UniformGrid<T>
+where
+    T: Float,
+{
+    pub fn new(grid_size: [usize; 3], cell_size: [usize; 3], grid_origin: [T; 3]) -> Self {
+        Self {
+            grid_size,
+            cell_size,
+            grid_origin,
+            cells: vec![vec![]; grid_size[0] * grid_size[1] * grid_size[2]],
+            aabbs: vec![],
+        }
+    }
+
+    /// Gets the position of the grid cell a point is in, clamped to the grid.
+    #[inline]
+    pub fn cell_position(&self, point: [T; 3]) -> [usize; 3] {
+        let corrected_position = math::sub_3d(point, self.grid_origin);
+        let mut grid_cell_position = [0; 3];
+        for axis in 0..3 {
+            // Anything below the grid truncates towards 0, which is where it would be clamped to anyway.
+            let position = corrected_position[axis].to_isize().max(0) as usize;
+            grid_cell_position[axis] =
+                (position / self.cell_size[axis]).min(self.grid_size[axis] - 1);
+        }
+        grid_cell_position
+    }
+
+    /// Gets the index of the grid cell a point is in, clamped to the grid.
+    #[inline]
+    pub fn cell_index(&self, point: [T; 3]) -> usize {
+        math::index_from_position_3d(
+            self.cell_position(point),
+            self.grid_size[0],
+            self.grid_size[1],
+        )
+    }
+
+    /// Gets the first and last grid cell positions an aabb touches, clamped to the grid.
+    ///
+    /// This works from the aabb's min and max, rather than its position and half size, so that any point inside the aabb, such as the corner pairs are found by, is in one of these cells, even after rounding.
+    #[inline]
+    pub fn cell_range(&self, aabb: AabbMinMax<T>) -> ([usize; 3], [usize; 3]) {
+        (self.cell_position(aabb.min), self.cell_position(aabb.max))
+    }
+}
+
+impl<T> Broadphase<T> for UniformGrid<T>
+where
+    T: Float,
+{
+    fn retain(&mut self, keep: &dyn Fn(usize) -> bool) {
+        for cell in &mut self.cells {
+            if cell.capacity() == 0 {
+                continue;
+            }
+            // This is meant to keep memory usage low, with only a minor performance cost, but I'm not convinced.
+            // Even though we check for 0, this still seems dodgy. Perhaps this should be a choice for the user.
+            if cell.len() <= cell.capacity() / 2 {
+                cell.shrink_to_fit();
+            }
+            cell.retain(|body_index| keep(*body_index));
+        }
+    }
+
+    fn clear(&mut self) {
+        for cell in &mut self.cells {
+            if cell.capacity() == 0 {
+                continue;
+            }
+            if cell.len() <= cell.capacity() / 2 {
+                cell.shrink_to_fit();
+            }
+            cell.clear();
+        }
+    }
+
+    /// Bodies outside of the grid are left out, as whatever outside_of_grid_bounds_behaviour wanted done has already been done.
+    fn insert(&mut self, body_index: usize, aabb: AabbCentredOrigin<T>) {
+        let real_grid_size = math::mul_3d(self.grid_size, self.cell_size);
+        let corrected_position = math::sub_3d(aabb.position, self.grid_origin);
+        if is_outside_of_grid_bounds(corrected_position, real_grid_size) {
+            return;
+        }
+
+        let aabb = AabbMinMax::from(aabb);
+        broadphase::store_aabb(&mut self.aabbs, body_index, aabb);
+
+        // The body goes in every cell its aabb touches, so queries can find it in any cell it is in.
+        let (min_grid_cell_position, max_grid_cell_position) = self.cell_range(aabb);
+
+        for x in min_grid_cell_position[0]..=max_grid_cell_position[0] {
+            for y in min_grid_cell_position[1]..=max_grid_cell_position[1] {
+                for z in min_grid_cell_position[2]..=max_grid_cell_position[2] {
+                    let grid_cell_index = math::index_from_position_3d(
+                        [x, y, z],
+                        self.grid_size[0],
+                        self.grid_size[1],
+                    );
+                    self.cells[grid_cell_index].push(body_index);
+                }
+            }
+        }
+    }
+
+    fn find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+        on_pair: &mut dyn FnMut(usize, usize),
+    ) {
+        let mut scratch = CellScratch::default();
+        for (grid_cell_index, cell) in self.cells.iter().enumerate() {
+            broadphase::find_pairs_in_cell(
+                cell,
+                &self.aabbs,
+                can_detect,
+                &mut scratch,
+                |point| self.cell_index(point) == grid_cell_index,
+                &mut *on_pair,
+            );
+        }
+    }
+
+    fn par_find_pairs(
+        &self,
+        can_detect: &(dyn Fn(usize) -> bool + Sync),
+    ) -> Vec<Vec<(usize, usize)>> {
+        self.cells
+            .par_iter()
+            .enumerate()
+            .fold(
+                || (vec![], CellScratch::default()),
+                |(mut pairs, mut scratch), (grid_cell_index, cell)| {
+                    broadphase::find_pairs_in_cell(
+                        cell,
+                        &self.aabbs,
+                        can_detect,
+                        &mut scratch,
+                        |point| self.cell_index(point) == grid_cell_index,
+                        |lhs_body_index, rhs_body_index| {
+                            pairs.push((lhs_body_index, rhs_body_index))
+                        },
+                    );
+                    (pairs, scratch)
+                },
+            )
+            .map(|(pairs, _)| pairs)
+            .collect()
+    }
+
+    fn extend_with_candidates(&self, aabb: AabbCentredOrigin<T>, results: &mut Vec<usize>) {
+        let (min_grid_cell_position, max_grid_cell_position) = self.cell_range(aabb.into());
+
+        for x in min_grid_cell_position[0]..=max_grid_cell_position[0] {
+            for y in min_grid_cell_position[1]..=max_grid_cell_position[1] {
+                for z in min_grid_cell_position[2]..=max_grid_cell_position[2] {
+                    let grid_cell_index = math::index_from_position_3d(
+                        [x, y, z],
+                        self.grid_size[0],
+                        self.grid_size[1],
+                    );
+                    results.extend_from_slice(&self.cells[grid_cell_index]);
+                }
+            }
+        }
+    }
+
+    fn cast(
+        &self,
+        origin: [T; 3],
+        half_size: [T; 3],
+        direction: [T; 3],
+        max_distance: T,
+        cast_against: &mut dyn FnMut(usize) -> T,
+    ) {
+        broadphase::cast_through_cells(
+            origin,
+            half_size,
+            direction,
+            max_distance,
+            self.grid_origin,
+            [
+                T::from_f64(self.cell_size[0] as f64),
+                T::from_f64(self.cell_size[1] as f64),
+                T::from_f64(self.cell_size[2] as f64),
+            ],
+            [
+                self.grid_size[0] as isize,
+                self.grid_size[1] as isize,
+                self.grid_size[2] as isize,
+            ],
+            |[x, y, z]| {
+                &self.cells[math::index_from_position_3d(
+                    [x as usize, y as usize, z as usize],
+                    self.grid_size[0],
+                    self.grid_size[1],
+                )]
+            },
+            cast_against,
+        );
+    }
+
+    fn bounds(&self) -> Option<([T; 3], [T; 3])> {
+        let real_grid_size = math::mul_3d(self.grid_size, self.cell_size);
+        Some((
+            self.grid_origin,
+            [
+                self.grid_origin[0] + T::from_f64(real_grid_size[0] as f64),
+                self.grid_origin[1] + T::from_f64(real_grid_size[1] as f64),
+                self.grid_origin[2] + T::from_f64(real_grid_size[2] as f64),
+            ],
+        ))
+    }
+}
+
+/// Checks whether a position, relative to the grid origin, is outside of the grid.
+/// real_grid_size is the grid size multiplied by the cell size.
+#[inline]
+pub(super) fn is_outside_of_grid_bounds<T: Float>(
+    corrected_position: [T; 3],
+    real_grid_size: [usize; 3],
+) -> bool {
+    let corrected_position_as_isize = [
+        corrected_position[0].to_isize(),
+        corrected_position[1].to_isize(),
+        corrected_position[2].to_isize(),
+    ];
+
+    corrected_position_as_isize[0] < 0
+        || corrected_position_as_isize[0] as usize > real_grid_size[0] - 1
+        || corrected_position_as_isize[1] < 0
+        || corrected_position_as_isize[1] as usize > real_grid_size[1] - 1
+        || corrected_position_as_isize[2] < 0
+        || corrected_position_as_isize[2] as usize > real_grid_size[2] - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_pairs_on_cell_boundaries() {
+        let mut grid = UniformGrid::new([10; 3], [1; 3], [0.1_f32; 3]);
+
+        // Puts the min of the right body within a few floats of each cell boundary, where rounding differently would give a different cell.
+        for boundary in 1..9 {
+            let boundary_position = boundary as f32 + 0.1 + 0.3;
+            for float_offset in -4..=4 {
+                let position =
+                    f32::from_bits((boundary_position.to_bits() as i32 + float_offset) as u32);
+
+                grid.clear();
+                grid.insert(
+                    0,
+                    AabbCentredOrigin {
+                        position: [position - 0.4, 5.5, 5.5],
+                        half_size: [0.3; 3],
+                    },
+                );
+                grid.insert(
+                    1,
+                    AabbCentredOrigin {
+                        position: [position, 5.5, 5.5],
+                        half_size: [0.3; 3],
+                    },
+                );
+                grid.finish_inserting();
+
+                let mut pairs = vec![];
+                grid.find_pairs(&|_| true, &mut |lhs_body_index, rhs_body_index| {
+                    pairs.push((lhs_body_index, rhs_body_index))
+                });
+                assert_eq!(pairs.len(), 1, "{position}");
+            }
+        }
+    }
+}
@@ -1,10 +1,12 @@
 use crate::math;
+use serde::{Deserialize, Serialize};
 
 extern crate test;
 
 pub mod bodies;
+pub mod cuboid_arrays;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Particle<T>
 where
     T: math::Number,
@@ -14,14 +16,10 @@ where
     pub acceleration: [T; 3],
 }
 
-// I am interested in an experimental particle that uses integers.
-struct Experiment {
-    position: [u16; 3],
-    // Perhaps this could be even smaller, and just have a lower precision, which I think would be fine.
-    previous_position: [u16; 3],
-
-    acceleration: [u16; 3],
-}
+/// A particle that uses integers, through [math::Fixed], so that it moves bit for bit the same on every platform.
+/// Use it with CpuSolver<math::Fixed, _> to make the whole simulation deterministic.
+/// It takes as much memory as a Particle<f64>, see [math::Fixed] for why.
+pub type FixedParticle = Particle<math::Fixed>;
 
 impl<T> Particle<T>
 where
@@ -73,10 +71,23 @@ where
         );
     }
 
+    /// Applies an impulse that opposes the particle's movement, such as friction, by moving the particle back, rather than changing its previous position.
+    /// The velocity changes the same as with [Particle::apply_impulse], but the movement it opposes is also undone, so the particle doesn't creep, such as down a slope it should be stuck to.
+    pub fn apply_friction_impulse(&mut self, impulse: [T; 3], delta_time: T) {
+        self.position = math::add_3d(self.position, math::mul_3d_by_1d(impulse, delta_time));
+    }
+
     /// Moves both position and previous_position.
     /// This avoids accidental velocity and displacement changes.
     pub fn apply_uniform_position_change(&mut self, translation: [T; 3]) {
         self.position = math::add_3d(self.position, translation);
         self.previous_position = math::add_3d(self.previous_position, translation);
     }
+
+    /// Sets both position and previous_position to the new position.
+    /// Unlike [Particle::apply_uniform_position_change], this removes all velocity and displacement.
+    pub fn teleport(&mut self, position: [T; 3]) {
+        self.position = position;
+        self.previous_position = position;
+    }
 }
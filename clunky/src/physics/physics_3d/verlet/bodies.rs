@@ -1,19 +1,24 @@
-use crate::math;
+use crate::{
+    math,
+    physics::physics_3d::{capsule, cylinder, material::PhysicsMaterial, sphere},
+};
+use serde::{Deserialize, Serialize};
 
 use super::Particle;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player<T>
 where
     T: math::Float,
 {
     pub particle: Particle<T>,
     pub mass: T,
-    pub friction: T,
-    pub restitution: T,
+    pub material: PhysicsMaterial<T>,
     pub half_size: [T; 3],
     pub dampening: [T; 3],
     pub grounded: bool,
+    /// Sweeps the player from where it was to where it is each substep, so that it can't fall through thin immovable floors when moving fast.
+    pub continuous_collision_detection: bool,
 }
 
 impl<T> Player<T>
@@ -31,13 +36,17 @@ where
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cuboid<T>
 where
     T: math::Float,
 {
     pub particle: Particle<T>, // Do we even want to store particles with collision data?
     pub half_size: [T; 3],
+    pub material: PhysicsMaterial<T>,
+    /// Sweeps the cuboid from where it was to where it is each substep, so that it can't tunnel through thin immovable bodies when moving fast.
+    /// Only worth turning on for bodies that move more than their own half size in a substep, such as bullets.
+    pub continuous_collision_detection: bool,
 }
 
 impl<T> Cuboid<T>
@@ -53,5 +62,116 @@ where
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sphere<T>
+where
+    T: math::Float,
+{
+    pub particle: Particle<T>,
+    pub radius: T,
+    pub material: PhysicsMaterial<T>,
+    /// Sweeps the sphere from where it was to where it is each substep, so that it can't tunnel through thin immovable bodies when moving fast.
+    /// The sweep uses the aabb around the sphere, so it can stop slightly early when passing close to an edge.
+    pub continuous_collision_detection: bool,
+}
+
+impl<T> Sphere<T>
+where
+    T: math::Float,
+{
+    /// The shape of the sphere, for collision checks.
+    #[inline]
+    pub fn shape(&self) -> sphere::Sphere<T> {
+        sphere::Sphere {
+            position: self.particle.position,
+            radius: self.radius,
+        }
+    }
+
+    pub fn update(&mut self, gravity: [T; 3], dampening: [T; 3], delta_time: T) {
+        self.particle.accelerate(gravity);
+        self.particle.update(
+            delta_time,
+            math::mul_3d(self.particle.calculate_displacement(), dampening),
+        );
+    }
+}
+
+/// An upright capsule. See [capsule::Capsule].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capsule<T>
+where
+    T: math::Float,
+{
+    pub particle: Particle<T>,
+    pub radius: T,
+    pub half_height: T,
+    pub material: PhysicsMaterial<T>,
+    /// Sweeps the capsule from where it was to where it is each substep, so that it can't tunnel through thin immovable bodies when moving fast.
+    /// The sweep uses the aabb around the capsule, so it can stop slightly early when passing close to an edge.
+    pub continuous_collision_detection: bool,
+}
+
+impl<T> Capsule<T>
+where
+    T: math::Float,
+{
+    /// The shape of the capsule, for collision checks.
+    #[inline]
+    pub fn shape(&self) -> capsule::Capsule<T> {
+        capsule::Capsule {
+            position: self.particle.position,
+            radius: self.radius,
+            half_height: self.half_height,
+        }
+    }
+
+    pub fn update(&mut self, gravity: [T; 3], dampening: [T; 3], delta_time: T) {
+        self.particle.accelerate(gravity);
+        self.particle.update(
+            delta_time,
+            math::mul_3d(self.particle.calculate_displacement(), dampening),
+        );
+    }
+}
+
+/// An upright cylinder. See [cylinder::Cylinder].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cylinder<T>
+where
+    T: math::Float,
+{
+    pub particle: Particle<T>,
+    pub radius: T,
+    pub half_height: T,
+    pub material: PhysicsMaterial<T>,
+    /// Sweeps the cylinder from where it was to where it is each substep, so that it can't tunnel through thin immovable bodies when moving fast.
+    /// The sweep uses the aabb around the cylinder, so it can stop slightly early when passing close to an edge.
+    pub continuous_collision_detection: bool,
+}
+
+impl<T> Cylinder<T>
+where
+    T: math::Float,
+{
+    /// The shape of the cylinder, for collision checks.
+    #[inline]
+    pub fn shape(&self) -> cylinder::Cylinder<T> {
+        cylinder::Cylinder {
+            position: self.particle.position,
+            radius: self.radius,
+            half_height: self.half_height,
+        }
+    }
+
+    pub fn update(&mut self, gravity: [T; 3], dampening: [T; 3], delta_time: T) {
+        self.particle.accelerate(gravity);
+        self.particle.update(
+            delta_time,
+            math::mul_3d(self.particle.calculate_displacement(), dampening),
+        );
+    }
+}
+
 // TODO: List of common shaps I want to include here. But first, a naming scheme. No rotation should be by default. Axis aligned should be the default. At least 1 particle should be the default. As such "cuboid" should refer to an axis aligned cuboid with a single particle that can't rotate.
 // List: Cuboid, ImmovableCuboid, Sphere, ImmovableSphere, Player, Cylinder, ImmovableCylinder